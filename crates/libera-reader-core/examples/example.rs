@@ -18,8 +18,8 @@ fn main() {
     .finish();
   tracing::subscriber::set_global_default(subscriber).unwrap();
 
-  let mut app_core = Core::new();
-  match app_core.settings.path_to_scan_is_valid() {
+  let mut app_core = Core::new().unwrap();
+  match app_core.settings.has_library_roots() {
     true => {
       app_core.services.run();
     }
@@ -28,7 +28,7 @@ fn main() {
       let mut user_input = String::new();
       io::stdin().read_line(&mut user_input).expect("Error: unable to read user input");
       let user_input = user_input.trim().to_string();
-      app_core.settings.set_path_to_scan(user_input);
+      app_core.settings.add_library_root(user_input).unwrap();
       app_core.services.run();
     }
   };
@@ -1,24 +1,205 @@
-use crate::db::models::Book;
-use crate::db::{crud, DB};
+use crate::book_query::{BookQuery, QueryResult};
+use crate::context::Context;
+use crate::db::models::{Book, BookDataType, BookMark, Collection, DataOfUnhashedBook, DuplicateAction, DuplicateGroup,
+                        FormField, FormValue, HistoryEntry, OcrText, RemovedDuplicates, SearchResult};
+use crate::db::crud;
+use crate::error::{CoreError, CoreResult};
+use crate::forms;
 use crate::types::BookPath;
+use mupdf::attachment::Attachment;
+use mupdf::document::Document;
+use mupdf::outline::Outline;
+use std::fs;
+use std::sync::Arc;
 
 
-pub struct BookApi {}
+pub struct BookApi {
+  ctx: Arc<Context>,
+}
 
 impl BookApi {
-  pub fn new() -> Self { Self {} }
-  //noinspection RsUnwrap
-  pub fn get_book_by_path(&self, path_to_book: &BookPath) -> native_db::db_type::Result<Option<Book>> {
-    let r_conn = DB.r_transaction().unwrap();
-    r_conn.get().primary::<Book>(path_to_book.clone())
+  pub(crate) fn new(ctx: Arc<Context>) -> Self { Self { ctx } }
+  pub fn get_book_by_path(&self, path_to_book: &BookPath) -> CoreResult<Option<Book>> {
+    let r_conn = self.ctx.db.r_transaction()?;
+    Ok(r_conn.get().primary::<Book>(path_to_book.clone())?)
   }
-  pub fn get_books_from_db(&self) -> Vec<Book> {
-    crud::book::get_all_from_db()
+  pub fn get_books_from_db(&self) -> CoreResult<Vec<Book>> {
+    crud::book::get_all_from_db(&self.ctx)
   }
-}
-impl Default for BookApi {
-  fn default() -> Self {
-    Self {}
+  /// Filtered, sorted and paginated books of the library
+  pub fn query_books(&self, query: &BookQuery) -> CoreResult<QueryResult> {
+    query.run(&self.ctx)
+  }
+  /// Adds the book to the history and returns the page to resume reading from,
+  /// `None` if the book is not in the db
+  pub fn open_book(&self, path_to_book: &BookPath) -> CoreResult<Option<i32>> {
+    let last_page_number = crud::history::open_book(&self.ctx, path_to_book.clone())?;
+    if last_page_number.is_some() {
+      self.ctx.workers.book_opened(path_to_book);
+    }
+    Ok(last_page_number)
+  }
+  /// Saves the page the reader is on, returns `false` if the book is not in the db
+  pub fn update_progress(&self, path_to_book: &BookPath, page_number: i32) -> CoreResult<bool> {
+    crud::history::update_progress(&self.ctx, path_to_book.clone(), page_number)
+  }
+  /// Returns `false` if the book is not in the db
+  pub fn close_book(&self, path_to_book: &BookPath) -> CoreResult<bool> {
+    self.ctx.workers.book_closed(path_to_book);
+    crud::history::close_book(&self.ctx, path_to_book.clone())
+  }
+  /// Removes the book from the history and forgets its progress
+  pub fn remove_from_history(&self, path_to_book: &BookPath) -> CoreResult<bool> {
+    crud::history::remove_from_history(&self.ctx, path_to_book.clone())
+  }
+  /// Recently read books, the most recent first
+  pub fn history(&self, limit: usize) -> CoreResult<Vec<HistoryEntry>> {
+    crud::history::get_history(&self.ctx, limit)
+  }
+  /// Adds the book to the favorites or removes it from them,
+  /// returns `false` if the book is not in the db
+  pub fn set_favorite(&self, path_to_book: &BookPath, favorite: bool) -> CoreResult<bool> {
+    crud::favorites::set_favorite(&self.ctx, path_to_book.clone(), favorite)
+  }
+  /// Favorite books in the user-defined order
+  pub fn favorites(&self) -> CoreResult<Vec<Book>> {
+    crud::favorites::get_favorites(&self.ctx)
+  }
+  /// Moves the favorite book to `new_position` of the favorites list
+  pub fn move_favorite(&self, path_to_book: &BookPath, new_position: usize) -> CoreResult<bool> {
+    crud::favorites::move_favorite(&self.ctx, path_to_book.clone(), new_position)
+  }
+  /// Collections sorted by name
+  pub fn collections(&self) -> CoreResult<Vec<Collection>> {
+    crud::collections::get_collections(&self.ctx)
+  }
+  pub fn create_collection(&self, name: &str) -> CoreResult<Collection> {
+    crud::collections::create_collection(&self.ctx, name.to_string())
+  }
+  pub fn rename_collection(&self, id: u64, name: &str) -> CoreResult<Option<Collection>> {
+    crud::collections::rename_collection(&self.ctx, id, name.to_string())
+  }
+  /// Deletes the collection, the books stay in the library
+  pub fn delete_collection(&self, id: u64) -> CoreResult<Option<Collection>> {
+    crud::collections::delete_collection(&self.ctx, id)
+  }
+  /// Adds the books to the collection, returns the number of books found in the db,
+  /// `None` if the collection doesn't exist
+  pub fn add_to_collection(&self, books_path: &[BookPath], collection_id: u64) -> CoreResult<Option<usize>> {
+    crud::collections::add_to_collection(&self.ctx, books_path.to_vec(), collection_id)
+  }
+  pub fn remove_from_collection(&self, books_path: &[BookPath], collection_id: u64) -> CoreResult<usize> {
+    crud::collections::remove_from_collection(&self.ctx, books_path.to_vec(), collection_id)
+  }
+  /// Books of the collection, duplicates are listed once
+  pub fn books_in_collection(&self, collection_id: u64) -> CoreResult<Vec<Book>> {
+    crud::collections::get_books_in_collection(&self.ctx, collection_id)
+  }
+  /// Tags every book of the list, tags are case-insensitive and shared by duplicates of a book.
+  /// Returns the number of books found in the db
+  pub fn add_tags(&self, books_path: &[BookPath], tags: &[String]) -> CoreResult<usize> {
+    crud::tags::add_tags(&self.ctx, books_path.to_vec(), tags)
+  }
+  pub fn remove_tags(&self, books_path: &[BookPath], tags: &[String]) -> CoreResult<usize> {
+    crud::tags::remove_tags(&self.ctx, books_path.to_vec(), tags)
+  }
+  pub fn get_tags(&self, path_to_book: &BookPath) -> CoreResult<Vec<String>> {
+    crud::tags::get_tags(&self.ctx, path_to_book.clone())
+  }
+  /// All tags of the library with the number of books tagged with each
+  pub fn all_tags(&self) -> CoreResult<Vec<(String, usize)>> {
+    crud::tags::get_all_tags(&self.ctx)
+  }
+  /// Books tagged with `tag`, use [`BookQuery::with_tag`] to combine it with other filters
+  pub fn books_with_tag(&self, tag: &str) -> CoreResult<Vec<Book>> {
+    Ok(BookQuery::new().with_tag(tag).run(&self.ctx)?.books)
+  }
+  /// Groups of identical books that have more than one copy in the library
+  pub fn duplicates(&self) -> CoreResult<Vec<DuplicateGroup>> {
+    crud::duplicates::get_duplicates(&self.ctx)
+  }
+  /// Deletes, trashes or moves every copy of the book except `path_to_kept_book`,
+  /// favorites, bookmarks and history stay with the kept copy. Returns the paths of the removed copies
+  pub fn remove_duplicates(&self, book_hash: &str, path_to_kept_book: &BookPath, action: &DuplicateAction)
+                           -> CoreResult<Vec<BookPath>> {
+    crud::duplicates::remove_redundant_copies(&self.ctx, &book_hash.to_string(), path_to_kept_book, action)
+  }
+  /// Removes the redundant copies of every duplicate group, keeping one copy per group.
+  /// The groups that failed are reported along with the removed copies
+  pub fn remove_all_duplicates(&self, action: &DuplicateAction) -> CoreResult<RemovedDuplicates> {
+    crud::duplicates::remove_all_redundant_copies(&self.ctx, action)
+  }
+  /// Bookmarks of the book sorted by page number, duplicates of the book share them
+  pub fn get_bookmarks(&self, path_to_book: &BookPath) -> CoreResult<Vec<BookMark>> {
+    crud::bookmark::get_bookmarks(&self.ctx, path_to_book.clone())
+  }
+  /// Returns `None` if the book is not in the db
+  pub fn add_bookmark(&self, path_to_book: &BookPath, title: String, content: String, page_number: i32)
+                      -> CoreResult<Option<BookMark>> {
+    crud::bookmark::add_bookmark(&self.ctx, path_to_book.clone(), title, content, page_number)
+  }
+  /// Returns `None` if there is no bookmark with this id
+  pub fn edit_bookmark(&self, id: u64, title: String, content: String, page_number: i32) -> CoreResult<Option<BookMark>> {
+    crud::bookmark::edit_bookmark(&self.ctx, id, title, content, page_number)
+  }
+  /// Returns the deleted bookmark or `None` if there is no bookmark with this id
+  pub fn delete_bookmark(&self, id: u64) -> CoreResult<Option<BookMark>> {
+    crud::bookmark::del_bookmark(&self.ctx, id)
+  }
+  /// Table of contents of the book, cached in the db after the first call,
+  /// `None` if the book is not in the db
+  pub fn get_outline(&self, path_to_book: &BookPath) -> CoreResult<Option<Vec<Outline>>> {
+    match crud::get_primary::<Book>(&self.ctx, path_to_book.clone())? {
+      None => { Ok(None) }
+      Some(book) => { crud::book::get_outline(&self.ctx, &book).map(Some) }
+    }
+  }
+  /// Files embedded into the book
+  pub fn get_attachments(&self, path_to_book: &BookPath) -> CoreResult<Vec<Attachment>> {
+    Document::open(path_to_book, 20).and_then(|doc| doc.attachments()).map_err(CoreError::Document)
+  }
+  /// Extracts the embedded file of the book into `path_to_out`
+  pub fn save_attachment(&self, path_to_book: &BookPath, attachment: &Attachment, path_to_out: &str)
+                         -> CoreResult<()> {
+    let data = Document::open(path_to_book, 20).and_then(|doc| doc.attachment_data(attachment))
+      .map_err(CoreError::Document)?;
+    Ok(fs::write(path_to_out, data)?)
+  }
+  /// Fields of the PDF form of the book, empty if the book has no form
+  pub fn get_form_fields(&self, path_to_book: &BookPath) -> CoreResult<Vec<FormField>> {
+    forms::get_form_fields(path_to_book)
+  }
+  /// Fills the fields listed by [`BookApi::get_form_fields`] and saves the book into `path_to_out`,
+  /// which may be the book itself. Returns the fields that weren't found in the book
+  pub fn fill_form(&self, path_to_book: &BookPath, values: &[(FormField, FormValue)], path_to_out: &str)
+                   -> CoreResult<Vec<FormField>> {
+    forms::fill_form(path_to_book, values, path_to_out)
+  }
+  /// Books whose text contains all words of the query, the most relevant first
+  pub fn search_library(&self, query: &str, limit: usize) -> CoreResult<Vec<SearchResult>> {
+    crud::search_index::search(&self.ctx, query, limit)
+  }
+  /// Recognized text of the book by pages, `None` if the book hasn't been recognized yet
+  pub fn get_ocr_text(&self, path_to_book: &BookPath) -> CoreResult<Option<Vec<String>>> {
+    let book = match crud::get_primary::<Book>(&self.ctx, path_to_book.clone())? {
+      None => { return Ok(None); }
+      Some(book) => { book }
+    };
+    let book_hash = match &book.book_data_pk {
+      BookDataType::RepeatingSize(book_hash) => book_hash.clone(),
+      BookDataType::UniqueSize(book_size) => {
+        match crud::get_primary::<DataOfUnhashedBook>(&self.ctx, *book_size)?.and_then(|data| data.book_hash) {
+          None => { return Ok(None); }
+          Some(book_hash) => { book_hash }
+        }
+      }
+    };
+    Ok(crud::get_primary::<OcrText>(&self.ctx, book_hash)?.map(|ocr_text| ocr_text.pages))
+  }
+  /// Queues the book for background text recognition
+  #[cfg(feature = "ocr")]
+  pub fn request_ocr(&self, path_to_book: &BookPath) {
+    crate::services::ocr_service::push_book_for_ocr(&self.ctx, path_to_book.clone());
   }
 }
 
@@ -0,0 +1,238 @@
+use crate::context::Context;
+use crate::db::crud;
+use crate::error::CoreResult;
+use crate::models::{Book, BookData, BookDataType, BookKey, DataOfHashedBook, DataOfUnhashedBook};
+use crate::types::BookSize;
+use gxhash::{HashMap, HashMapExt};
+use itertools::Itertools;
+use std::cmp::Ordering;
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SortBy {
+  #[default]
+  Name,
+  Author,
+  Size,
+  AddedDate,
+  LastOpened,
+}
+
+/// Query of library books.
+///
+/// Only the whole library sorted by name is paged straight from an index. Any filter or another sort order
+/// reads all the candidate books, the books of the dir or of the extension if set, and sorts them in memory.
+///
+/// ```ignore
+/// let query = BookQuery::new().in_dir("/books/science").with_text("physics").sort_by(SortBy::Author).page(0, 50);
+/// let res = core.book_api.query_books(&query)?;
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct BookQuery {
+  dir: Option<String>,
+  library_root: Option<String>,
+  ext: Option<String>,
+  favorite: Option<bool>,
+  in_history: Option<bool>,
+  path_is_valid: Option<bool>,
+  tag: Option<String>,
+  collection: Option<u64>,
+  text: Option<String>,
+  sort_by: SortBy,
+  descending: bool,
+  offset: usize,
+  limit: Option<usize>,
+}
+
+pub struct QueryResult {
+  /// Books of the requested page
+  pub books: Vec<Book>,
+  /// Number of books matching the query on all pages
+  pub total: usize,
+}
+
+impl BookQuery {
+  pub fn new() -> Self { Self::default() }
+  /// Only books located directly in the directory
+  pub fn in_dir(mut self, path_to_dir: &str) -> Self {
+    self.dir = Some(path_to_dir.to_string());
+    self
+  }
+  /// Only books that came from the library root
+  pub fn in_library_root(mut self, path_to_root: &str) -> Self {
+    self.library_root = Some(path_to_root.to_string());
+    self
+  }
+  pub fn with_ext(mut self, ext: &str) -> Self {
+    self.ext = Some(ext.to_string());
+    self
+  }
+  pub fn favorite(mut self, favorite: bool) -> Self {
+    self.favorite = Some(favorite);
+    self
+  }
+  pub fn in_history(mut self, in_history: bool) -> Self {
+    self.in_history = Some(in_history);
+    self
+  }
+  pub fn path_is_valid(mut self, path_is_valid: bool) -> Self {
+    self.path_is_valid = Some(path_is_valid);
+    self
+  }
+  pub fn with_tag(mut self, tag: &str) -> Self {
+    self.tag = Some(crud::tags::normalize_tag(tag));
+    self
+  }
+  pub fn in_collection(mut self, collection_id: u64) -> Self {
+    self.collection = Some(collection_id);
+    self
+  }
+  /// Case-insensitive search in the title, author and file name
+  pub fn with_text(mut self, text: &str) -> Self {
+    self.text = Some(text.to_lowercase());
+    self
+  }
+  pub fn sort_by(mut self, sort_by: SortBy) -> Self {
+    self.sort_by = sort_by;
+    self
+  }
+  pub fn descending(mut self, descending: bool) -> Self {
+    self.descending = descending;
+    self
+  }
+  pub fn page(mut self, offset: usize, limit: usize) -> Self {
+    self.offset = offset;
+    self.limit = Some(limit);
+    self
+  }
+
+  fn filters_by_book_data(&self) -> bool {
+    self.favorite.is_some() || self.in_history.is_some() || self.text.is_some()
+      || self.tag.is_some() || self.collection.is_some()
+  }
+  fn needs_book_data(&self) -> bool {
+    self.filters_by_book_data() || !matches!(self.sort_by, SortBy::Name | SortBy::AddedDate)
+  }
+  fn paginate(&self, books: impl Iterator<Item=Book>) -> Vec<Book> {
+    books.skip(self.offset).take(self.limit.unwrap_or(usize::MAX)).collect()
+  }
+
+  pub(crate) fn run(&self, ctx: &Context) -> CoreResult<QueryResult> {
+    let r_conn = ctx.db.r_transaction()?;
+    let unfiltered = self.dir.is_none() && self.library_root.is_none() && self.ext.is_none()
+      && self.path_is_valid.is_none()
+      && !self.needs_book_data();
+    // The whole library sorted by name is read straight from the index
+    if unfiltered && self.sort_by == SortBy::Name {
+      let total = r_conn.len().secondary::<Book>(BookKey::book_name)? as usize;
+      let books = r_conn.scan().secondary::<Book>(BookKey::book_name)?;
+      let books: Vec<Book> = match self.descending {
+        true => books.all()?.rev().skip(self.offset).take(self.limit.unwrap_or(usize::MAX)).try_collect()?,
+        false => books.all()?.skip(self.offset).take(self.limit.unwrap_or(usize::MAX)).try_collect()?,
+      };
+      return Ok(QueryResult { books, total });
+    }
+
+    let candidates: Vec<Book> = match (&self.dir, &self.ext) {
+      (Some(path_to_dir), _) => {
+        let books: Vec<Book> = r_conn.scan().secondary::<Book>(BookKey::path_to_dir)?
+          .start_with(path_to_dir.clone())?.try_collect()?;
+        books.into_iter().filter(|book| &book.path_to_dir == path_to_dir).collect()
+      }
+      (None, Some(ext)) => {
+        let books: Vec<Book> = r_conn.scan().secondary::<Book>(BookKey::ext)?
+          .start_with(ext.clone())?.try_collect()?;
+        books.into_iter().filter(|book| &book.ext == ext).collect()
+      }
+      (None, None) => {
+        r_conn.scan().primary::<Book>()?.all()?.try_collect()?
+      }
+    };
+
+    let books = candidates.into_iter()
+      .filter(|book| self.ext.as_ref().map_or(true, |ext| &book.ext == ext))
+      .filter(|book| self.library_root.as_ref().map_or(true, |library_root| &book.library_root == library_root))
+      .filter(|book| self.path_is_valid.map_or(true, |path_is_valid| book.path_is_valid == path_is_valid));
+
+    let mut books_with_data: Vec<(Book, Option<(BookData, BookSize)>)> = if self.needs_book_data() {
+      // All the data records are read in one pass, duplicates share theirs
+      let data_of_unhashed_books: Vec<DataOfUnhashedBook> = r_conn.scan().primary()?.all()?.try_collect()?;
+      let data_of_hashed_books: Vec<DataOfHashedBook> = r_conn.scan().primary()?.all()?.try_collect()?;
+      let mut book_data: HashMap<BookDataType, (BookData, BookSize)> = HashMap::new();
+      for data in data_of_unhashed_books {
+        book_data.insert(BookDataType::UniqueSize(data.book_size), (data.book_data, data.book_size));
+      }
+      for data in data_of_hashed_books {
+        book_data.insert(BookDataType::RepeatingSize(data.book_hash), (data.book_data, data.book_size));
+      }
+      books
+        .map(|book| {
+          let book_data = book_data.get(&book.book_data_pk).cloned();
+          (book, book_data)
+        })
+        .filter(|(book, book_data)| self.matches_book_data(book, book_data.as_ref().map(|(book_data, _)| book_data)))
+        .collect()
+    } else {
+      books.map(|book| (book, None)).collect()
+    };
+    drop(r_conn);
+
+    books_with_data.sort_by(|a, b| {
+      let ordering = self.compare(a, b);
+      if self.descending { ordering.reverse() } else { ordering }
+    });
+    let total = books_with_data.len();
+    Ok(QueryResult { books: self.paginate(books_with_data.into_iter().map(|(book, _)| book)), total })
+  }
+
+  fn matches_book_data(&self, book: &Book, book_data: Option<&BookData>) -> bool {
+    // A book without a data record is only sorted, not filtered out, unless a filter needs the data
+    let book_data = match book_data {
+      None => { return !self.filters_by_book_data(); }
+      Some(book_data) => { book_data }
+    };
+    if self.favorite.is_some_and(|favorite| book_data.favorite != favorite) {
+      return false;
+    }
+    if self.in_history.is_some_and(|in_history| book_data.in_history != in_history) {
+      return false;
+    }
+    if self.tag.as_ref().is_some_and(|tag| !book_data.tags.contains(tag)) {
+      return false;
+    }
+    if self.collection.is_some_and(|collection_id| !book_data.collections.contains(&collection_id)) {
+      return false;
+    }
+    match &self.text {
+      None => true,
+      Some(text) => {
+        let contains = |value: &Option<String>| value.as_ref().is_some_and(|value| value.to_lowercase().contains(text));
+        book.book_name.to_lowercase().contains(text) || contains(&book_data.title) || contains(&book_data.author)
+      }
+    }
+  }
+
+  fn compare(&self, (a, a_data): &(Book, Option<(BookData, BookSize)>),
+             (b, b_data): &(Book, Option<(BookData, BookSize)>)) -> Ordering {
+    let by_name = || a.book_name.cmp(&b.book_name);
+    match self.sort_by {
+      SortBy::Name => by_name(),
+      SortBy::AddedDate => a.added_at.cmp(&b.added_at).then_with(by_name),
+      SortBy::Author => {
+        let a_author = a_data.as_ref().and_then(|(data, _)| data.author.as_ref());
+        let b_author = b_data.as_ref().and_then(|(data, _)| data.author.as_ref());
+        a_author.cmp(&b_author).then_with(by_name)
+      }
+      SortBy::LastOpened => {
+        let a_opening = a_data.as_ref().and_then(|(data, _)| data.latest_opening_in);
+        let b_opening = b_data.as_ref().and_then(|(data, _)| data.latest_opening_in);
+        a_opening.cmp(&b_opening).then_with(by_name)
+      }
+      SortBy::Size => {
+        let a_size = a_data.as_ref().map_or(0, |(_, size)| *size);
+        let b_size = b_data.as_ref().map_or(0, |(_, size)| *size);
+        a_size.cmp(&b_size).then_with(by_name)
+      }
+    }
+  }
+}
@@ -0,0 +1,88 @@
+use crate::app_dirs::AppDirs;
+use crate::db::{migration, open_db};
+use crate::error::CoreResult;
+use crate::models::{CoreEvent, LibraryRoot, Settings, TargetExt};
+use crate::progress::ServicesProgressTracker;
+use crate::services::notify_service::RootsWatcher;
+use crate::types::BookPath;
+use crate::types::NotifyEvents;
+use crate::utils::NotCachedBook;
+use crate::workers::Workers;
+use concurrent_queue::ConcurrentQueue;
+use native_db::Database;
+use std::path::PathBuf;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex, RwLock};
+
+
+/// Everything a [`Core`](crate::core::Core) owns: the db, the app dirs, the queues and the watcher,
+/// shared between the api and the services of this core only
+pub(crate) struct Context {
+  pub(crate) db: Database<'static>,
+  pub(crate) app_dirs: AppDirs,
+  pub(crate) library_roots: RwLock<Vec<LibraryRoot>>,
+  pub(crate) target_ext: RwLock<TargetExt>,
+  /// Events of the watcher, received by the notify service only
+  pub(crate) notify_events: Mutex<Receiver<NotifyEvents>>,
+  pub(crate) watcher: Mutex<RootsWatcher>,
+  pub(crate) not_cached_books: ConcurrentQueue<NotCachedBook>,
+  #[cfg(feature = "ocr")]
+  pub(crate) books_for_ocr: ConcurrentQueue<BookPath>,
+  pub(crate) books_for_indexing: ConcurrentQueue<BookPath>,
+  pub(crate) event_subscribers: Subscribers<CoreEvent>,
+  pub(crate) progress: ServicesProgressTracker,
+  pub(crate) workers: Workers,
+}
+
+impl Context {
+  pub(crate) fn open(data_dir: PathBuf) -> CoreResult<Arc<Self>> {
+    let app_dirs = AppDirs::new(data_dir)?;
+    let db = open_db(&app_dirs.path_to_db)?;
+    let (notify_sender, notify_events) = channel();
+    let watcher = notify::recommended_watcher(notify_sender)?;
+    let ctx = Self {
+      db,
+      app_dirs,
+      library_roots: Default::default(),
+      target_ext: RwLock::new(TargetExt::new()),
+      notify_events: Mutex::new(notify_events),
+      watcher: Mutex::new(RootsWatcher::new(watcher)),
+      not_cached_books: ConcurrentQueue::unbounded(),
+      #[cfg(feature = "ocr")]
+      books_for_ocr: ConcurrentQueue::unbounded(),
+      books_for_indexing: ConcurrentQueue::unbounded(),
+      event_subscribers: Subscribers::new(),
+      progress: ServicesProgressTracker::new(),
+      workers: Workers::new(),
+    };
+    migration::migrate(&ctx)?;
+    let settings = Settings::from_db(&ctx)?;
+    ctx.workers.update(&settings);
+    *ctx.library_roots.write().unwrap() = settings.library_roots;
+    *ctx.target_ext.write().unwrap() = TargetExt::from_db(&ctx)?;
+    Ok(Arc::new(ctx))
+  }
+  /// Sends the event to the subscribers of the core
+  pub(crate) fn emit(&self, event: CoreEvent) {
+    self.event_subscribers.send(event);
+  }
+}
+
+pub(crate) struct Subscribers<T> {
+  senders: Mutex<Vec<Sender<T>>>,
+}
+
+impl<T: Clone> Subscribers<T> {
+  fn new() -> Self {
+    Self { senders: Mutex::new(vec![]) }
+  }
+  pub(crate) fn subscribe(&self) -> Receiver<T> {
+    let (sender, receiver) = channel();
+    self.senders.lock().unwrap().push(sender);
+    receiver
+  }
+  pub(crate) fn send(&self, message: T) {
+    // Receivers that have been dropped are unsubscribed
+    self.senders.lock().unwrap().retain(|sender| sender.send(message.clone()).is_ok());
+  }
+}
@@ -1,20 +1,61 @@
+use crate::app_dirs::default_data_dir;
 use crate::book_api::BookApi;
-use crate::models::Settings;
+use crate::context::Context;
+use crate::error::CoreResult;
+use crate::models::CoreEvent;
 use crate::services::Services;
+use crate::settings_api::SettingsApi;
+use std::path::PathBuf;
+use std::sync::mpsc::Receiver;
+use std::sync::Arc;
 
 
+/// Where and how a [`Core`] keeps its data
+#[derive(Default)]
+pub struct CoreConfig {
+  data_dir: Option<PathBuf>,
+}
+
+impl CoreConfig {
+  pub fn new() -> Self {
+    Self::default()
+  }
+  /// Dir with the db and the thumbnails, the default one is used if not set
+  pub fn data_dir(mut self, data_dir: PathBuf) -> Self {
+    self.data_dir = Some(data_dir);
+    self
+  }
+}
+
 pub struct Core {
   pub services: Services,
-  pub settings: Settings,
+  pub settings: SettingsApi,
   pub book_api: BookApi,
+  ctx: Arc<Context>,
 }
 
 impl Core {
-  pub fn new() -> Self {
-    Self {
-      services: Services::new(),
-      settings: Settings::new(),
-      book_api: BookApi {},
-    }
+  /// Opens the core in the default data dir
+  pub fn new() -> CoreResult<Self> {
+    Self::open(CoreConfig::default())
+  }
+  /// Cores opened in different data dirs don't share anything and can live in one process
+  pub fn open(config: CoreConfig) -> CoreResult<Self> {
+    let data_dir = match config.data_dir {
+      None => { default_data_dir()? }
+      Some(data_dir) => { data_dir }
+    };
+    let ctx = Context::open(data_dir)?;
+    Ok(Self {
+      services: Services::new(ctx.clone()),
+      settings: SettingsApi::new(ctx.clone()),
+      book_api: BookApi::new(ctx.clone()),
+      ctx,
+    })
+  }
+  /// Receives every change of the library made by the api or the services,
+  /// drop the receiver to unsubscribe
+  pub fn subscribe(&self) -> Receiver<CoreEvent> {
+    self.ctx.event_subscribers.subscribe()
   }
 }
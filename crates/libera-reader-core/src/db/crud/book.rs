@@ -1,26 +1,37 @@
-use crate::db::{crud, models_impl::GetBookData, DB};
-use crate::models::{Book, BookDataType, DataOfHashedBook, DataOfHashedBookKey, DataOfUnhashedBook};
+use crate::context::Context;
+use crate::db::{crud, models_impl::GetBookData};
+use crate::error::{CoreError, CoreResult};
+use crate::models::{Book, BookData, BookDataType, BookOutline, CoreEvent, DataOfHashedBook, DataOfHashedBookKey,
+                    DataOfUnhashedBook, RemovedBook, Settings};
 use crate::models::{BookDataType::RepeatingSize, BookDataType::UniqueSize};
-use crate::types::{BookPath, BookSize};
-use crate::utils::{calc_file_hash, NotCachedBook};
-use crate::vars::APP_DIRS;
+use crate::types::{BookHash, BookPath, BookSize};
+use crate::utils::{calc_file_hash, calc_file_size, get_thumbnail_path, is_hashed_partially, is_partial_hash,
+                   path_to_string, NotCachedBook};
 use itertools::Itertools;
+use mupdf::document::Document;
+use mupdf::outline::Outline;
+use native_db::transaction::RwTransaction;
 use native_db::ToInput;
 use std::fs::remove_file;
-use std::path::PathBuf;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use tracing::error;
 
 
-pub(crate) fn get_all_from_db() -> Vec<Book> {
-  let r_conn = DB.r_transaction().unwrap();
-  r_conn.scan().primary().unwrap().all().unwrap().try_collect().unwrap()
+pub(crate) fn get_all_from_db(ctx: &Context) -> CoreResult<Vec<Book>> {
+  let r_conn = ctx.db.r_transaction()?;
+  Ok(r_conn.scan().primary()?.all()?.try_collect()?)
 }
-pub(crate) fn get_num_of_books_of_this_size(book_size: BookSize) -> (usize, Option<DataOfUnhashedBook>) {
+pub(crate) fn get_num_of_books_of_this_size(ctx: &Context, book_size: BookSize)
+                                            -> CoreResult<(usize, Option<DataOfUnhashedBook>)> {
   let mut out_data: Option<DataOfUnhashedBook> = None;
   let mut num_of_book_with_this_size = 0;
-  match crud::get_primary::<DataOfUnhashedBook>(book_size.clone()) {
+  match crud::get_primary::<DataOfUnhashedBook>(ctx, book_size)? {
     None => {
-      let r_conn = DB.r_transaction().unwrap();
-      for i in r_conn.scan().secondary::<DataOfHashedBook>(DataOfHashedBookKey::book_size).unwrap().all().unwrap() {
+      let r_conn = ctx.db.r_transaction()?;
+      let data_of_hashed_books = r_conn.scan().secondary::<DataOfHashedBook>(DataOfHashedBookKey::book_size)?
+        .range(book_size..=book_size)?;
+      for i in data_of_hashed_books {
         match i {
           Ok(_data) => { num_of_book_with_this_size += 1; }
           Err(_) => {}
@@ -32,152 +43,584 @@ pub(crate) fn get_num_of_books_of_this_size(book_size: BookSize) -> (usize, Opti
       out_data = Some(data);
     }
   };
-  (num_of_book_with_this_size, out_data)
+  Ok((num_of_book_with_this_size, out_data))
 }
-pub(crate) fn update_book_data_type(book_path: BookPath, book_data_type: BookDataType) {
-  let old_book = crud::get_primary::<Book>(book_path).unwrap();
+fn get_num_of_books_of_this_size_in(rw_conn: &RwTransaction, book_size: BookSize)
+                                    -> CoreResult<(usize, Option<DataOfUnhashedBook>)> {
+  match rw_conn.get().primary::<DataOfUnhashedBook>(book_size)? {
+    None => {
+      let data_of_hashed_books: Vec<DataOfHashedBook> = rw_conn.scan()
+        .secondary(DataOfHashedBookKey::book_size)?.range(book_size..=book_size)?.try_collect()?;
+      Ok((data_of_hashed_books.len(), None))
+    }
+    Some(data) => Ok((data.book_data.books_pk.len(), Some(data))),
+  }
+}
+fn update_book_data_type(rw_conn: &RwTransaction, book_path: BookPath, book_data_type: BookDataType)
+                         -> CoreResult<()> {
+  let old_book = rw_conn.get().primary::<Book>(book_path.clone())?
+    .ok_or(CoreError::NotFound(format!("book {book_path}")))?;
   let mut new_book = old_book.clone();
   new_book.book_data_pk = book_data_type;
-  crud::update(old_book, new_book).unwrap();
+  Ok(rw_conn.update(old_book, new_book)?)
+}
+/// Content hash of the file, partial if the book is larger than the size set in the settings
+pub(crate) fn calc_book_hash(ctx: &Context, path_to_book: &Path) -> CoreResult<BookHash> {
+  calc_file_hash(path_to_book, Settings::from_db(ctx)?.partial_hash_min_size)
+}
+/// Hashes of a book being added. The stored hashes may have been calculated with another partial hashing setting,
+/// so the book is hashed in the mode of the hash it's compared with. Each mode is calculated once
+pub(crate) struct BookHasher<'a> {
+  path_to_book: &'a Path,
+  full_hash: Option<BookHash>,
+  partial_hash: Option<BookHash>,
 }
 
-pub(crate) fn del_book_and_its_data(book: Book) {
-  let book_data_type = book.book_data_pk.clone();
+impl<'a> BookHasher<'a> {
+  pub(crate) fn new(path_to_book: &'a Path) -> Self {
+    Self { path_to_book, full_hash: None, partial_hash: None }
+  }
+  /// A hash calculated so far, it's kept as the cache of a unique size book
+  fn known_hash(&self) -> Option<BookHash> {
+    self.full_hash.clone().or_else(|| self.partial_hash.clone())
+  }
+  /// Hash in the same mode as `other_hash`
+  fn hash_like(&mut self, other_hash: &BookHash) -> CoreResult<BookHash> {
+    self.hash(is_partial_hash(other_hash))
+  }
+  /// Hash in the mode set in the settings, for a book that isn't compared with any other
+  pub(crate) fn hash_by_settings(&mut self, ctx: &Context, book_size: BookSize) -> CoreResult<BookHash> {
+    self.hash(is_hashed_partially(book_size, Settings::from_db(ctx)?.partial_hash_min_size))
+  }
+  fn hash(&mut self, partially: bool) -> CoreResult<BookHash> {
+    let (book_hash, partial_hash_min_size) = match partially {
+      true => (&mut self.partial_hash, Some(0)),
+      false => (&mut self.full_hash, None),
+    };
+    match book_hash {
+      Some(book_hash) => Ok(book_hash.clone()),
+      None => {
+        let new_hash = calc_file_hash(self.path_to_book, partial_hash_min_size)?;
+        *book_hash = Some(new_hash.clone());
+        Ok(new_hash)
+      }
+    }
+  }
+}
+
+/// Returns the content hash of the book, unique size books get it calculated and saved on first call
+pub(crate) fn get_or_calc_book_hash(ctx: &Context, book: &Book) -> CoreResult<BookHash> {
+  match &book.book_data_pk {
+    RepeatingSize(book_hash) => Ok(book_hash.clone()),
+    UniqueSize(book_size) => {
+      let old_book_data = crud::get_primary::<DataOfUnhashedBook>(ctx, *book_size)?
+        .ok_or_else(|| CoreError::NotFound(format!("data of the book {}", book.path_to_book)))?;
+      match &old_book_data.book_hash {
+        Some(book_hash) => Ok(book_hash.clone()),
+        None => {
+          let book_hash = calc_book_hash(ctx, Path::new(&book.path_to_book))?;
+          let mut new_book_data = old_book_data.clone();
+          new_book_data.book_hash = Some(book_hash.clone());
+          crud::update(ctx, old_book_data, new_book_data)?;
+          Ok(book_hash)
+        }
+      }
+    }
+  }
+}
+/// Data records of all books, one per group of duplicates
+pub(crate) fn get_all_book_data(ctx: &Context) -> CoreResult<Vec<BookData>> {
+  let r_conn = ctx.db.r_transaction()?;
+  let data_of_unhashed_books: Vec<DataOfUnhashedBook> = r_conn.scan().primary()?.all()?.try_collect()?;
+  let data_of_hashed_books: Vec<DataOfHashedBook> = r_conn.scan().primary()?.all()?.try_collect()?;
+  Ok(data_of_unhashed_books.into_iter().map(|data| data.book_data)
+    .chain(data_of_hashed_books.into_iter().map(|data| data.book_data))
+    .collect())
+}
+/// Picks the book that represents the group of duplicates, preferring a copy that still exists on disk
+pub(crate) fn get_main_book(ctx: &Context, book_data: &BookData) -> CoreResult<Option<Book>> {
+  let mut books = vec![];
+  for book_path in &book_data.books_pk {
+    if let Some(book) = crud::get_primary::<Book>(ctx, book_path.clone())? {
+      books.push(book);
+    }
+  }
+  Ok(books.iter().find(|book| book.path_is_valid).or(books.first()).cloned())
+}
+/// Data record of the book together with the size of the book
+pub(crate) fn get_book_data_with_size(ctx: &Context, book_data_type: &BookDataType)
+                                      -> CoreResult<Option<(BookData, BookSize)>> {
   match book_data_type {
     UniqueSize(book_size) => {
-      let book_data = crud::get_primary::<DataOfUnhashedBook>(book_size).unwrap();
-      delete_books_and_their_data(book_data, book);
+      Ok(crud::get_primary::<DataOfUnhashedBook>(ctx, *book_size)?.map(|data| (data.book_data, data.book_size)))
     }
     RepeatingSize(book_hash) => {
-      let book_data = crud::get_primary::<DataOfHashedBook>(book_hash).unwrap();
-      delete_books_and_their_data(book_data, book);
+      Ok(crud::get_primary::<DataOfHashedBook>(ctx, book_hash.clone())?.map(|data| (data.book_data, data.book_size)))
     }
-  };
+  }
 }
-fn delete_books_and_their_data<T: ToInput + GetBookData>(data: T, book: Book) {
-  let rw_conn = DB.rw_transaction().unwrap();
-  let book_data = data.get_book_data_as_ref();
-  if book_data.favorite == false && book_data.in_history == false {
-    if book_data.cached {
-      remove_thumbnail(&book.book_data_pk);
-    }
-    if book_data.books_pk.len() == 1 {
-      rw_conn.remove::<Book>(book).unwrap();
-      rw_conn.remove::<T>(data).unwrap();
-    } else if book_data.books_pk.len() > 1 {
-      for i in book_data.books_pk.clone() {
-        let book_for_deletion = crud::get_primary::<Book>(i).unwrap();
-        rw_conn.remove::<Book>(book_for_deletion).unwrap();
-      }
-      rw_conn.remove::<T>(data).unwrap();
+fn has_book_data_in(rw_conn: &RwTransaction, book_data_type: &BookDataType) -> CoreResult<bool> {
+  match book_data_type {
+    UniqueSize(book_size) => Ok(rw_conn.get().primary::<DataOfUnhashedBook>(*book_size)?.is_some()),
+    RepeatingSize(book_hash) => Ok(rw_conn.get().primary::<DataOfHashedBook>(book_hash.clone())?.is_some()),
+  }
+}
+/// Changes the data record shared by the book and its duplicates
+pub(crate) fn update_book_data(ctx: &Context, book: &Book, change_book_data: impl Fn(&mut BookData)) -> CoreResult<()> {
+  let rw_conn = ctx.db.rw_transaction()?;
+  update_book_data_in(&rw_conn, book, change_book_data)?;
+  Ok(rw_conn.commit()?)
+}
+pub(crate) fn update_book_data_in(rw_conn: &RwTransaction, book: &Book, change_book_data: impl Fn(&mut BookData))
+                                   -> CoreResult<()> {
+  let not_found = || CoreError::NotFound(format!("data of the book {}", book.path_to_book));
+  match &book.book_data_pk {
+    UniqueSize(book_size) => {
+      let old_book_data = rw_conn.get().primary::<DataOfUnhashedBook>(*book_size)?.ok_or_else(not_found)?;
+      let mut new_book_data = old_book_data.clone();
+      change_book_data(&mut new_book_data.book_data);
+      Ok(rw_conn.update(old_book_data, new_book_data)?)
     }
-  } else {
-    mark_book_paths_as_invalid(book_data.books_pk.clone());
+    RepeatingSize(book_hash) => {
+      let old_book_data = rw_conn.get().primary::<DataOfHashedBook>(book_hash.clone())?.ok_or_else(not_found)?;
+      let mut new_book_data = old_book_data.clone();
+      change_book_data(&mut new_book_data.book_data);
+      Ok(rw_conn.update(old_book_data, new_book_data)?)
+    }
+  }
+}
+
+pub(crate) fn del_book_and_its_data(ctx: &Context, book: Book) -> CoreResult<()> {
+  remove_book(ctx, book, true).map(|_| ())
+}
+/// Deletes the books in one transaction, e.g. the books of a removed dir
+pub(crate) fn del_books_and_their_data(ctx: &Context, books: Vec<Book>) -> CoreResult<()> {
+  let rw_conn = ctx.db.rw_transaction()?;
+  let mut events = vec![];
+  for book in books {
+    let (event, _) = remove_book_in(ctx, &rw_conn, book, true)?;
+    events.push(event);
+  }
+  rw_conn.commit()?;
+  for event in events {
+    ctx.emit(event);
+  }
+  Ok(())
+}
+/// The content of the book has changed, so it's added again under its new size and hash.
+/// Unless other copies keep them, the progress, favorites, tags, collections and bookmarks go along with it
+pub(crate) fn reidentify_book(ctx: &Context, book: Book, bookbuf: &PathBuf) -> CoreResult<()> {
+  let book_size = calc_file_size(bookbuf)?;
+  let mut book_hasher = BookHasher::new(bookbuf);
+  if !content_has_changed(ctx, &book, &mut book_hasher, book_size)? {
+    return Ok(());
   }
-  rw_conn.commit().unwrap();
+  hash_before_adding(ctx, book_size, &mut book_hasher)?;
+  let old_book_data_pk = book.book_data_pk.clone();
+  let mut after_commit = AfterCommit::default();
+  let rw_conn = ctx.db.rw_transaction()?;
+  let (event, removed_book) = remove_book_in(ctx, &rw_conn, book, false)?;
+  after_commit.events.push(event);
+  // The outline of the old content goes along with its data record, the copies still sharing it keep the outline
+  if !has_book_data_in(&rw_conn, &old_book_data_pk)? {
+    remove_outline(&rw_conn, &old_book_data_pk.as_key())?;
+  }
+  add_new_book(ctx, &rw_conn, bookbuf, book_size, &mut book_hasher, &mut after_commit)?;
+  if let Some(removed_book) = removed_book {
+    crud::removed_books::restore_removed_book(&rw_conn, path_to_string(bookbuf)?, removed_book)?;
+  }
+  rw_conn.commit()?;
+  after_commit.send(ctx);
+  Ok(())
+}
+/// Same size and the same known hash mean the book was only touched or rewritten with the same content
+fn content_has_changed(ctx: &Context, book: &Book, book_hasher: &mut BookHasher, book_size: BookSize)
+                       -> CoreResult<bool> {
+  let (old_size, old_hash) = match &book.book_data_pk {
+    UniqueSize(old_size) => {
+      let old_hash = crud::get_primary::<DataOfUnhashedBook>(ctx, *old_size)?.and_then(|data| data.book_hash);
+      (Some(*old_size), old_hash)
+    }
+    RepeatingSize(old_hash) => {
+      let old_size = crud::get_primary::<DataOfHashedBook>(ctx, old_hash.clone())?.map(|data| data.book_size);
+      (old_size, Some(old_hash.clone()))
+    }
+  };
+  match (old_size, old_hash) {
+    (Some(old_size), Some(old_hash)) if old_size == book_size => Ok(book_hasher.hash_like(&old_hash)? != old_hash),
+    _ => Ok(true),
+  }
+}
+/// Returns the removed book the data of the last copy has gone to
+fn remove_book(ctx: &Context, book: Book, keep_missing_book: bool) -> CoreResult<Option<RemovedBook>> {
+  let rw_conn = ctx.db.rw_transaction()?;
+  let (event, removed_book) = remove_book_in(ctx, &rw_conn, book, keep_missing_book)?;
+  rw_conn.commit()?;
+  ctx.emit(event);
+  Ok(removed_book)
 }
-fn remove_thumbnail(book_data_type: &BookDataType) {
+/// The data record is read in the transaction, so the books removed before in it are taken into account
+fn remove_book_in(ctx: &Context, rw_conn: &RwTransaction, book: Book, keep_missing_book: bool)
+                  -> CoreResult<(CoreEvent, Option<RemovedBook>)> {
+  let book_data_type = book.book_data_pk.clone();
   match book_data_type {
     UniqueSize(book_size) => {
-      remove_file(APP_DIRS.read().unwrap().dir_of_unhashed_books.join(book_size)).unwrap()
+      match rw_conn.get().primary::<DataOfUnhashedBook>(book_size)? {
+        None => { remove_book_without_data(rw_conn, book) }
+        Some(book_data) => { delete_books_and_their_data(ctx, rw_conn, book_data, book, keep_missing_book) }
+      }
     }
     RepeatingSize(book_hash) => {
-      remove_file(APP_DIRS.read().unwrap().dir_of_hashed_books.join(book_hash)).unwrap()
+      match rw_conn.get().primary::<DataOfHashedBook>(book_hash)? {
+        None => { remove_book_without_data(rw_conn, book) }
+        Some(book_data) => { delete_books_and_their_data(ctx, rw_conn, book_data, book, keep_missing_book) }
+      }
+    }
+  }
+}
+fn remove_book_without_data(rw_conn: &RwTransaction, book: Book) -> CoreResult<(CoreEvent, Option<RemovedBook>)> {
+  let book = rw_conn.remove(book)?;
+  Ok((CoreEvent::BookRemoved { book_path: book.path_to_book, book_data_key: book.book_data_pk.as_key() }, None))
+}
+/// Removes the book, its data stays with the other copies of the book. The data of the last copy is kept
+/// as a removed book, or in the db along with the missing book if `keep_missing_book` is set
+/// and the user has done anything with the book
+fn delete_books_and_their_data<T: ToInput + GetBookData + Clone>(ctx: &Context, rw_conn: &RwTransaction, data: T,
+                                                                  book: Book, keep_missing_book: bool)
+                                                                  -> CoreResult<(CoreEvent, Option<RemovedBook>)> {
+  let book_data = data.get_book_data_as_ref();
+  let book_data_key = book.book_data_pk.as_key();
+  let other_copies = book_data.books_pk.iter().filter(|book_path| **book_path != book.path_to_book).cloned()
+    .collect_vec();
+  let mut removed_book = None;
+  let event = if !other_copies.is_empty() {
+    let mut new_data = data.clone();
+    new_data.get_book_data_as_mut().books_pk = other_copies;
+    rw_conn.update::<T>(data, new_data)?;
+    let book = rw_conn.remove::<Book>(book)?;
+    CoreEvent::BookRemoved { book_path: book.path_to_book, book_data_key }
+  } else if keep_missing_book && book_data.has_user_data() {
+    mark_book_paths_as_invalid(rw_conn, vec![book.path_to_book.clone()])?;
+    CoreEvent::BookMissing { book_path: book.path_to_book, book_data_key }
+  } else {
+    // The key of the data may go to another book of the same size, so what is derived from the book is dropped
+    if book_data.cached {
+      remove_thumbnail(ctx, &book.book_data_pk);
+    }
+    if let Some(book_outline) = rw_conn.get().primary::<BookOutline>(book_data_key.clone())? {
+      rw_conn.remove::<BookOutline>(book_outline)?;
     }
+    crud::search_index::remove_from_index(rw_conn, &book_data_key)?;
+    let mut removed_book_data = book_data.clone();
+    removed_book_data.books_pk = vec![];
+    removed_book = Some(crud::removed_books::keep_removed_book(rw_conn, &book, data.get_book_size(),
+                                                               data.get_book_hash(), removed_book_data)?);
+    rw_conn.remove::<T>(data)?;
+    let book = rw_conn.remove::<Book>(book)?;
+    CoreEvent::BookRemoved { book_path: book.path_to_book, book_data_key }
   };
+  Ok((event, removed_book))
 }
-fn mark_book_paths_as_invalid(books_pk: Vec<BookPath>) {
-  books_pk.into_iter().for_each(|book_path| {
-    match crud::get_primary::<Book>(book_path) {
+fn remove_thumbnail(ctx: &Context, book_data_type: &BookDataType) {
+  let path_to_thumbnail = get_thumbnail_path(ctx, book_data_type);
+  match remove_file(&path_to_thumbnail) {
+    Ok(_) => {}
+    Err(e) if e.kind() == ErrorKind::NotFound => {}
+    Err(e) => { error!("failed to remove the thumbnail {}: {e}", path_to_thumbnail.display()); }
+  }
+}
+fn mark_book_paths_as_invalid(rw_conn: &RwTransaction, books_pk: Vec<BookPath>) -> CoreResult<()> {
+  for book_path in books_pk {
+    match rw_conn.get().primary::<Book>(book_path)? {
       None => {}
       Some(old_book) => {
         let mut new_book = old_book.clone();
         new_book.path_is_valid = false;
-        crud::update::<Book>(old_book, new_book).unwrap()
+        rw_conn.update::<Book>(old_book, new_book)?;
       }
     }
-  });
+  }
+  Ok(())
 }
 
-pub(crate) fn add_book(bookbuf: &PathBuf, book_size: BookSize) {
-  let (
-    db_book_count_with_this_size,
-    data_of_unhashed_book
-  ) = get_num_of_books_of_this_size(book_size.clone());
-
-  if db_book_count_with_this_size == 0 {
-    add_unique_size_book(bookbuf, book_size);
-  } else if db_book_count_with_this_size == 1 {
-    add_book_to_an_existing_one(bookbuf, book_size, data_of_unhashed_book.unwrap());
-  } else if db_book_count_with_this_size > 1 {
-    add_book_of_repeating_size(bookbuf, book_size);
-  }
-}
-fn add_unique_size_book(bookbuf: &PathBuf, book_size: BookSize) {
-  let book_path = bookbuf.to_str().unwrap().to_string();
-  let book_data_type = UniqueSize(book_size.clone());
-  crud::insert::<DataOfUnhashedBook>(DataOfUnhashedBook::new(book_size, vec![book_path.clone()])).unwrap();
-  crud::insert::<Book>(Book::from_pathbuf(bookbuf, book_data_type.clone())).unwrap();
-  NotCachedBook::new(book_path).push_to_storage();
-}
-fn add_book_to_an_existing_one(bookbuf: &PathBuf, book_size: BookSize, data_of_unhashed_book: DataOfUnhashedBook) {
-  let path_of_other_book = &data_of_unhashed_book.book_data.books_pk[0];
-  let path_of_new_book = bookbuf.to_str().unwrap().to_string();
+/// Adds the book. If it is a copy of a missing or recently removed book, e.g. the book was moved by deleting
+/// and creating it, the book takes the place of the missing one or gets the data of the removed one back
+pub(crate) fn add_book(ctx: &Context, bookbuf: &PathBuf, book_size: BookSize) -> CoreResult<()> {
+  add_book_with_hasher(ctx, bookbuf, book_size, &mut BookHasher::new(bookbuf))
+}
+/// [`add_book`] with the hashes of the book calculated beforehand, e.g. in parallel with the other books.
+/// The book is added in one transaction, the files are hashed before it
+pub(crate) fn add_book_with_hasher(ctx: &Context, bookbuf: &PathBuf, book_size: BookSize,
+                                   book_hasher: &mut BookHasher) -> CoreResult<()> {
+  let missing_books = get_missing_books_of_this_size(ctx, book_size)?;
+  let removed_books = crud::removed_books::get_removed_books_of_this_size(ctx, book_size)?;
+  if let Some(missing_book) = find_copy(book_hasher, missing_books, |(book, _)| &book.path_to_book,
+                                        |(_, book_hash)| book_hash.as_ref())? {
+    return move_book(ctx, missing_book.0, bookbuf);
+  }
+  let removed_book = find_copy(book_hasher, removed_books, |removed_book| &removed_book.book_path,
+                               |removed_book| removed_book.book_hash.as_ref())?;
+  hash_before_adding(ctx, book_size, book_hasher)?;
+  let mut after_commit = AfterCommit::default();
+  let rw_conn = ctx.db.rw_transaction()?;
+  add_new_book(ctx, &rw_conn, bookbuf, book_size, book_hasher, &mut after_commit)?;
+  if let Some(removed_book) = removed_book {
+    crud::removed_books::restore_removed_book(&rw_conn, path_to_string(bookbuf)?, removed_book)?;
+  }
+  rw_conn.commit()?;
+  after_commit.send(ctx);
+  Ok(())
+}
+/// Adds the books of a unique size along with their data in one transaction
+pub(crate) fn add_books_of_unique_size(ctx: &Context, books: Vec<Book>, data: Vec<DataOfUnhashedBook>)
+                                       -> CoreResult<()> {
+  let rw_conn = ctx.db.rw_transaction()?;
+  for book in books {
+    rw_conn.insert(book)?;
+  }
+  for book_data in data {
+    rw_conn.insert(book_data)?;
+  }
+  Ok(rw_conn.commit()?)
+}
+/// Hashes the book in the modes of the hashes it's going to be compared with, so no file is read
+/// while the db is locked for writing. The hash of the only other book of this size is saved as its cache
+fn hash_before_adding(ctx: &Context, book_size: BookSize, book_hasher: &mut BookHasher) -> CoreResult<()> {
+  match get_num_of_books_of_this_size(ctx, book_size)? {
+    (0, _) => {}
+    (1, Some(data_of_unhashed_book)) => {
+      let hash_of_other_book = match &data_of_unhashed_book.book_hash {
+        Some(book_hash) => { book_hash.clone() }
+        None => {
+          let book_hash = calc_book_hash(ctx, Path::new(&data_of_unhashed_book.book_data.books_pk[0]))?;
+          let mut new_data = data_of_unhashed_book.clone();
+          new_data.book_hash = Some(book_hash.clone());
+          crud::update(ctx, data_of_unhashed_book, new_data)?;
+          book_hash
+        }
+      };
+      book_hasher.hash_like(&hash_of_other_book)?;
+    }
+    _ => {
+      let r_conn = ctx.db.r_transaction()?;
+      let data_of_books_of_this_size: Vec<DataOfHashedBook> = r_conn.scan()
+        .secondary(DataOfHashedBookKey::book_size)?.range(book_size..=book_size)?.try_collect()?;
+      drop(r_conn);
+      for data in data_of_books_of_this_size {
+        book_hasher.hash_like(&data.book_hash)?;
+      }
+      book_hasher.hash_by_settings(ctx, book_size)?;
+    }
+  }
+  Ok(())
+}
+/// Books of this size whose files are missing, with their hash if it is known
+fn get_missing_books_of_this_size(ctx: &Context, book_size: BookSize) -> CoreResult<Vec<(Book, Option<BookHash>)>> {
+  let mut books_pk_with_hash: Vec<(BookPath, Option<BookHash>)> = vec![];
+  if let Some(data) = crud::get_primary::<DataOfUnhashedBook>(ctx, book_size)? {
+    let book_hash = data.book_hash;
+    books_pk_with_hash.extend(data.book_data.books_pk.into_iter().map(|book_path| (book_path, book_hash.clone())));
+  }
+  let r_conn = ctx.db.r_transaction()?;
+  let data_of_hashed_books: Vec<DataOfHashedBook> = r_conn.scan()
+    .secondary(DataOfHashedBookKey::book_size)?.range(book_size..=book_size)?.try_collect()?;
+  for data in data_of_hashed_books {
+    books_pk_with_hash.extend(data.book_data.books_pk.into_iter().map(|book_path| {
+      (book_path, Some(data.book_hash.clone()))
+    }));
+  }
+  let mut missing_books = vec![];
+  for (book_path, book_hash) in books_pk_with_hash {
+    if let Some(book) = r_conn.get().primary::<Book>(book_path)? {
+      if !book.path_is_valid {
+        missing_books.push((book, book_hash));
+      }
+    }
+  }
+  Ok(missing_books)
+}
+/// The candidate with the same hash as the book or, if the hash of the candidate is unknown,
+/// with the same file name
+fn find_copy<T>(book_hasher: &mut BookHasher, candidates: Vec<T>, get_path: impl Fn(&T) -> &BookPath,
+                get_hash: impl Fn(&T) -> Option<&BookHash>) -> CoreResult<Option<T>> {
+  let mut unhashed = vec![];
+  for candidate in candidates {
+    match get_hash(&candidate) {
+      None => { unhashed.push(candidate); }
+      Some(book_hash) => {
+        if &book_hasher.hash_like(book_hash)? == book_hash {
+          return Ok(Some(candidate));
+        }
+      }
+    }
+  }
+  let file_name = book_hasher.path_to_book.file_name();
+  Ok(unhashed.into_iter().find(|candidate| Path::new(get_path(candidate)).file_name() == file_name))
+}
+/// Moves the book to the new path, the book keeps its data record
+pub(crate) fn move_book(ctx: &Context, old_book: Book, new_path: &PathBuf) -> CoreResult<()> {
+  move_books(ctx, vec![(old_book, new_path.clone())])
+}
+/// Moves the books in one transaction, e.g. the books of a renamed dir
+pub(crate) fn move_books(ctx: &Context, moves: Vec<(Book, PathBuf)>) -> CoreResult<()> {
+  let rw_conn = ctx.db.rw_transaction()?;
+  let mut events = vec![];
+  for (old_book, new_path) in moves {
+    let mut new_book = Book::from_pathbuf(ctx, &new_path, old_book.book_data_pk.clone())?;
+    new_book.added_at = old_book.added_at;
+    let old_path = old_book.path_to_book.clone();
+    let new_path = new_book.path_to_book.clone();
+    rw_conn.update(old_book, new_book.clone())?;
+    update_book_data_in(&rw_conn, &new_book, |book_data| {
+      for book_path in book_data.books_pk.iter_mut().filter(|book_path| **book_path == old_path) {
+        *book_path = new_path.clone();
+      }
+    })?;
+    events.push(CoreEvent::BookMoved { old_path, new_path, book_data_key: new_book.book_data_pk.as_key() });
+  }
+  rw_conn.commit()?;
+  for event in events {
+    ctx.emit(event);
+  }
+  Ok(())
+}
+/// What a change of the library sends out once its transaction is committed
+#[derive(Default)]
+struct AfterCommit {
+  events: Vec<CoreEvent>,
+  not_cached_books: Vec<BookPath>,
+}
+impl AfterCommit {
+  fn send(self, ctx: &Context) {
+    for event in self.events {
+      ctx.emit(event);
+    }
+    for book_path in self.not_cached_books {
+      NotCachedBook::new(book_path).push_to_storage(ctx);
+    }
+  }
+}
+fn add_new_book(ctx: &Context, rw_conn: &RwTransaction, bookbuf: &PathBuf, book_size: BookSize,
+                book_hasher: &mut BookHasher, after_commit: &mut AfterCommit) -> CoreResult<()> {
+  match get_num_of_books_of_this_size_in(rw_conn, book_size)? {
+    (0, _) => add_unique_size_book(ctx, rw_conn, bookbuf, book_size, book_hasher, after_commit),
+    (1, Some(data_of_unhashed_book)) => {
+      add_book_to_an_existing_one(ctx, rw_conn, bookbuf, book_size, data_of_unhashed_book, book_hasher, after_commit)
+    }
+    _ => add_book_of_repeating_size(ctx, rw_conn, bookbuf, book_size, book_hasher, after_commit),
+  }
+}
+fn add_unique_size_book(ctx: &Context, rw_conn: &RwTransaction, bookbuf: &PathBuf, book_size: BookSize,
+                        book_hasher: &BookHasher, after_commit: &mut AfterCommit) -> CoreResult<()> {
+  let book = Book::from_pathbuf(ctx, bookbuf, UniqueSize(book_size))?;
+  let book_path = book.path_to_book.clone();
+  let book_data_key = book.book_data_pk.as_key();
+  let book_data = DataOfUnhashedBook::new(book_size, vec![book_path.clone()]);
+  rw_conn.insert::<DataOfUnhashedBook>(DataOfUnhashedBook { book_hash: book_hasher.known_hash(), ..book_data })?;
+  rw_conn.insert::<Book>(book)?;
+  after_commit.events.push(CoreEvent::BookAdded { book_path: book_path.clone(), book_data_key });
+  after_commit.not_cached_books.push(book_path);
+  Ok(())
+}
+fn add_book_to_an_existing_one(ctx: &Context, rw_conn: &RwTransaction, bookbuf: &PathBuf, book_size: BookSize,
+                               data_of_unhashed_book: DataOfUnhashedBook, book_hasher: &mut BookHasher,
+                               after_commit: &mut AfterCommit) -> CoreResult<()> {
+  let path_of_other_book = data_of_unhashed_book.book_data.books_pk[0].clone();
+  let path_of_new_book = path_to_string(bookbuf)?;
   let hash_of_other_book = match &data_of_unhashed_book.book_hash {
-    None => { calc_file_hash(bookbuf) }
+    None => { calc_book_hash(ctx, Path::new(&path_of_other_book))? }
     Some(hash_of_previus_book) => { hash_of_previus_book.clone() }
   };
-  let hash_of_new_book = calc_file_hash(bookbuf);
-  match hash_of_other_book.eq(&hash_of_new_book) {
-    true => {
-      update_book_data_type(path_of_other_book.clone(), RepeatingSize(hash_of_new_book.clone()));
-      data_of_unhashed_book.replace_to_data_of_hashed_book(hash_of_new_book.clone());
-    }
-    false => {
-      update_book_data_type(path_of_other_book.clone(), RepeatingSize(hash_of_other_book.clone()));
-      data_of_unhashed_book.replace_to_data_of_hashed_book(hash_of_other_book);
-
-      let new_book_data =
-        DataOfHashedBook::new(hash_of_new_book.clone(), book_size, vec![path_of_new_book.clone()]);
-      crud::insert::<DataOfHashedBook>(new_book_data).unwrap();
+  let hash_of_new_book = book_hasher.hash_like(&hash_of_other_book)?;
+  let new_book = Book::from_pathbuf(ctx, bookbuf, RepeatingSize(hash_of_new_book.clone()))?;
+  let book_data_key = new_book.book_data_pk.as_key();
+  let data_of_other_book = RepeatingSize(hash_of_other_book.clone());
+  let data_key_changed = CoreEvent::BookDataKeyChanged {
+    book_path: path_of_other_book.clone(),
+    old_book_data_key: UniqueSize(book_size).as_key(),
+    new_book_data_key: data_of_other_book.as_key(),
+  };
+  update_book_data_type(rw_conn, path_of_other_book, data_of_other_book)?;
+  data_of_unhashed_book.replace_to_data_of_hashed_book(rw_conn, hash_of_other_book.clone())?;
+  if hash_of_other_book != hash_of_new_book {
+    let new_book_data = DataOfHashedBook::new(hash_of_new_book, book_size, vec![path_of_new_book.clone()]);
+    rw_conn.insert::<DataOfHashedBook>(new_book_data)?;
+  }
+  rw_conn.insert::<Book>(new_book)?;
+  after_commit.events.push(data_key_changed);
+  after_commit.events.push(CoreEvent::BookAdded { book_path: path_of_new_book.clone(), book_data_key });
+  after_commit.not_cached_books.push(path_of_new_book);
+  Ok(())
+}
+fn add_book_of_repeating_size(ctx: &Context, rw_conn: &RwTransaction, bookbuf: &PathBuf, book_size: BookSize,
+                              book_hasher: &mut BookHasher, after_commit: &mut AfterCommit) -> CoreResult<()> {
+  // The copies of the book may have been hashed in the other mode
+  let data_of_books_of_this_size: Vec<DataOfHashedBook> = rw_conn.scan()
+    .secondary(DataOfHashedBookKey::book_size)?.range(book_size..=book_size)?.try_collect()?;
+  let mut data_of_copies = None;
+  for data in data_of_books_of_this_size {
+    if book_hasher.hash_like(&data.book_hash)? == data.book_hash {
+      data_of_copies = Some(data);
+      break;
     }
+  }
+  let hash_of_new_book = match &data_of_copies {
+    None => { book_hasher.hash_by_settings(ctx, book_size)? }
+    Some(data) => { data.book_hash.clone() }
   };
-  let new_book = Book::from_pathbuf(bookbuf, RepeatingSize(hash_of_new_book));
-  crud::insert::<Book>(new_book).unwrap();
-  NotCachedBook::new(path_of_new_book).push_to_storage();
-}
-fn add_book_of_repeating_size(bookbuf: &PathBuf, book_size: BookSize) {
-  let book_path = bookbuf.to_str().unwrap().to_string();
-  let hash_of_new_book = calc_file_hash(bookbuf);
-  match crud::get_primary::<DataOfHashedBook>(hash_of_new_book.clone()) {
+  let new_book = Book::from_pathbuf(ctx, bookbuf, RepeatingSize(hash_of_new_book.clone()))?;
+  let book_path = new_book.path_to_book.clone();
+  let book_data_key = new_book.book_data_pk.as_key();
+  let cached = match data_of_copies {
     None => {
-      let new_book_data = DataOfHashedBook::new(hash_of_new_book.clone(), book_size, vec![book_path.clone()]);
-      crud::insert::<DataOfHashedBook>(new_book_data).unwrap();
-      crud::insert::<Book>(Book::from_pathbuf(bookbuf, RepeatingSize(hash_of_new_book))).unwrap();
-      NotCachedBook::new(book_path).push_to_storage();
-    }
-    Some(data_of_hashed_book) => {
-      crud::insert::<Book>(Book::from_pathbuf(bookbuf, RepeatingSize(hash_of_new_book))).unwrap();
-      match &data_of_hashed_book.book_data.cached {
-        true => {}
-        false => { NotCachedBook::new(book_path).push_to_storage(); }
-      }
+      let new_book_data = DataOfHashedBook::new(hash_of_new_book, book_size, vec![book_path.clone()]);
+      rw_conn.insert::<DataOfHashedBook>(new_book_data)?;
+      false
     }
+    Some(data_of_hashed_book) => { data_of_hashed_book.book_data.cached }
   };
+  rw_conn.insert::<Book>(new_book)?;
+  after_commit.events.push(CoreEvent::BookAdded { book_path: book_path.clone(), book_data_key });
+  if !cached {
+    after_commit.not_cached_books.push(book_path);
+  }
+  Ok(())
 }
 
-pub(crate) fn get_books_located_in_dir(path_to_dir: String) -> Vec<Book> {
-  let r_conn = DB.r_transaction().unwrap();
-  let books: Vec<Book> = r_conn.scan().primary().unwrap()
-    .start_with(path_to_dir).unwrap().try_collect().unwrap();
-  books
-}
-pub(crate) fn update_the_books_directory(old_dir_path: &PathBuf, new_dir_path: &PathBuf) {
-  for old_book in get_books_located_in_dir(old_dir_path.to_str().unwrap().to_string()) {
-    let mut new_book = old_book.clone();
-    new_book.dir_name = new_dir_path.file_name().unwrap().to_str().unwrap().to_string();
-    new_book.path_to_dir = new_dir_path.to_str().unwrap().to_string();
-    new_book.path_to_book = new_dir_path.join(&old_book.book_name).to_str().unwrap().to_string();
-    crud::update(old_book, new_book).unwrap();
+pub(crate) fn get_books_located_in_dir(ctx: &Context, path_to_dir: String) -> CoreResult<Vec<Book>> {
+  let r_conn = ctx.db.r_transaction()?;
+  let books: Vec<Book> = r_conn.scan().primary()?.start_with(path_to_dir.clone())?.try_collect()?;
+  // The prefix scan also catches the books of sibling dirs like `books2` for `books`
+  Ok(books.into_iter().filter(|book| Path::new(&book.path_to_book).starts_with(&path_to_dir)).collect())
+}
+/// Returns the table of contents of the book, loading it from the book on the first call
+pub(crate) fn get_outline(ctx: &Context, book: &Book) -> CoreResult<Vec<Outline>> {
+  let book_data_key = book.book_data_pk.as_key();
+  match crud::get_primary::<BookOutline>(ctx, book_data_key.clone())? {
+    Some(book_outline) => Ok(book_outline.outlines),
+    None => {
+      let outlines = Document::open(&book.path_to_book, 20).and_then(|doc| doc.outlines())
+        .map_err(CoreError::Document)?;
+      crud::insert(ctx, BookOutline { book_data_key, outlines: outlines.clone() })?;
+      Ok(outlines)
+    }
+  }
+}
+/// Moves the cached outline to the new key of the book data, e.g. when a unique size book gets a hash
+pub(crate) fn relink_outline(rw_conn: &RwTransaction, old_book_data_key: &String, new_book_data_key: &String)
+                             -> CoreResult<()> {
+  if let Some(book_outline) = rw_conn.get().primary::<BookOutline>(old_book_data_key.clone())? {
+    let book_outline = rw_conn.remove(book_outline)?;
+    if let Some(stale_book_outline) = rw_conn.get().primary::<BookOutline>(new_book_data_key.clone())? {
+      rw_conn.remove(stale_book_outline)?;
+    }
+    rw_conn.insert(BookOutline { book_data_key: new_book_data_key.clone(), outlines: book_outline.outlines })?;
+  }
+  Ok(())
+}
+fn remove_outline(rw_conn: &RwTransaction, book_data_key: &String) -> CoreResult<()> {
+  if let Some(book_outline) = rw_conn.get().primary::<BookOutline>(book_data_key.clone())? {
+    rw_conn.remove(book_outline)?;
   }
+  Ok(())
 }
@@ -0,0 +1,84 @@
+use crate::context::Context;
+use crate::db::crud;
+use crate::error::CoreResult;
+use crate::models::{Book, BookMark, BookMarkKey};
+use crate::types::BookPath;
+use crate::utils::get_timestamp;
+use itertools::Itertools;
+use native_db::transaction::RwTransaction;
+
+
+pub(crate) fn get_bookmarks_by_data_key(ctx: &Context, book_data_key: &String) -> CoreResult<Vec<BookMark>> {
+  let r_conn = ctx.db.r_transaction()?;
+  let bookmarks: Vec<BookMark> = r_conn.scan().secondary(BookMarkKey::book_data_key)?
+    .start_with(book_data_key.clone())?.try_collect()?;
+  Ok(bookmarks.into_iter()
+    .filter(|bookmark| &bookmark.book_data_key == book_data_key)
+    .sorted_by_key(|bookmark| (bookmark.page_number, bookmark.id))
+    .collect())
+}
+
+pub(crate) fn get_bookmarks(ctx: &Context, book_path: BookPath) -> CoreResult<Vec<BookMark>> {
+  match crud::get_primary::<Book>(ctx, book_path)? {
+    None => { Ok(vec![]) }
+    Some(book) => { get_bookmarks_by_data_key(ctx, &book.book_data_pk.as_key()) }
+  }
+}
+
+pub(crate) fn add_bookmark(ctx: &Context, book_path: BookPath, title: String, content: String, page_number: i32)
+                           -> CoreResult<Option<BookMark>> {
+  let book = match crud::get_primary::<Book>(ctx, book_path)? {
+    None => { return Ok(None); }
+    Some(book) => { book }
+  };
+  let rw_conn = ctx.db.rw_transaction()?;
+  let last_bookmark: Option<BookMark> = rw_conn.scan().primary::<BookMark>()?.all()?.last().transpose()?;
+  let timestamp = get_timestamp();
+  let bookmark = BookMark {
+    id: last_bookmark.map_or(1, |bookmark| bookmark.id + 1),
+    title,
+    content,
+    page_number,
+    book_data_key: book.book_data_pk.as_key(),
+    time_created: timestamp,
+    time_updated: timestamp,
+  };
+  rw_conn.insert(bookmark.clone())?;
+  rw_conn.commit()?;
+  Ok(Some(bookmark))
+}
+
+pub(crate) fn edit_bookmark(ctx: &Context, id: u64, title: String, content: String, page_number: i32)
+                            -> CoreResult<Option<BookMark>> {
+  let old_bookmark = match crud::get_primary::<BookMark>(ctx, id)? {
+    None => { return Ok(None); }
+    Some(bookmark) => { bookmark }
+  };
+  let mut new_bookmark = old_bookmark.clone();
+  new_bookmark.title = title;
+  new_bookmark.content = content;
+  new_bookmark.page_number = page_number;
+  new_bookmark.time_updated = get_timestamp();
+  crud::update(ctx, old_bookmark, new_bookmark.clone())?;
+  Ok(Some(new_bookmark))
+}
+
+pub(crate) fn del_bookmark(ctx: &Context, id: u64) -> CoreResult<Option<BookMark>> {
+  match crud::get_primary::<BookMark>(ctx, id)? {
+    None => { Ok(None) }
+    Some(bookmark) => { crud::remove(ctx, bookmark).map(Some) }
+  }
+}
+
+/// Moves bookmarks to the new book data record, e.g. when a unique size book gets a hash
+pub(crate) fn relink_bookmarks(rw_conn: &RwTransaction, old_book_data_key: &String, new_book_data_key: &String)
+                               -> CoreResult<()> {
+  let bookmarks: Vec<BookMark> = rw_conn.scan().secondary(BookMarkKey::book_data_key)?
+    .start_with(old_book_data_key.clone())?.try_collect()?;
+  for old_bookmark in bookmarks.into_iter().filter(|bookmark| &bookmark.book_data_key == old_book_data_key) {
+    let mut new_bookmark = old_bookmark.clone();
+    new_bookmark.book_data_key = new_book_data_key.clone();
+    rw_conn.update(old_bookmark, new_bookmark)?;
+  }
+  Ok(())
+}
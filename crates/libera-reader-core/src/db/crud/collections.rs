@@ -0,0 +1,95 @@
+use crate::context::Context;
+use crate::db::crud;
+use crate::error::CoreResult;
+use crate::models::{Book, Collection};
+use crate::types::BookPath;
+use crate::utils::get_timestamp;
+use itertools::Itertools;
+
+
+/// Collections sorted by name
+pub(crate) fn get_collections(ctx: &Context) -> CoreResult<Vec<Collection>> {
+  let r_conn = ctx.db.r_transaction()?;
+  let collections: Vec<Collection> = r_conn.scan().primary()?.all()?.try_collect()?;
+  Ok(collections.into_iter().sorted_by_key(|collection| collection.name.to_lowercase()).collect())
+}
+
+pub(crate) fn create_collection(ctx: &Context, name: String) -> CoreResult<Collection> {
+  let rw_conn = ctx.db.rw_transaction()?;
+  let last_collection: Option<Collection> = rw_conn.scan().primary::<Collection>()?.all()?.last().transpose()?;
+  let collection = Collection {
+    id: last_collection.map_or(1, |collection| collection.id + 1),
+    name,
+    time_created: get_timestamp(),
+  };
+  rw_conn.insert(collection.clone())?;
+  rw_conn.commit()?;
+  Ok(collection)
+}
+
+pub(crate) fn rename_collection(ctx: &Context, id: u64, name: String) -> CoreResult<Option<Collection>> {
+  let old_collection = match crud::get_primary::<Collection>(ctx, id)? {
+    None => { return Ok(None); }
+    Some(collection) => { collection }
+  };
+  let mut new_collection = old_collection.clone();
+  new_collection.name = name;
+  crud::update(ctx, old_collection, new_collection.clone())?;
+  Ok(Some(new_collection))
+}
+
+/// Deletes the collection, the books themselves stay in the library
+pub(crate) fn delete_collection(ctx: &Context, id: u64) -> CoreResult<Option<Collection>> {
+  let collection = match crud::get_primary::<Collection>(ctx, id)? {
+    None => { return Ok(None); }
+    Some(collection) => { collection }
+  };
+  for book_data in crud::book::get_all_book_data(ctx)? {
+    if book_data.collections.contains(&id) {
+      if let Some(book) = crud::book::get_main_book(ctx, &book_data)? {
+        crud::book::update_book_data(ctx, &book, |book_data| book_data.collections.retain(|&other_id| other_id != id))?;
+      }
+    }
+  }
+  crud::remove(ctx, collection).map(Some)
+}
+
+/// Returns the number of books found in the db,
+/// `None` if the collection doesn't exist
+pub(crate) fn add_to_collection(ctx: &Context, books_path: Vec<BookPath>, id: u64) -> CoreResult<Option<usize>> {
+  if crud::get_primary::<Collection>(ctx, id)?.is_none() {
+    return Ok(None);
+  }
+  update_books(ctx, books_path, |collections| {
+    if !collections.contains(&id) {
+      collections.push(id);
+    }
+  }).map(Some)
+}
+
+pub(crate) fn remove_from_collection(ctx: &Context, books_path: Vec<BookPath>, id: u64) -> CoreResult<usize> {
+  update_books(ctx, books_path, |collections| collections.retain(|&other_id| other_id != id))
+}
+
+/// Books of the collection, one per group of duplicates
+pub(crate) fn get_books_in_collection(ctx: &Context, id: u64) -> CoreResult<Vec<Book>> {
+  let mut books = vec![];
+  for book_data in crud::book::get_all_book_data(ctx)? {
+    if book_data.collections.contains(&id) {
+      books.extend(crud::book::get_main_book(ctx, &book_data)?);
+    }
+  }
+  Ok(books.into_iter().sorted_by(|a, b| a.book_name.cmp(&b.book_name)).collect())
+}
+
+fn update_books(ctx: &Context, books_path: Vec<BookPath>, change_collections: impl Fn(&mut Vec<u64>))
+                -> CoreResult<usize> {
+  let mut num_of_books = 0;
+  for book_path in books_path {
+    if let Some(book) = crud::get_primary::<Book>(ctx, book_path)? {
+      crud::book::update_book_data(ctx, &book, |book_data| change_collections(&mut book_data.collections))?;
+      num_of_books += 1;
+    }
+  }
+  Ok(num_of_books)
+}
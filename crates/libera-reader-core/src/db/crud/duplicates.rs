@@ -0,0 +1,181 @@
+use crate::context::Context;
+use crate::db::crud;
+use crate::error::{CoreError, CoreResult};
+use crate::models::{Book, CoreEvent, DataOfHashedBook, DuplicateAction, DuplicateCopy, DuplicateGroup,
+                    RemovedDuplicates};
+use crate::types::{BookHash, BookPath};
+use itertools::Itertools;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+use tracing::error;
+
+
+/// Groups of identical books that have more than one copy in the library
+pub(crate) fn get_duplicates(ctx: &Context) -> CoreResult<Vec<DuplicateGroup>> {
+  let r_conn = ctx.db.r_transaction()?;
+  let data_of_hashed_books: Vec<DataOfHashedBook> = r_conn.scan().primary()?.all()?.try_collect()?;
+  Ok(data_of_hashed_books.into_iter()
+    .filter(|data| data.book_data.books_pk.len() > 1)
+    .map(|data| DuplicateGroup {
+      copies: data.book_data.books_pk.iter().map(|book_path| get_copy_info(book_path)).collect(),
+      book_hash: data.book_hash,
+      book_size: data.book_size,
+      title: data.book_data.title,
+    })
+    .sorted_by(|a, b| a.title.cmp(&b.title))
+    .collect())
+}
+
+fn get_copy_info(book_path: &BookPath) -> DuplicateCopy {
+  let metadata = fs::metadata(book_path).ok();
+  DuplicateCopy {
+    path_to_book: book_path.clone(),
+    size_in_bytes: metadata.as_ref().map(|metadata| metadata.len()),
+    modified_at: metadata.and_then(|metadata| metadata.modified().ok())
+      .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+      .map(|duration| duration.as_secs()),
+  }
+}
+
+/// Deletes, trashes or moves every copy of the book except `path_to_kept_book`.
+/// The data record is shared, so favorites, bookmarks and history stay with the kept copy.
+/// Returns the paths of the removed copies
+pub(crate) fn remove_redundant_copies(ctx: &Context, book_hash: &BookHash, path_to_kept_book: &BookPath,
+                                      action: &DuplicateAction) -> CoreResult<Vec<BookPath>> {
+  let r_conn = ctx.db.r_transaction()?;
+  let data = r_conn.get().primary::<DataOfHashedBook>(book_hash.clone())?
+    .ok_or(CoreError::NotFound(format!("duplicate group {book_hash}")))?;
+  let kept_book = r_conn.get().primary::<Book>(path_to_kept_book.clone())?;
+  drop(r_conn);
+  if !data.book_data.books_pk.contains(path_to_kept_book) {
+    return Err(CoreError::InvalidArgument(format!("{path_to_kept_book} is not a copy of the book {book_hash}")));
+  }
+  // A stale path would leave the user without any copy of the book
+  match kept_book {
+    Some(kept_book) if kept_book.path_is_valid && Path::new(path_to_kept_book).exists() => {}
+    _ => { return Err(CoreError::InvalidArgument(format!("the kept copy {path_to_kept_book} is missing"))); }
+  }
+  if let DuplicateAction::MoveTo(quarantine_dir) = action {
+    check_quarantine_dir(ctx, quarantine_dir)?;
+  }
+  let mut removed_copies = vec![];
+  for book_path in data.book_data.books_pk.iter().filter(|book_path| *book_path != path_to_kept_book) {
+    let book = match detach_copy(ctx, book_hash, book_path)? {
+      None => { continue; }
+      Some(book) => { book }
+    };
+    let book_data_key = book.book_data_pk.as_key();
+    let removed_copy = CoreEvent::BookRemoved { book_path: book_path.clone(), book_data_key };
+    if !Path::new(book_path).exists() {
+      ctx.emit(removed_copy);
+      removed_copies.push(book_path.clone());
+      continue;
+    }
+    let res = match action {
+      DuplicateAction::Delete => fs::remove_file(book_path).map_err(CoreError::from),
+      DuplicateAction::Trash => trash::delete(book_path).map_err(CoreError::from),
+      DuplicateAction::MoveTo(quarantine_dir) => move_to_dir(book_path, quarantine_dir),
+    };
+    match res {
+      Ok(_) => {
+        ctx.emit(removed_copy);
+        removed_copies.push(book_path.clone());
+      }
+      Err(e) => {
+        error!("failed to remove the duplicate {book_path}: {e}");
+        attach_copy(ctx, book_hash, book)?;
+      }
+    }
+  }
+  Ok(removed_copies)
+}
+
+/// Keeps the first valid copy of every group, see [`remove_redundant_copies`].
+/// A failing group doesn't stop the others, its error is returned along with the removed copies
+pub(crate) fn remove_all_redundant_copies(ctx: &Context, action: &DuplicateAction) -> CoreResult<RemovedDuplicates> {
+  if let DuplicateAction::MoveTo(quarantine_dir) = action {
+    check_quarantine_dir(ctx, quarantine_dir)?;
+  }
+  let mut removed_duplicates = RemovedDuplicates { removed_copies: vec![], errors: vec![] };
+  for group in get_duplicates(ctx)? {
+    match remove_redundant_copies_of_group(ctx, &group.book_hash, action) {
+      Ok(removed_copies) => { removed_duplicates.removed_copies.extend(removed_copies); }
+      Err(e) => {
+        error!("failed to remove the duplicates of {}: {e}", group.book_hash);
+        removed_duplicates.errors.push((group.book_hash, e));
+      }
+    }
+  }
+  Ok(removed_duplicates)
+}
+
+fn remove_redundant_copies_of_group(ctx: &Context, book_hash: &BookHash, action: &DuplicateAction)
+                                    -> CoreResult<Vec<BookPath>> {
+  let data = match crud::get_primary::<DataOfHashedBook>(ctx, book_hash.clone())? {
+    None => { return Ok(vec![]); }
+    Some(data) => { data }
+  };
+  match crud::book::get_main_book(ctx, &data.book_data)? {
+    None => Ok(vec![]),
+    Some(kept_book) => remove_redundant_copies(ctx, book_hash, &kept_book.path_to_book, action),
+  }
+}
+
+fn check_quarantine_dir(ctx: &Context, quarantine_dir: &PathBuf) -> CoreResult<()> {
+  // Books moved inside the library would be picked up again by the notify service
+  if ctx.library_roots.read().unwrap().iter().any(|library_root| library_root.contains(quarantine_dir)) {
+    return Err(CoreError::InvalidArgument(
+      format!("quarantine dir must be outside the library: {}", quarantine_dir.display())));
+  }
+  Ok(fs::create_dir_all(quarantine_dir)?)
+}
+
+fn move_to_dir(book_path: &BookPath, dir: &PathBuf) -> CoreResult<()> {
+  let book_path = Path::new(book_path);
+  let file_name = book_path.file_name().and_then(|file_name| file_name.to_str())
+    .ok_or(CoreError::InvalidPath(book_path.to_path_buf()))?;
+  let mut new_path = dir.join(file_name);
+  let mut num = 1;
+  while new_path.exists() {
+    new_path = dir.join(format!("({num}) {file_name}"));
+    num += 1;
+  }
+  // Renaming fails if the quarantine dir is on another device
+  if fs::rename(book_path, &new_path).is_err() {
+    fs::copy(book_path, &new_path)?;
+    fs::remove_file(book_path)?;
+  }
+  Ok(())
+}
+
+/// Removes the copy from the db before touching the file,
+/// so the notify service doesn't treat it as a deleted book
+fn detach_copy(ctx: &Context, book_hash: &BookHash, book_path: &BookPath) -> CoreResult<Option<Book>> {
+  let book = match crud::get_primary::<Book>(ctx, book_path.clone())? {
+    None => { return Ok(None); }
+    Some(book) => { book }
+  };
+  let old_data = match crud::get_primary::<DataOfHashedBook>(ctx, book_hash.clone())? {
+    None => { return Ok(None); }
+    Some(data) => { data }
+  };
+  let mut new_data = old_data.clone();
+  new_data.book_data.books_pk.retain(|path| path != book_path);
+  let rw_conn = ctx.db.rw_transaction()?;
+  rw_conn.update(old_data, new_data)?;
+  let book = rw_conn.remove(book)?;
+  rw_conn.commit()?;
+  Ok(Some(book))
+}
+
+fn attach_copy(ctx: &Context, book_hash: &BookHash, book: Book) -> CoreResult<()> {
+  let old_data = crud::get_primary::<DataOfHashedBook>(ctx, book_hash.clone())?
+    .ok_or(CoreError::NotFound(format!("duplicate group {book_hash}")))?;
+  let mut new_data = old_data.clone();
+  new_data.book_data.books_pk.push(book.path_to_book.clone());
+  let rw_conn = ctx.db.rw_transaction()?;
+  rw_conn.update(old_data, new_data)?;
+  rw_conn.insert(book)?;
+  Ok(rw_conn.commit()?)
+}
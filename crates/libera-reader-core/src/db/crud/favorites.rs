@@ -0,0 +1,81 @@
+use crate::context::Context;
+use crate::db::crud;
+use crate::error::{CoreError, CoreResult};
+use crate::models::{Book, BookData, CoreEvent, FavoritesChange};
+use crate::types::BookPath;
+use itertools::Itertools;
+
+
+fn get_favorite_book_data(ctx: &Context) -> CoreResult<Vec<BookData>> {
+  Ok(crud::book::get_all_book_data(ctx)?.into_iter()
+    .filter(|book_data| book_data.favorite)
+    .sorted_by_key(|book_data| book_data.favorite_order)
+    .collect())
+}
+
+/// Favorite books in the user-defined order
+pub(crate) fn get_favorites(ctx: &Context) -> CoreResult<Vec<Book>> {
+  let mut books = vec![];
+  for book_data in get_favorite_book_data(ctx)? {
+    books.extend(crud::book::get_main_book(ctx, &book_data)?);
+  }
+  Ok(books)
+}
+
+/// Returns `false` if the book is not in the db
+pub(crate) fn set_favorite(ctx: &Context, book_path: BookPath, favorite: bool) -> CoreResult<bool> {
+  let book = match crud::get_primary::<Book>(ctx, book_path.clone())? {
+    None => { return Ok(false); }
+    Some(book) => { book }
+  };
+  if book.get_book_data(ctx)?.favorite == favorite {
+    return Ok(true);
+  }
+  // New favorites go to the end of the list
+  let favorite_order = get_favorite_book_data(ctx)?.last().map_or(0, |book_data| book_data.favorite_order + 1);
+  crud::book::update_book_data(ctx, &book, |book_data| {
+    book_data.favorite = favorite;
+    book_data.favorite_order = if favorite { favorite_order } else { 0 };
+  })?;
+  match favorite {
+    true => ctx.emit(CoreEvent::FavoritesChanged(FavoritesChange::Added(book_path))),
+    false => ctx.emit(CoreEvent::FavoritesChanged(FavoritesChange::Removed(book_path))),
+  }
+  Ok(true)
+}
+
+/// Moves the favorite book to `new_position` of the list,
+/// returns `false` if the book is not in the db or is not a favorite
+pub(crate) fn move_favorite(ctx: &Context, book_path: BookPath, new_position: usize) -> CoreResult<bool> {
+  let book = match crud::get_primary::<Book>(ctx, book_path.clone())? {
+    None => { return Ok(false); }
+    Some(book) => { book }
+  };
+  let moved_book_data = book.get_book_data(ctx)?;
+  if !moved_book_data.favorite {
+    return Ok(false);
+  }
+  let mut favorites = get_favorite_book_data(ctx)?;
+  let old_position = favorites.iter()
+    .position(|book_data| book_data.books_pk == moved_book_data.books_pk)
+    .ok_or(CoreError::NotFound(format!("favorite {book_path}")))?;
+  let moved_book_data = favorites.remove(old_position);
+  favorites.insert(new_position.min(favorites.len()), moved_book_data);
+
+  // All the positions are rewritten together, so the order is never seen half moved
+  let rw_conn = ctx.db.rw_transaction()?;
+  for (favorite_order, book_data) in favorites.iter().enumerate() {
+    if book_data.favorite_order != favorite_order as u32 {
+      let book = match book_data.books_pk.first() {
+        None => { continue; }
+        Some(path) => { rw_conn.get().primary::<Book>(path.clone())? }
+      };
+      if let Some(book) = book {
+        crud::book::update_book_data_in(&rw_conn, &book, |book_data| book_data.favorite_order = favorite_order as u32)?;
+      }
+    }
+  }
+  rw_conn.commit()?;
+  ctx.emit(CoreEvent::FavoritesChanged(FavoritesChange::Reordered));
+  Ok(true)
+}
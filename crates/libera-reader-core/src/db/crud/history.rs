@@ -0,0 +1,97 @@
+use crate::context::Context;
+use crate::db::crud;
+use crate::error::CoreResult;
+use crate::models::{Book, BookData, HistoryEntry};
+use crate::types::BookPath;
+use crate::utils::get_timestamp;
+use itertools::Itertools;
+
+
+/// Puts the book at the top of the history and returns the page to resume reading from
+pub(crate) fn open_book(ctx: &Context, book_path: BookPath) -> CoreResult<Option<i32>> {
+  let book = match crud::get_primary::<Book>(ctx, book_path)? {
+    None => { return Ok(None); }
+    Some(book) => { book }
+  };
+  let timestamp = get_timestamp();
+  crud::book::update_book_data(ctx, &book, |book_data| {
+    book_data.in_history = true;
+    book_data.latest_opening_in = Some(timestamp);
+  })?;
+  Ok(Some(book.get_book_data(ctx)?.last_page_number))
+}
+
+pub(crate) fn update_progress(ctx: &Context, book_path: BookPath, page_number: i32) -> CoreResult<bool> {
+  match crud::get_primary::<Book>(ctx, book_path)? {
+    None => { Ok(false) }
+    Some(book) => {
+      let timestamp = get_timestamp();
+      crud::book::update_book_data(ctx, &book, |book_data| {
+        book_data.in_history = true;
+        book_data.last_page_number = page_number;
+        book_data.latest_opening_in = Some(timestamp);
+      })?;
+      Ok(true)
+    }
+  }
+}
+
+pub(crate) fn close_book(ctx: &Context, book_path: BookPath) -> CoreResult<bool> {
+  match crud::get_primary::<Book>(ctx, book_path)? {
+    None => { Ok(false) }
+    Some(book) => {
+      let timestamp = get_timestamp();
+      crud::book::update_book_data(ctx, &book, |book_data| book_data.latest_opening_in = Some(timestamp))?;
+      Ok(true)
+    }
+  }
+}
+
+pub(crate) fn remove_from_history(ctx: &Context, book_path: BookPath) -> CoreResult<bool> {
+  match crud::get_primary::<Book>(ctx, book_path)? {
+    None => { Ok(false) }
+    Some(book) => {
+      crud::book::update_book_data(ctx, &book, |book_data| {
+        book_data.in_history = false;
+        book_data.last_page_number = 0;
+        book_data.latest_opening_in = None;
+      })?;
+      Ok(true)
+    }
+  }
+}
+
+/// Recently read books, the most recent first
+pub(crate) fn get_history(ctx: &Context, limit: usize) -> CoreResult<Vec<HistoryEntry>> {
+  let mut history = vec![];
+  let book_data_in_history = crud::book::get_all_book_data(ctx)?.into_iter()
+    .filter(|book_data| book_data.in_history)
+    .sorted_by_key(|book_data| std::cmp::Reverse(book_data.latest_opening_in));
+  for book_data in book_data_in_history {
+    if history.len() == limit {
+      break;
+    }
+    history.extend(to_history_entry(ctx, book_data)?);
+  }
+  Ok(history)
+}
+
+fn to_history_entry(ctx: &Context, book_data: BookData) -> CoreResult<Option<HistoryEntry>> {
+  let book = match crud::book::get_main_book(ctx, &book_data)? {
+    None => { return Ok(None); }
+    Some(book) => { book }
+  };
+  let progress = match book_data.page_count {
+    Some(page_count) if page_count > 0 => {
+      Some(((book_data.last_page_number + 1) as f32 / page_count as f32 * 100.0).min(100.0))
+    }
+    _ => None,
+  };
+  Ok(Some(HistoryEntry {
+    book,
+    last_page_number: book_data.last_page_number,
+    page_count: book_data.page_count,
+    progress,
+    latest_opening_in: book_data.latest_opening_in.unwrap_or(0),
+  }))
+}
@@ -1,47 +1,48 @@
-use crate::db::DB;
-use native_db::{db_type, ToInput, ToKey};
+use crate::context::Context;
+use crate::error::CoreResult;
+use native_db::{ToInput, ToKey};
 pub(crate) mod book;
+pub(crate) mod bookmark;
+pub(crate) mod collections;
+pub(crate) mod duplicates;
+pub(crate) mod favorites;
+pub(crate) mod history;
+pub(crate) mod removed_books;
+pub(crate) mod search_index;
+pub(crate) mod tags;
 
 
-pub fn get_primary<T: ToInput>(key: impl ToKey) -> Option<T> {
-  let r_conn = DB.r_transaction().unwrap();
-  r_conn.get().primary(key).unwrap()
+pub fn get_primary<T: ToInput>(ctx: &Context, key: impl ToKey) -> CoreResult<Option<T>> {
+  let r_conn = ctx.db.r_transaction()?;
+  Ok(r_conn.get().primary(key)?)
 }
 
-//noinspection RsUnwrap
-pub fn insert<T: ToInput>(item: T) -> db_type::Result<()> {
-  let rw_conn = DB.rw_transaction().unwrap();
-  rw_conn.insert(item).unwrap();
-  rw_conn.commit()
+pub fn insert<T: ToInput>(ctx: &Context, item: T) -> CoreResult<()> {
+  let rw_conn = ctx.db.rw_transaction()?;
+  rw_conn.insert(item)?;
+  Ok(rw_conn.commit()?)
 }
 
-pub fn insert_batch<T: ToInput>(data: Vec<T>) {
+pub fn insert_batch<T: ToInput>(ctx: &Context, data: Vec<T>) -> CoreResult<()> {
   if data.len() > 0 {
-    let rw_conn = DB.rw_transaction().unwrap();
+    let rw_conn = ctx.db.rw_transaction()?;
     for i in data {
-      rw_conn.insert(i).unwrap();
+      rw_conn.insert(i)?;
     }
-    rw_conn.commit().unwrap();
+    rw_conn.commit()?;
   }
+  Ok(())
 }
 
-//noinspection RsUnwrap
-pub fn update<T: ToInput>(old_data: T, new_data: T) -> db_type::Result<()> {
-  let rw_conn = DB.rw_transaction().unwrap();
-  rw_conn.update(old_data, new_data).unwrap();
-  rw_conn.commit()
+pub fn update<T: ToInput>(ctx: &Context, old_data: T, new_data: T) -> CoreResult<()> {
+  let rw_conn = ctx.db.rw_transaction()?;
+  rw_conn.update(old_data, new_data)?;
+  Ok(rw_conn.commit()?)
 }
 
-//noinspection RsUnwrap
-pub fn remove<T: ToInput>(item: T) -> Result<T, db_type::Error> {
-  let rw_conn = DB.rw_transaction().unwrap();
-  let res = rw_conn.remove(item).unwrap();
-  match rw_conn.commit() {
-    Ok(_) => {
-      Ok(res)
-    }
-    Err(e) => {
-      Err(e)
-    }
-  }
+pub fn remove<T: ToInput>(ctx: &Context, item: T) -> CoreResult<T> {
+  let rw_conn = ctx.db.rw_transaction()?;
+  let res = rw_conn.remove(item)?;
+  rw_conn.commit()?;
+  Ok(res)
 }
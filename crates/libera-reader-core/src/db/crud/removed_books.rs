@@ -0,0 +1,82 @@
+use crate::context::Context;
+use crate::db::crud;
+use crate::error::{CoreError, CoreResult};
+use crate::models::{Book, BookData, BookMark, BookMarkKey, RemovedBook, RemovedBookKey};
+use crate::types::{BookHash, BookPath, BookSize};
+use crate::utils::get_timestamp;
+use itertools::Itertools;
+use native_db::transaction::RwTransaction;
+use tracing::debug;
+
+/// How long the data of a removed book is kept
+const GRACE_PERIOD_SECS: u64 = 7 * 24 * 60 * 60;
+
+
+/// Keeps the data of the last copy of a book being removed along with its bookmarks
+pub(crate) fn keep_removed_book(rw_conn: &RwTransaction, book: &Book, book_size: BookSize,
+                                book_hash: Option<BookHash>, book_data: BookData) -> CoreResult<RemovedBook> {
+  let removed_book = RemovedBook {
+    book_path: book.path_to_book.clone(),
+    book_size,
+    book_hash,
+    book_data,
+    removed_at: get_timestamp(),
+  };
+  // The same path removed again replaces the data kept before, the bookmarks kept with it go too,
+  // they are under the same key as the bookmarks moved here
+  if let Some(old_removed_book) = rw_conn.get().primary::<RemovedBook>(removed_book.book_path.clone())? {
+    remove_bookmarks_of(rw_conn, &old_removed_book)?;
+    rw_conn.remove(old_removed_book)?;
+  }
+  let book_data_key = book.book_data_pk.as_key();
+  let bookmarks: Vec<BookMark> = rw_conn.scan().secondary(BookMarkKey::book_data_key)?
+    .start_with(book_data_key.clone())?.try_collect()?;
+  for old_bookmark in bookmarks.into_iter().filter(|bookmark| bookmark.book_data_key == book_data_key) {
+    let mut new_bookmark = old_bookmark.clone();
+    new_bookmark.book_data_key = removed_book.bookmarks_key();
+    rw_conn.update(old_bookmark, new_bookmark)?;
+  }
+  rw_conn.insert(removed_book.clone())?;
+  Ok(removed_book)
+}
+
+pub(crate) fn get_removed_books_of_this_size(ctx: &Context, book_size: BookSize) -> CoreResult<Vec<RemovedBook>> {
+  let r_conn = ctx.db.r_transaction()?;
+  Ok(r_conn.scan().secondary(RemovedBookKey::book_size)?.range(book_size..=book_size)?.try_collect()?)
+}
+
+/// Gives the data of the removed book back to the book that has replaced it
+pub(crate) fn restore_removed_book(rw_conn: &RwTransaction, book_path: BookPath, removed_book: RemovedBook)
+                                   -> CoreResult<()> {
+  let book = rw_conn.get().primary::<Book>(book_path.clone())?
+    .ok_or_else(|| CoreError::NotFound(format!("book {book_path}")))?;
+  crud::book::update_book_data_in(rw_conn, &book, |book_data| book_data.merge_user_data(&removed_book.book_data))?;
+  crud::bookmark::relink_bookmarks(rw_conn, &removed_book.bookmarks_key(), &book.book_data_pk.as_key())?;
+  debug!("the data of the removed book {} has been given to {book_path}", removed_book.book_path);
+  rw_conn.remove(removed_book)?;
+  Ok(())
+}
+
+/// Forgets the books removed longer than the grace period ago
+pub(crate) fn remove_expired(ctx: &Context) -> CoreResult<()> {
+  let now = get_timestamp();
+  let rw_conn = ctx.db.rw_transaction()?;
+  let removed_books: Vec<RemovedBook> = rw_conn.scan().primary()?.all()?.try_collect()?;
+  for removed_book in removed_books {
+    if removed_book.removed_at + GRACE_PERIOD_SECS > now {
+      continue;
+    }
+    remove_bookmarks_of(&rw_conn, &removed_book)?;
+    rw_conn.remove(removed_book)?;
+  }
+  Ok(rw_conn.commit()?)
+}
+fn remove_bookmarks_of(rw_conn: &RwTransaction, removed_book: &RemovedBook) -> CoreResult<()> {
+  let bookmarks_key = removed_book.bookmarks_key();
+  let bookmarks: Vec<BookMark> = rw_conn.scan().secondary(BookMarkKey::book_data_key)?
+    .start_with(bookmarks_key.clone())?.try_collect()?;
+  for bookmark in bookmarks.into_iter().filter(|bookmark| bookmark.book_data_key == bookmarks_key) {
+    rw_conn.remove(bookmark)?;
+  }
+  Ok(())
+}
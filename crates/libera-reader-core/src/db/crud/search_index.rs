@@ -0,0 +1,157 @@
+use crate::context::Context;
+use crate::db::crud;
+use crate::error::CoreResult;
+use crate::models::{BookDataType, IndexedBook, PageHit, Posting, SearchResult, TermPostings, TermPostingsKey};
+use gxhash::{HashMap, HashMapExt};
+use itertools::Itertools;
+use native_db::transaction::RwTransaction;
+
+const MIN_TERM_LEN: usize = 2;
+const SNIPPET_RADIUS: usize = 60;
+const MAX_PAGES_PER_RESULT: usize = 10;
+
+
+/// Splits the text into lowercase words and counts them
+pub(crate) fn tokenize(text: &str) -> HashMap<String, u32> {
+  let mut terms: HashMap<String, u32> = HashMap::new();
+  for word in text.split(|c: char| !c.is_alphanumeric()) {
+    if word.chars().count() >= MIN_TERM_LEN {
+      *terms.entry(word.to_lowercase()).or_insert(0) += 1;
+    }
+  }
+  terms
+}
+
+pub(crate) fn is_indexed(ctx: &Context, book_data_key: &String) -> CoreResult<bool> {
+  Ok(crud::get_primary::<IndexedBook>(ctx, book_data_key.clone())?.is_some())
+}
+
+/// Adds the pages of the book to the index, replacing its previous version
+pub(crate) fn index_book(ctx: &Context, book_data_pk: BookDataType, pages: Vec<String>) -> CoreResult<()> {
+  let rw_conn = ctx.db.rw_transaction()?;
+  remove_from_index(&rw_conn, &book_data_pk.as_key())?;
+  add_to_index(&rw_conn, book_data_pk, pages)?;
+  Ok(rw_conn.commit()?)
+}
+
+fn add_to_index(rw_conn: &RwTransaction, book_data_pk: BookDataType, pages: Vec<String>) -> CoreResult<()> {
+  let book_data_key = book_data_pk.as_key();
+  let mut postings: HashMap<String, Vec<Posting>> = HashMap::new();
+  for (page_number, page) in pages.iter().enumerate() {
+    for (term, count) in tokenize(page) {
+      postings.entry(term).or_insert_with(Vec::new).push(Posting { page_number: page_number as u32, count });
+    }
+  }
+  for (term, postings) in postings {
+    rw_conn.insert(TermPostings::new(&term, book_data_key.clone(), postings))?;
+  }
+  rw_conn.insert(IndexedBook { book_data_key, book_data_pk, pages })?;
+  Ok(())
+}
+
+/// Removes the book from the index and returns its pages
+pub(crate) fn remove_from_index(rw_conn: &RwTransaction, book_data_key: &String) -> CoreResult<Option<Vec<String>>> {
+  let indexed_book = match rw_conn.get().primary::<IndexedBook>(book_data_key.clone())? {
+    None => { return Ok(None); }
+    Some(indexed_book) => { indexed_book }
+  };
+  let term_postings: Vec<TermPostings> = rw_conn.scan().secondary(TermPostingsKey::book_data_key)?
+    .start_with(book_data_key.clone())?.try_collect()?;
+  for term_postings in term_postings {
+    // The scan also finds the keys the book data key is a prefix of
+    if &term_postings.book_data_key == book_data_key {
+      rw_conn.remove(term_postings)?;
+    }
+  }
+  let pages = rw_conn.remove(indexed_book)?.pages;
+  Ok(Some(pages))
+}
+
+/// Moves the indexed text to the new book data record, e.g. when a unique size book gets a hash
+pub(crate) fn relink_index(rw_conn: &RwTransaction, old_book_data_key: &String, new_book_data_pk: BookDataType)
+                           -> CoreResult<()> {
+  if let Some(pages) = remove_from_index(rw_conn, old_book_data_key)? {
+    remove_from_index(rw_conn, &new_book_data_pk.as_key())?;
+    add_to_index(rw_conn, new_book_data_pk, pages)?;
+  }
+  Ok(())
+}
+
+/// Books containing all words of the query, ranked by tf-idf
+pub(crate) fn search(ctx: &Context, query: &str, limit: usize) -> CoreResult<Vec<SearchResult>> {
+  let query_terms = tokenize(query).into_keys().collect_vec();
+  if query_terms.is_empty() {
+    return Ok(vec![]);
+  }
+  let r_conn = ctx.db.r_transaction()?;
+  let num_of_books = r_conn.len().primary::<IndexedBook>()?.max(1) as f32;
+
+  // book data key -> page number -> score
+  let mut scores: HashMap<String, HashMap<u32, f32>> = HashMap::new();
+  let mut term_matches: HashMap<String, usize> = HashMap::new();
+  for term in &query_terms {
+    let term_postings: Vec<TermPostings> = r_conn.scan().primary()?
+      .start_with(TermPostings::key_prefix(term))?.try_collect()?;
+    if term_postings.is_empty() {
+      return Ok(vec![]);
+    }
+    let idf = (num_of_books / term_postings.len() as f32).ln() + 1.0;
+    for term_postings in term_postings {
+      *term_matches.entry(term_postings.book_data_key.clone()).or_insert(0) += 1;
+      let page_scores = scores.entry(term_postings.book_data_key).or_insert_with(HashMap::new);
+      for posting in term_postings.postings {
+        *page_scores.entry(posting.page_number).or_insert(0.0) += posting.count as f32 * idf;
+      }
+    }
+  }
+
+  let ranked_books = scores.into_iter()
+    .filter(|(book_data_key, _)| term_matches.get(book_data_key) == Some(&query_terms.len()))
+    .map(|(book_data_key, page_scores)| {
+      let score: f32 = page_scores.values().sum();
+      (book_data_key, page_scores, score)
+    })
+    .sorted_by(|a, b| b.2.total_cmp(&a.2));
+  let mut search_results = vec![];
+  for (book_data_key, page_scores, score) in ranked_books {
+    if search_results.len() == limit {
+      break;
+    }
+    let indexed_book = match r_conn.get().primary::<IndexedBook>(book_data_key)? {
+      None => { continue; }
+      Some(indexed_book) => { indexed_book }
+    };
+    let book = match crud::book::get_book_data_with_size(ctx, &indexed_book.book_data_pk)? {
+      None => { None }
+      Some((book_data, _)) => { crud::book::get_main_book(ctx, &book_data)? }
+    };
+    let book = match book {
+      None => { continue; }
+      Some(book) => { book }
+    };
+    let pages = page_scores.into_iter()
+      .sorted_by(|a, b| b.1.total_cmp(&a.1))
+      .take(MAX_PAGES_PER_RESULT)
+      .map(|(page_number, _)| PageHit {
+        page_number,
+        snippet: make_snippet(&indexed_book.pages[page_number as usize], &query_terms),
+      })
+      .collect();
+    search_results.push(SearchResult { book, score, pages });
+  }
+  Ok(search_results)
+}
+
+/// Part of the page around the first occurrence of a query term
+fn make_snippet(page: &str, query_terms: &[String]) -> String {
+  let chars = page.chars().collect_vec();
+  let lowercase_page: String = chars.iter().map(|c| c.to_lowercase().next().unwrap_or(*c)).collect();
+  let lowercase_chars = lowercase_page.chars().collect_vec();
+  let first_match = query_terms.iter().filter_map(|term| {
+    let term_chars = term.chars().collect_vec();
+    lowercase_chars.windows(term_chars.len()).position(|window| window == term_chars.as_slice())
+  }).min().unwrap_or(0);
+  let start = first_match.saturating_sub(SNIPPET_RADIUS);
+  let end = (first_match + SNIPPET_RADIUS).min(chars.len());
+  chars[start..end].iter().collect::<String>().split_whitespace().join(" ")
+}
@@ -0,0 +1,60 @@
+use crate::context::Context;
+use crate::db::crud;
+use crate::error::CoreResult;
+use crate::models::Book;
+use crate::types::BookPath;
+use gxhash::{HashMap, HashMapExt};
+use itertools::Itertools;
+
+
+/// Tags are case-insensitive, so they are stored trimmed and lowercase
+pub(crate) fn normalize_tag(tag: &str) -> String {
+  tag.trim().to_lowercase()
+}
+
+/// Adds the tags to every book of the list, returns the number of books found in the db
+pub(crate) fn add_tags(ctx: &Context, books_path: Vec<BookPath>, tags: &[String]) -> CoreResult<usize> {
+  let tags = tags.iter().map(|tag| normalize_tag(tag)).filter(|tag| !tag.is_empty()).collect_vec();
+  update_books(ctx, books_path, |book_tags| {
+    for tag in &tags {
+      if !book_tags.contains(tag) {
+        book_tags.push(tag.clone());
+      }
+    }
+    book_tags.sort();
+  })
+}
+
+pub(crate) fn remove_tags(ctx: &Context, books_path: Vec<BookPath>, tags: &[String]) -> CoreResult<usize> {
+  let tags = tags.iter().map(|tag| normalize_tag(tag)).collect_vec();
+  update_books(ctx, books_path, |book_tags| book_tags.retain(|tag| !tags.contains(tag)))
+}
+
+/// All tags of the library with the number of books tagged with each
+pub(crate) fn get_all_tags(ctx: &Context) -> CoreResult<Vec<(String, usize)>> {
+  let mut tags: HashMap<String, usize> = HashMap::new();
+  for book_data in crud::book::get_all_book_data(ctx)? {
+    for tag in book_data.tags {
+      *tags.entry(tag).or_insert(0) += 1;
+    }
+  }
+  Ok(tags.into_iter().sorted().collect())
+}
+
+pub(crate) fn get_tags(ctx: &Context, book_path: BookPath) -> CoreResult<Vec<String>> {
+  match crud::get_primary::<Book>(ctx, book_path)? {
+    None => { Ok(vec![]) }
+    Some(book) => { Ok(book.get_book_data(ctx)?.tags) }
+  }
+}
+
+fn update_books(ctx: &Context, books_path: Vec<BookPath>, change_tags: impl Fn(&mut Vec<String>)) -> CoreResult<usize> {
+  let mut num_of_books = 0;
+  for book_path in books_path {
+    if let Some(book) = crud::get_primary::<Book>(ctx, book_path)? {
+      crud::book::update_book_data(ctx, &book, |book_data| change_tags(&mut book_data.tags))?;
+      num_of_books += 1;
+    }
+  }
+  Ok(num_of_books)
+}
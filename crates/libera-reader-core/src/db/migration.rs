@@ -0,0 +1,326 @@
+use crate::context::Context;
+use crate::db::models_old::mb_to_bytes;
+use crate::db::models_old;
+use crate::error::CoreResult;
+use crate::models::{Book, BookDataType, BookMark, BookOutline, DataOfHashedBook, DataOfUnhashedBook, IndexedBook,
+                    OcrText, Posting, Settings, TermPostings};
+use crate::types::{BookHash, BookPath, BookSize};
+use crate::utils::{calc_file_hash, calc_file_size, find_library_root_in, get_thumbnail_path};
+use gxhash::{HashMap, HashMapExt, HashSet};
+use itertools::Itertools;
+use native_db::transaction::RwTransaction;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::Path;
+use tracing::{error, info};
+
+
+/// Keys of the first schema mapped to the keys of the current one
+struct NewKeys {
+  book_data: HashMap<String, BookDataType>,
+  hashes: HashMap<BookHash, BookHash>,
+}
+
+/// Upgrades the records written by older versions of the core.
+/// The book data with sizes in megabytes moves to exact sizes and portable hashes:
+/// books still on disk are measured and hashed again, the records of missing ones keep the old hash,
+/// bookmarks, outlines, the search index, recognized text and thumbnails follow the new keys
+pub(crate) fn migrate(ctx: &Context) -> CoreResult<()> {
+  upgrade_records(ctx)?;
+  if !has_v1_records(ctx)? {
+    return Ok(());
+  }
+  info!("migrating the book data to exact sizes and portable hashes");
+  // Hashing takes long, so it's done before the db is locked for writing
+  let new_hashes = hash_books_again(ctx)?;
+  let rw_conn = ctx.db.rw_transaction()?;
+  let new_keys = migrate_book_data(&rw_conn, new_hashes)?;
+  migrate_books(&rw_conn, &new_keys)?;
+  migrate_search_index(&rw_conn, &new_keys)?;
+  relink_book_data_keys(&rw_conn, &new_keys)?;
+  rw_conn.commit()?;
+  rename_thumbnails(ctx, &new_keys);
+  Ok(())
+}
+
+/// Records whose layout has changed are converted by native_model from the version they were written with
+fn upgrade_records(ctx: &Context) -> CoreResult<()> {
+  let rw_conn = ctx.db.rw_transaction()?;
+  rw_conn.migrate::<Settings>()?;
+  rw_conn.migrate::<BookMark>()?;
+  // The book data with sizes in megabytes and the books keyed by it are left as they are,
+  // the move to exact sizes needs the files on disk. Once it's done the old tables stay empty
+  split_search_terms(&rw_conn)?;
+  Ok(rw_conn.commit()?)
+}
+
+/// The postings of all books kept in one record per term are split into records per term and book
+fn split_search_terms(rw_conn: &RwTransaction) -> CoreResult<()> {
+  let search_terms: Vec<models_old::SearchTermV1> = rw_conn.scan().primary()?.all()?.try_collect()?;
+  for search_term in search_terms {
+    let search_term = rw_conn.remove(search_term)?;
+    let postings_by_book = search_term.postings.into_iter().into_group_map_by(|posting| posting.book_data_key.clone());
+    for (book_data_key, postings) in postings_by_book {
+      let postings = postings.into_iter()
+        .map(|posting| Posting { page_number: posting.page_number, count: posting.count })
+        .collect();
+      rw_conn.insert(TermPostings::new(&search_term.term, book_data_key, postings))?;
+    }
+  }
+  Ok(())
+}
+
+fn has_v1_records(ctx: &Context) -> CoreResult<bool> {
+  let r_conn = ctx.db.r_transaction()?;
+  Ok(r_conn.len().primary::<models_old::DataOfUnhashedBookV1>()? > 0
+    || r_conn.len().primary::<models_old::DataOfHashedBookV1>()? > 0
+    || r_conn.len().primary::<models_old::BookV1>()? > 0)
+}
+
+/// Old hashes mapped to the hashes of the same files calculated the current way.
+/// Old databases can't have partial hashing enabled, so the whole files are hashed.
+/// The hash of a unique size book is only a cache, it's dropped and calculated again when needed,
+/// unless the recognized text of the book is kept under it
+fn hash_books_again(ctx: &Context) -> CoreResult<HashMap<BookHash, BookHash>> {
+  let r_conn = ctx.db.r_transaction()?;
+  let ocr_texts: Vec<OcrText> = r_conn.scan().primary()?.all()?.try_collect()?;
+  let data_of_unhashed_books: Vec<models_old::DataOfUnhashedBookV1> = r_conn.scan().primary()?.all()?.try_collect()?;
+  let data_of_hashed_books: Vec<models_old::DataOfHashedBookV1> = r_conn.scan().primary()?.all()?.try_collect()?;
+  drop(r_conn);
+  let hashes_of_ocr_texts: HashSet<BookHash> = ocr_texts.into_iter().map(|ocr_text| ocr_text.book_hash).collect();
+  let old_hashes = data_of_unhashed_books.into_iter()
+    .filter_map(|data| {
+      let book_hash = data.book_hash.filter(|book_hash| hashes_of_ocr_texts.contains(book_hash))?;
+      Some((book_hash, data.book_data.books_pk))
+    })
+    .chain(data_of_hashed_books.into_iter().map(|data| (data.book_hash, data.book_data.books_pk)));
+  let mut new_hashes = HashMap::new();
+  for (old_hash, books_pk) in old_hashes {
+    if let Some(new_hash) = find_existing_file(&books_pk).and_then(|path| calc_file_hash(path, None).ok()) {
+      new_hashes.insert(old_hash, new_hash);
+    }
+  }
+  Ok(new_hashes)
+}
+
+fn migrate_book_data(rw_conn: &RwTransaction, new_hashes: HashMap<BookHash, BookHash>) -> CoreResult<NewKeys> {
+  let mut new_keys = NewKeys { book_data: HashMap::new(), hashes: new_hashes };
+
+  let data_of_unhashed_books: Vec<models_old::DataOfUnhashedBookV1> =
+    rw_conn.scan().primary()?.all()?.try_collect()?;
+  for old_data in data_of_unhashed_books {
+    rw_conn.remove(old_data.clone())?;
+    let existing_file = find_existing_file(&old_data.book_data.books_pk);
+    let book_size = existing_file.and_then(|path| calc_file_size(path).ok())
+      .unwrap_or_else(|| mb_to_bytes(&old_data.book_size));
+    let book_hash = old_data.book_hash.as_ref().and_then(|old_hash| new_keys.hashes.get(old_hash)).cloned();
+    new_keys.book_data.insert(old_data.book_size.clone(), BookDataType::UniqueSize(book_size));
+    rw_conn.insert(DataOfUnhashedBook { book_size, book_hash, ..old_data.into() })?;
+  }
+
+  let data_of_hashed_books: Vec<models_old::DataOfHashedBookV1> = rw_conn.scan().primary()?.all()?.try_collect()?;
+  for old_data in data_of_hashed_books {
+    rw_conn.remove(old_data.clone())?;
+    let existing_file = find_existing_file(&old_data.book_data.books_pk);
+    let book_size = existing_file.and_then(|path| calc_file_size(path).ok())
+      .unwrap_or_else(|| mb_to_bytes(&old_data.book_size));
+    let book_hash = new_keys.hashes.get(&old_data.book_hash).cloned().unwrap_or_else(|| old_data.book_hash.clone());
+    new_keys.book_data.insert(old_data.book_hash.clone(), BookDataType::RepeatingSize(book_hash.clone()));
+    rw_conn.insert(DataOfHashedBook { book_size, book_hash, ..old_data.into() })?;
+  }
+  Ok(new_keys)
+}
+
+fn migrate_books(rw_conn: &RwTransaction, new_keys: &NewKeys) -> CoreResult<()> {
+  let library_roots = rw_conn.get().primary::<Settings>(1)?.map_or(vec![], |settings| settings.library_roots);
+  let books: Vec<models_old::BookV1> = rw_conn.scan().primary()?.all()?.try_collect()?;
+  for old_book in books {
+    rw_conn.remove(old_book.clone())?;
+    // A book whose data record is lost keeps its key in the new format
+    let book_data_pk = new_keys.book_data.get(&old_book.book_data_pk.as_key()).cloned();
+    let mut book: Book = old_book.into();
+    // Books of the schemas before the library roots have none
+    if book.library_root.is_empty() {
+      book.library_root = find_library_root_in(&library_roots, Path::new(&book.path_to_book))
+        .map_or(String::new(), |library_root| library_root.path.clone());
+    }
+    rw_conn.insert(Book { book_data_pk: book_data_pk.unwrap_or(book.book_data_pk), ..book })?;
+  }
+  Ok(())
+}
+
+/// Books whose data record is lost are dropped from the index, the search index service adds them again
+fn migrate_search_index(rw_conn: &RwTransaction, new_keys: &NewKeys) -> CoreResult<()> {
+  let indexed_books: Vec<models_old::IndexedBookV1> = rw_conn.scan().primary()?.all()?.try_collect()?;
+  for old_indexed_book in indexed_books {
+    rw_conn.remove(old_indexed_book.clone())?;
+    if let Some(book_data_pk) = new_keys.book_data.get(&old_indexed_book.book_data_key) {
+      rw_conn.insert(IndexedBook {
+        book_data_key: book_data_pk.as_key(),
+        book_data_pk: book_data_pk.clone(),
+        pages: old_indexed_book.pages,
+      })?;
+    }
+  }
+  let term_postings: Vec<TermPostings> = rw_conn.scan().primary()?.all()?.try_collect()?;
+  for old_term_postings in term_postings {
+    let old_term_postings = rw_conn.remove(old_term_postings)?;
+    if let Some(book_data_pk) = new_keys.book_data.get(&old_term_postings.book_data_key) {
+      let term = old_term_postings.term().to_string();
+      rw_conn.insert(TermPostings::new(&term, book_data_pk.as_key(), old_term_postings.postings))?;
+    }
+  }
+  Ok(())
+}
+
+fn relink_book_data_keys(rw_conn: &RwTransaction, new_keys: &NewKeys) -> CoreResult<()> {
+  let bookmarks: Vec<BookMark> = rw_conn.scan().primary()?.all()?.try_collect()?;
+  for old_bookmark in bookmarks {
+    if let Some(book_data_pk) = new_keys.book_data.get(&old_bookmark.book_data_key) {
+      let mut new_bookmark = old_bookmark.clone();
+      new_bookmark.book_data_key = book_data_pk.as_key();
+      rw_conn.update(old_bookmark, new_bookmark)?;
+    }
+  }
+  let book_outlines: Vec<BookOutline> = rw_conn.scan().primary()?.all()?.try_collect()?;
+  for old_book_outline in book_outlines {
+    if let Some(book_data_pk) = new_keys.book_data.get(&old_book_outline.book_data_key) {
+      let old_book_outline = rw_conn.remove(old_book_outline)?;
+      rw_conn.insert(BookOutline { book_data_key: book_data_pk.as_key(), outlines: old_book_outline.outlines })?;
+    }
+  }
+  let ocr_texts: Vec<OcrText> = rw_conn.scan().primary()?.all()?.try_collect()?;
+  for old_ocr_text in ocr_texts {
+    if let Some(book_hash) = new_keys.hashes.get(&old_ocr_text.book_hash) {
+      let old_ocr_text = rw_conn.remove(old_ocr_text)?;
+      rw_conn.insert(OcrText { book_hash: book_hash.clone(), pages: old_ocr_text.pages })?;
+    }
+  }
+  Ok(())
+}
+
+fn rename_thumbnails(ctx: &Context, new_keys: &NewKeys) {
+  for (old_key, book_data_pk) in &new_keys.book_data {
+    let new_path = get_thumbnail_path(ctx, book_data_pk);
+    let old_path = new_path.with_file_name(format!("{old_key}.jpeg"));
+    match fs::rename(&old_path, &new_path) {
+      Ok(_) => {}
+      Err(e) if e.kind() == ErrorKind::NotFound => {}
+      Err(e) => { error!("failed to rename the thumbnail {}: {e}", old_path.display()); }
+    }
+  }
+}
+
+fn find_existing_file(books_pk: &[BookPath]) -> Option<&Path> {
+  books_pk.iter().map(Path::new).find(|path| path.is_file())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::app_dirs::AppDirs;
+  use crate::db::models_old::{BookDataTypeV1, BookDataV1, BookMarkV1, BookV1, DataOfHashedBookV1,
+                              DataOfUnhashedBookV1, SettingsV1};
+  use crate::db::open_db;
+  use crate::models::{BookKey, Language, LibraryRoot, Theme};
+  use std::path::PathBuf;
+
+  /// Writes the records of the first schema, the way the first versions of the core left them
+  fn write_baseline_db(data_dir: PathBuf, library_dir: &Path, path_to_book: &Path) {
+    let app_dirs = AppDirs::new(data_dir).unwrap();
+    let db = open_db(&app_dirs.path_to_db).unwrap();
+    let rw_conn = db.rw_transaction().unwrap();
+    rw_conn.insert(SettingsV1 {
+      id: 1,
+      language: Language::EN,
+      theme: Theme::Dark,
+      path_to_scan: Some(library_dir.to_str().unwrap().to_string()),
+      number_of_columns: 6,
+      page_scaling_factor: 1.0,
+      thumbnails_scaling_factor: 1.0,
+      workers_num: 2,
+    }).unwrap();
+    let book_data = |books_pk: Vec<BookPath>| BookDataV1 {
+      cached: true,
+      title: None,
+      author: None,
+      page_count: Some(1),
+      in_history: true,
+      favorite: false,
+      last_page_number: 0,
+      latest_opening_in: None,
+      books_pk,
+    };
+    let path_to_book = path_to_book.to_str().unwrap().to_string();
+    // 13 bytes
+    rw_conn.insert(DataOfUnhashedBookV1 {
+      book_size: "0.000012".to_string(),
+      book_hash: None,
+      book_data: book_data(vec![path_to_book.clone()]),
+    }).unwrap();
+    rw_conn.insert(DataOfHashedBookV1 {
+      book_size: "1.5".to_string(),
+      book_hash: "old_hash".to_string(),
+      book_data: book_data(vec![library_dir.join("missing.pdf").to_str().unwrap().to_string()]),
+    }).unwrap();
+    rw_conn.insert(BookV1 {
+      path_to_book,
+      path_to_dir: library_dir.to_str().unwrap().to_string(),
+      dir_name: library_dir.file_name().unwrap().to_str().unwrap().to_string(),
+      book_name: "book".to_string(),
+      ext: "pdf".to_string(),
+      path_is_valid: true,
+      book_data_pk: BookDataTypeV1::UniqueSize("0.000012".to_string()),
+    }).unwrap();
+    rw_conn.insert(BookMarkV1 {
+      id: 1,
+      title: "First page".to_string(),
+      content: String::new(),
+      page_number: 0,
+      book_data_link: "0.000012".to_string(),
+      time_created: "2024-05-17 09:30:00".to_string(),
+      time_updated: "2024-05-17T09:30:00Z".to_string(),
+    }).unwrap();
+    rw_conn.commit().unwrap();
+  }
+
+  #[test]
+  fn opens_a_db_of_the_first_schema() {
+    // Unique per process, so parallel runs of the tests don't share the db
+    let data_dir = std::env::temp_dir().join(format!("libera_reader_first_schema_db_{}", std::process::id()));
+    let _ = fs::remove_dir_all(&data_dir);
+    let library_dir = data_dir.join("library");
+    let path_to_book = library_dir.join("book.pdf");
+    fs::create_dir_all(&library_dir).unwrap();
+    fs::write(&path_to_book, b"baseline book").unwrap();
+    write_baseline_db(data_dir.clone(), &library_dir, &path_to_book);
+
+    let ctx = Context::open(data_dir.clone()).unwrap();
+    let r_conn = ctx.db.r_transaction().unwrap();
+
+    let settings: Settings = r_conn.get().primary(1).unwrap().unwrap();
+    assert_eq!(settings.library_roots, vec![LibraryRoot::new(library_dir.to_str().unwrap().to_string())]);
+    // The scans of the first schema included hidden files
+    assert!(!settings.skip_hidden);
+
+    let books: Vec<Book> = r_conn.scan().secondary(BookKey::book_name).unwrap().all().unwrap().try_collect().unwrap();
+    assert_eq!(books.len(), 1);
+    assert_eq!(books[0].book_data_pk, BookDataType::UniqueSize(13));
+    assert_eq!(books[0].library_root, library_dir.to_str().unwrap());
+
+    let data_of_unhashed_book: DataOfUnhashedBook = r_conn.get().primary(13 as BookSize).unwrap().unwrap();
+    assert!(data_of_unhashed_book.book_data.in_history);
+    assert_eq!(data_of_unhashed_book.book_hash, None);
+    // The file is missing, the record keeps its hash and the size in megabytes
+    let data_of_hashed_book: DataOfHashedBook = r_conn.get().primary("old_hash".to_string()).unwrap().unwrap();
+    assert_eq!(data_of_hashed_book.book_size, mb_to_bytes("1.5"));
+
+    let bookmark: BookMark = r_conn.get().primary(1u64).unwrap().unwrap();
+    assert_eq!(bookmark.book_data_key, "13");
+    assert_eq!((bookmark.time_created, bookmark.time_updated), (1715938200, 1715938200));
+
+    drop(r_conn);
+    drop(ctx);
+    let _ = fs::remove_dir_all(&data_dir);
+  }
+}
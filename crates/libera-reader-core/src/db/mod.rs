@@ -1,32 +1,49 @@
-use crate::db::models::{Book, BookMark, DataOfHashedBook, DataOfUnhashedBook, Settings};
+use crate::db::models::{Book, BookMark, BookOutline, Collection, DataOfHashedBook, DataOfUnhashedBook, IndexedBook,
+                        OcrText, RemovedBook, Settings, TermPostings};
 use crate::models::TargetExt;
-use crate::vars::APP_DIRS;
+use crate::error::CoreResult;
 use native_db::{Builder, Database, Models};
 use once_cell::sync::Lazy;
+use std::path::Path;
 
 
 pub mod models;
 pub(crate) mod crud;
 pub(crate) mod models_impl;
+/// Frozen versions of the records whose layout has changed, the db upgrades them to the current ones on open
+pub(crate) mod models_old;
+pub(crate) mod migration;
 
 fn get_models() -> Models {
   let mut models = Models::new();
+  models.define::<models_old::SettingsV1>().unwrap();
   models.define::<Settings>().unwrap();
+  models.define::<models_old::BookMarkV1>().unwrap();
   models.define::<BookMark>().unwrap();
+  models.define::<models_old::BookV1>().unwrap();
   models.define::<Book>().unwrap();
+  models.define::<models_old::DataOfUnhashedBookV1>().unwrap();
   models.define::<DataOfUnhashedBook>().unwrap();
+  models.define::<models_old::DataOfHashedBookV1>().unwrap();
   models.define::<DataOfHashedBook>().unwrap();
   models.define::<TargetExt>().unwrap();
+  models.define::<OcrText>().unwrap();
+  models.define::<BookOutline>().unwrap();
+  models.define::<models_old::IndexedBookV1>().unwrap();
+  models.define::<IndexedBook>().unwrap();
+  models.define::<models_old::SearchTermV1>().unwrap();
+  models.define::<TermPostings>().unwrap();
+  models.define::<Collection>().unwrap();
+  models.define::<RemovedBook>().unwrap();
   models
 }
 
-fn get_db(models: &Models) -> native_db::db_type::Result<Database> {
-  let path_to_db = &APP_DIRS.read().unwrap().path_to_db;
+static MODELS: Lazy<Models> = Lazy::new(|| get_models());
+
+/// Opens the db of a core, it's created on the first run
+pub(crate) fn open_db(path_to_db: &Path) -> CoreResult<Database<'static>> {
   match path_to_db.exists() {
-    true => { Builder::new().open(models, path_to_db) }
-    false => { Builder::new().create(models, path_to_db) }
+    true => { Ok(Builder::new().open(&MODELS, path_to_db)?) }
+    false => { Ok(Builder::new().create(&MODELS, path_to_db)?) }
   }
 }
-
-static MODELS: Lazy<Models> = Lazy::new(|| get_models());
-pub(crate) static DB: Lazy<Database> = Lazy::new(|| get_db(&MODELS).unwrap());
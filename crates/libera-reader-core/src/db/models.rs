@@ -1,8 +1,13 @@
+use crate::db::models_old::{BookMarkV1, BookV1, DataOfHashedBookV1, DataOfUnhashedBookV1, IndexedBookV1, SettingsV1};
+use crate::error::CoreError;
 use crate::types::{BookHash, BookPath, BookSize};
+use mupdf::outline::Outline;
+use mupdf::widget::{Rect, WidgetType};
 use native_db::*;
 #[allow(unused_imports)]
 use native_model::{native_model, Model};
 use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
 
 
 #[derive(Serialize, Deserialize, Clone)]
@@ -16,33 +21,64 @@ pub enum Theme {
   Dark,
 }
 
+/// Directory the library books are collected from
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct LibraryRoot {
+  pub path: String,
+  pub enabled: bool,
+  /// Extensions of the books taken from this root, the global [`TargetExt`] is used if `None`
+  pub formats: Option<Vec<String>>,
+}
+
 #[derive(Serialize, Deserialize, Clone)]
-#[native_model(id = 1, version = 1)]
+#[native_model(id = 1, version = 2, from = SettingsV1)]
 #[native_db]
 pub struct Settings {
   #[primary_key]
   pub id: i32,
   pub language: Language,
   pub theme: Theme,
-  pub path_to_scan: Option<String>,
+  pub library_roots: Vec<LibraryRoot>,
+  /// Gitignore-style patterns excluded from every library root
+  pub ignore_patterns: Vec<String>,
+  /// Skip files and dirs whose name starts with a dot
+  pub skip_hidden: bool,
+  /// How deep to look for books inside a library root, unlimited if `None`
+  pub max_scan_depth: Option<usize>,
   pub number_of_columns: i32,
   pub page_scaling_factor: f64,
   pub thumbnails_scaling_factor: f64,
+  /// Threads extracting thumbnails and metadata, picked by the number of CPUs if not positive
   pub workers_num: i32,
+  /// Threads hashing books of the same size during a dir scan, `2` if not positive
+  pub hashing_workers_num: i32,
+  /// Threads extracting the text of books for the search index, `1` if not positive
+  pub indexing_workers_num: i32,
+  /// Background services run on one thread with pauses while a book is open
+  pub low_priority: bool,
+  /// Books of at least this many bytes are hashed by their head, middle and tail instead of the whole file,
+  /// applies to the books hashed after the change
+  pub partial_hash_min_size: Option<u64>,
+  pub ocr_language: String,
+  pub path_to_tessdata: Option<String>,
 }
 
-#[derive(Serialize, Deserialize)]
-#[native_model(id = 2, version = 1)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[native_model(id = 2, version = 2, from = BookMarkV1)]
 #[native_db]
-pub(crate) struct BookMark {
+pub struct BookMark {
   #[primary_key]
-  pub id: i32,
+  pub id: u64,
   pub title: String,
   pub content: String,
   pub page_number: i32,
-  pub book_data_link: String,
-  pub time_created: String,
-  pub time_updated: String,
+  /// Key of the shared book data record, so duplicates of a book share bookmarks
+  #[secondary_key]
+  pub book_data_key: String,
+  /// Unix time in seconds
+  pub time_created: u64,
+  /// Unix time in seconds
+  pub time_updated: u64,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -50,16 +86,27 @@ pub(crate) struct BookData {
   pub cached: bool,
   pub title: Option<String>,
   pub author: Option<String>,
+  pub subject: Option<String>,
+  pub keywords: Option<String>,
+  pub format: Option<String>,
   pub page_count: Option<i32>,
+  pub has_outline: bool,
   pub in_history: bool,
   pub favorite: bool,
+  /// Position of the book in the user-defined order of favorites
+  pub favorite_order: u32,
   pub last_page_number: i32,
-  pub latest_opening_in: Option<String>,
+  /// Unix time in seconds of the last time the book was read
+  pub latest_opening_in: Option<u64>,
+  /// Free-form user tags, lowercase
+  pub tags: Vec<String>,
+  /// Ids of the collections the book is in
+  pub collections: Vec<u64>,
   pub books_pk: Vec<BookPath>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
-#[native_model(id = 3, version = 1)]
+#[native_model(id = 3, version = 2, from = DataOfUnhashedBookV1)]
 #[native_db]
 pub(crate) struct DataOfUnhashedBook {
   #[primary_key]
@@ -69,7 +116,7 @@ pub(crate) struct DataOfUnhashedBook {
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
-#[native_model(id = 4, version = 1)]
+#[native_model(id = 4, version = 2, from = DataOfHashedBookV1)]
 #[native_db]
 pub(crate) struct DataOfHashedBook {
   #[secondary_key]
@@ -80,20 +127,28 @@ pub(crate) struct DataOfHashedBook {
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
-#[native_model(id = 5, version = 1)]
+#[native_model(id = 5, version = 2, from = BookV1)]
 #[native_db]
 pub struct Book {
   #[primary_key]
   pub path_to_book: String,
+  #[secondary_key]
   pub path_to_dir: String,
   pub dir_name: String,
+  #[secondary_key]
   pub book_name: String,
+  #[secondary_key]
   pub ext: String,
   pub path_is_valid: bool,
+  /// Path of the library root the book came from
+  #[secondary_key]
+  pub library_root: String,
   pub book_data_pk: BookDataType,
+  /// Unix time in seconds when the book was added to the library
+  pub added_at: u64,
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq, Hash)]
 pub enum BookDataType {
   UniqueSize(BookSize),
   RepeatingSize(BookHash),
@@ -109,3 +164,231 @@ pub struct TargetExt {
   pub epub: bool,
   pub mobi: bool,
 }
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[native_model(id = 7, version = 1)]
+#[native_db]
+pub(crate) struct OcrText {
+  #[primary_key]
+  pub book_hash: BookHash,
+  pub pages: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[native_model(id = 8, version = 1)]
+#[native_db]
+pub(crate) struct BookOutline {
+  #[primary_key]
+  pub book_data_key: String,
+  pub outlines: Vec<Outline>,
+}
+
+/// Book from the reading history
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct HistoryEntry {
+  pub book: Book,
+  pub last_page_number: i32,
+  pub page_count: Option<i32>,
+  /// Percentage of the book read, `None` if the page count is unknown yet
+  pub progress: Option<f32>,
+  /// Unix time in seconds
+  pub latest_opening_in: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum FavoritesChange {
+  Added(BookPath),
+  Removed(BookPath),
+  Reordered,
+}
+
+/// Change of the library sent to the subscribers of the core,
+/// `book_data_key` is the key of the data record shared by the duplicates of the book
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum CoreEvent {
+  BookAdded { book_path: BookPath, book_data_key: String },
+  BookRemoved { book_path: BookPath, book_data_key: String },
+  /// The file is gone, but the book stays in the db because of its favorites, history, tags or collections
+  BookMissing { book_path: BookPath, book_data_key: String },
+  BookMoved { old_path: BookPath, new_path: BookPath, book_data_key: String },
+  /// The book got a new data record, e.g. a duplicate of it appeared in the library
+  BookDataKeyChanged { book_path: BookPath, old_book_data_key: String, new_book_data_key: String },
+  ThumbnailReady { book_path: BookPath, book_data_key: String },
+  MetadataExtracted { book_path: BookPath, book_data_key: String },
+  FavoritesChanged(FavoritesChange),
+}
+
+/// What a background service is busy with
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProgressPhase {
+  Idle,
+  /// Walking the library roots and comparing them with the db
+  Scanning,
+  /// Removing books that are no longer on disk
+  Deleting,
+  /// Adding books of a unique size, they don't need a hash
+  Adding,
+  /// Hashing and adding books whose size matches another book
+  Hashing,
+  /// Extracting thumbnails and metadata
+  Extracting,
+  Indexing,
+  Recognizing,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ServiceProgress {
+  pub phase: ProgressPhase,
+  pub done: usize,
+  /// `0` while the number of items is not known yet
+  pub total: usize,
+  pub current_file: Option<BookPath>,
+  /// Seconds left, `None` until the first item of the phase is done
+  pub eta_secs: Option<u64>,
+}
+
+/// Progress of every background service at the moment of the query
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ServicesProgress {
+  /// Books added by the file system watcher, one phase per batch of settled changes
+  pub notify: ServiceProgress,
+  pub dir_scan: ServiceProgress,
+  pub data_extraction: ServiceProgress,
+  pub search_index: ServiceProgress,
+  #[cfg(feature = "ocr")]
+  pub ocr: ServiceProgress,
+}
+
+/// Text of an indexed book by pages, one record per group of duplicates
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[native_model(id = 9, version = 2, from = IndexedBookV1)]
+#[native_db]
+pub(crate) struct IndexedBook {
+  #[primary_key]
+  pub book_data_key: String,
+  pub book_data_pk: BookDataType,
+  pub pages: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Posting {
+  pub page_number: u32,
+  /// Number of occurrences of the term on the page
+  pub count: u32,
+}
+
+/// Entry of the inverted index: pages of one book containing the term.
+/// The key starts with the term, so the books containing it are read by a prefix scan
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[native_model(id = 13, version = 1)]
+#[native_db]
+pub(crate) struct TermPostings {
+  #[primary_key]
+  pub id: String,
+  #[secondary_key]
+  pub book_data_key: String,
+  pub postings: Vec<Posting>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PageHit {
+  pub page_number: u32,
+  pub snippet: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SearchResult {
+  pub book: Book,
+  pub score: f32,
+  /// Pages containing the query terms, the most relevant first
+  pub pages: Vec<PageHit>,
+}
+
+/// User-defined collection (shelf) of books
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[native_model(id = 11, version = 1)]
+#[native_db]
+pub struct Collection {
+  #[primary_key]
+  pub id: u64,
+  pub name: String,
+  /// Unix time in seconds
+  pub time_created: u64,
+}
+
+/// Data of the last copy of a book that was removed from the library, kept for a grace period,
+/// so the book gets its progress, favorites and bookmarks back if it shows up again, e.g. after a move
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[native_model(id = 12, version = 1)]
+#[native_db]
+pub(crate) struct RemovedBook {
+  #[primary_key]
+  pub book_path: BookPath,
+  #[secondary_key]
+  pub book_size: BookSize,
+  /// `None` if the book was never hashed, then a book of the same size and file name is taken for it
+  pub book_hash: Option<BookHash>,
+  pub book_data: BookData,
+  /// Unix time in seconds
+  pub removed_at: u64,
+}
+
+/// Identical files of the library, they share one data record
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DuplicateGroup {
+  pub book_hash: BookHash,
+  pub book_size: BookSize,
+  pub title: Option<String>,
+  pub copies: Vec<DuplicateCopy>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DuplicateCopy {
+  pub path_to_book: BookPath,
+  /// `None` if the file no longer exists
+  pub size_in_bytes: Option<u64>,
+  /// Unix time in seconds of the last modification of the file
+  pub modified_at: Option<u64>,
+}
+
+/// Field of the PDF form of a book
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct FormField {
+  pub page_number: u32,
+  /// Fields with the same name, e.g. the buttons of a radio group, share the value
+  pub name: String,
+  pub widget_type: WidgetType,
+  /// Text of a text field, selected option of a choice field,
+  /// `Off` or the name of the on state for checkboxes and radio buttons
+  pub value: String,
+  pub rect: Rect,
+  /// Options of a combobox or listbox, empty for other fields
+  pub options: Vec<String>,
+}
+
+/// New value of a form field, it must match the type of the field
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum FormValue {
+  Text(String),
+  Checked(bool),
+  /// Selected options of a combobox or listbox
+  Choice(Vec<String>),
+}
+
+/// What to do with the redundant copies of a book
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum DuplicateAction {
+  Delete,
+  /// Moves the copies to the trash of the system, so they can be restored
+  Trash,
+  /// Moves the copies into the quarantine dir, which must be outside the library
+  MoveTo(PathBuf),
+}
+
+/// Outcome of removing the redundant copies of every duplicate group
+#[derive(Debug)]
+pub struct RemovedDuplicates {
+  pub removed_copies: Vec<BookPath>,
+  /// Hashes of the groups that failed with the errors, the other groups are processed anyway
+  pub errors: Vec<(BookHash, CoreError)>,
+}
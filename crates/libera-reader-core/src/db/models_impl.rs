@@ -1,33 +1,37 @@
+use crate::context::Context;
 use crate::db::crud;
 use crate::db::models::{Book, BookData, DataOfHashedBook, DataOfUnhashedBook,
-                        Language, Settings, Theme};
+                        Language, LibraryRoot, Posting, RemovedBook, Settings, TermPostings, Theme};
 use crate::models::{BookDataType, TargetExt};
-use crate::services::notify_service;
 use crate::types::{BookHash, BookPath, BookSize};
-use crate::vars::PATH_TO_SCAN;
+use crate::error::{CoreError, CoreResult};
+use crate::utils::{find_library_root, get_timestamp, path_to_string};
+use itertools::Itertools;
+use native_db::transaction::RwTransaction;
 use std::hash::{Hash, Hasher};
-use std::path::PathBuf;
-use tracing::error;
+use std::path::{Path, PathBuf};
 
 
 impl Book {
-  pub(crate) fn from_pathbuf(future_book: &PathBuf, book_data_type: BookDataType) -> Self {
-    Self {
-      path_to_book: future_book.to_str().unwrap().to_string(),
-      path_to_dir: future_book.parent().unwrap().to_str().unwrap().to_string(),
-      book_name: future_book.file_name().unwrap().to_str().unwrap().to_string(),
-      dir_name: future_book.parent().unwrap().file_name().unwrap().to_str().unwrap().to_string(),
-      ext: future_book.extension().unwrap().to_str().unwrap().to_string(),
+  pub(crate) fn from_pathbuf(ctx: &Context, future_book: &PathBuf, book_data_type: BookDataType) -> CoreResult<Self> {
+    let invalid_path = || CoreError::InvalidPath(future_book.clone());
+    let parent = future_book.parent().ok_or_else(invalid_path)?;
+    Ok(Self {
+      path_to_book: path_to_string(future_book)?,
+      path_to_dir: path_to_string(parent)?,
+      book_name: future_book.file_name().and_then(|name| name.to_str()).ok_or_else(invalid_path)?.to_string(),
+      dir_name: parent.file_name().and_then(|name| name.to_str()).unwrap_or_default().to_string(),
+      ext: future_book.extension().and_then(|ext| ext.to_str()).ok_or_else(invalid_path)?.to_string(),
       book_data_pk: book_data_type,
       path_is_valid: true,
-    }
+      library_root: find_library_root(ctx, future_book).map_or(String::new(), |library_root| library_root.path),
+      added_at: get_timestamp(),
+    })
   }
-  pub(crate) fn get_book_data(&self) -> BookData {
-    let book_data = match self.book_data_pk.clone() {
-      BookDataType::UniqueSize(book_size) => { crud::get_primary::<DataOfUnhashedBook>(book_size).unwrap().book_data }
-      BookDataType::RepeatingSize(book_hash) => { crud::get_primary::<DataOfHashedBook>(book_hash).unwrap().book_data }
-    };
-    book_data
+  pub(crate) fn get_book_data(&self, ctx: &Context) -> CoreResult<BookData> {
+    crud::book::get_book_data_with_size(ctx, &self.book_data_pk)?
+      .map(|(book_data, _)| book_data)
+      .ok_or_else(|| CoreError::NotFound(format!("data of the book {}", self.path_to_book)))
   }
 }
 impl Hash for Book {
@@ -42,44 +46,40 @@ impl PartialEq for Book {
 }
 impl Eq for Book {}
 
-impl Settings {
-  pub(crate) fn new() -> Settings {
-    *PATH_TO_SCAN.write().unwrap() = Settings::get_self().path_to_scan;
-    Self::get_self()
-  }
-  pub fn set_path_to_scan(&mut self, path_to_scan: String) {
-    let old_settings = Settings::get_self();
-    match &old_settings.path_to_scan {
-      None => {}
-      Some(path_to_scan) => notify_service::stop_watcher(path_to_scan)
-    };
-    notify_service::run_watcher(&path_to_scan);
-    let mut new_settings = old_settings.clone();
-    new_settings.path_to_scan = Some(path_to_scan.clone());
-    match crud::update::<Self>(old_settings, new_settings) {
-      Ok(_) => {}
-      Err(e) => {
-        error!("{:?}", &e);
-        panic!("{:?}", e);
-      }
-    };
-    let _ = PATH_TO_SCAN.write().unwrap().insert(path_to_scan);
-  }
-  pub fn get_path_to_scan(&self) -> Option<String> {
-    PATH_TO_SCAN.read().unwrap().clone()
-  }
-  pub fn path_to_scan_is_valid(&self) -> bool {
-    PATH_TO_SCAN.read().unwrap().is_some()
+impl BookDataType {
+  /// Key of the book data record, also used as the thumbnail file name
+  pub(crate) fn as_key(&self) -> String {
+    match self {
+      BookDataType::UniqueSize(book_size) => book_size.to_string(),
+      BookDataType::RepeatingSize(book_hash) => book_hash.clone(),
+    }
   }
-  //noinspection RsUnwrap
-  fn get_self() -> Self {
-    match crud::get_primary::<Self>(1) {
+}
+
+impl Settings {
+  pub(crate) fn from_db(ctx: &Context) -> CoreResult<Self> {
+    match crud::get_primary::<Self>(ctx, 1)? {
       None => {
         let settings_model = Self::default();
-        crud::insert(settings_model.clone()).unwrap();
-        settings_model
+        crud::insert(ctx, settings_model.clone())?;
+        Ok(settings_model)
       }
-      Some(res) => { res }
+      Some(res) => { Ok(res) }
+    }
+  }
+  /// Saves the change and returns the new settings
+  pub(crate) fn update(ctx: &Context, change_settings: impl FnOnce(&mut Settings)) -> CoreResult<Settings> {
+    let old_settings = Settings::from_db(ctx)?;
+    let mut new_settings = old_settings.clone();
+    change_settings(&mut new_settings);
+    crud::update::<Self>(ctx, old_settings, new_settings.clone())?;
+    Ok(new_settings)
+  }
+  /// Directory with the `*.traineddata` files, the `tessdata` app dir is used if no other is set
+  pub(crate) fn get_path_to_tessdata(&self, ctx: &Context) -> PathBuf {
+    match &self.path_to_tessdata {
+      None => ctx.app_dirs.tessdata.clone(),
+      Some(path_to_tessdata) => PathBuf::from(path_to_tessdata),
     }
   }
 }
@@ -89,15 +89,40 @@ impl Default for Settings {
       id: 1,
       language: Language::EN,
       theme: Theme::Sunset,
-      path_to_scan: None,
+      library_roots: vec![],
+      ignore_patterns: vec![],
+      skip_hidden: true,
+      max_scan_depth: None,
       number_of_columns: 6,
       page_scaling_factor: 1.0,
       thumbnails_scaling_factor: 4.0,
-      workers_num: 2,
+      workers_num: 0,
+      hashing_workers_num: 0,
+      indexing_workers_num: 0,
+      low_priority: false,
+      partial_hash_min_size: None,
+      ocr_language: "eng".to_string(),
+      path_to_tessdata: None,
     }
   }
 }
 
+impl LibraryRoot {
+  pub fn new(path: String) -> Self {
+    Self { path, enabled: true, formats: None }
+  }
+  /// `default_formats` apply if the root has no formats of its own
+  pub fn accepts_ext(&self, ext: &str, default_formats: &TargetExt) -> bool {
+    match &self.formats {
+      None => default_formats.contains(ext),
+      Some(formats) => formats.iter().any(|format| format.eq_ignore_ascii_case(ext)),
+    }
+  }
+  pub fn contains(&self, path: &Path) -> bool {
+    path.starts_with(&self.path)
+  }
+}
+
 impl DataOfHashedBook {
   pub fn new(hash: String, file_size: BookSize, books_pk: Vec<BookPath>) -> Self {
     DataOfHashedBook {
@@ -107,11 +132,18 @@ impl DataOfHashedBook {
         cached: false,
         title: None,
         author: None,
+        subject: None,
+        keywords: None,
+        format: None,
         page_count: None,
+        has_outline: false,
         in_history: false,
         favorite: false,
+        favorite_order: 0,
         last_page_number: 0,
         latest_opening_in: None,
+        tags: vec![],
+        collections: vec![],
         books_pk,
       },
     }
@@ -126,38 +158,115 @@ impl DataOfUnhashedBook {
         cached: false,
         title: None,
         author: None,
+        subject: None,
+        keywords: None,
+        format: None,
         page_count: None,
+        has_outline: false,
         in_history: false,
         favorite: false,
+        favorite_order: 0,
         last_page_number: 0,
         latest_opening_in: None,
+        tags: vec![],
+        collections: vec![],
         books_pk,
       },
     }
   }
-  pub(crate) fn replace_to_data_of_hashed_book(self, book_hash: BookHash) {
-    let old_book_data = crud::remove::<Self>(self).unwrap();
+  pub(crate) fn replace_to_data_of_hashed_book(self, rw_conn: &RwTransaction, book_hash: BookHash)
+                                               -> CoreResult<()> {
+    let old_book_data = rw_conn.remove::<Self>(self)?;
+    let old_book_data_key = BookDataType::UniqueSize(old_book_data.book_size).as_key();
+    crud::bookmark::relink_bookmarks(rw_conn, &old_book_data_key, &book_hash)?;
+    crud::book::relink_outline(rw_conn, &old_book_data_key, &book_hash)?;
+    crud::search_index::relink_index(rw_conn, &old_book_data_key, BookDataType::RepeatingSize(book_hash.clone()))?;
     let new_book_data = DataOfHashedBook {
       book_hash,
       book_size: old_book_data.book_size,
       book_data: old_book_data.book_data,
     };
-    crud::insert::<DataOfHashedBook>(new_book_data).unwrap();
+    Ok(rw_conn.insert::<DataOfHashedBook>(new_book_data)?)
   }
 }
 
 pub trait GetBookData {
   fn get_book_data_as_ref(&self) -> &BookData;
+  fn get_book_data_as_mut(&mut self) -> &mut BookData;
+  fn get_book_size(&self) -> BookSize;
+  fn get_book_hash(&self) -> Option<BookHash>;
 }
 impl GetBookData for DataOfUnhashedBook {
   fn get_book_data_as_ref(&self) -> &BookData {
     &self.book_data
   }
+  fn get_book_data_as_mut(&mut self) -> &mut BookData {
+    &mut self.book_data
+  }
+  fn get_book_size(&self) -> BookSize {
+    self.book_size
+  }
+  fn get_book_hash(&self) -> Option<BookHash> {
+    self.book_hash.clone()
+  }
 }
 impl GetBookData for DataOfHashedBook {
   fn get_book_data_as_ref(&self) -> &BookData {
     &self.book_data
   }
+  fn get_book_data_as_mut(&mut self) -> &mut BookData {
+    &mut self.book_data
+  }
+  fn get_book_size(&self) -> BookSize {
+    self.book_size
+  }
+  fn get_book_hash(&self) -> Option<BookHash> {
+    Some(self.book_hash.clone())
+  }
+}
+
+impl BookData {
+  /// Whether the user has done anything with the book, such a book stays in the db while its file is missing
+  pub(crate) fn has_user_data(&self) -> bool {
+    self.favorite || self.in_history || !self.tags.is_empty() || !self.collections.is_empty()
+  }
+  /// Takes the progress, favorites, tags and collections of a removed copy of the book
+  pub(crate) fn merge_user_data(&mut self, other: &BookData) {
+    if !self.favorite && other.favorite {
+      self.favorite = true;
+      self.favorite_order = other.favorite_order;
+    }
+    if other.latest_opening_in > self.latest_opening_in {
+      self.latest_opening_in = other.latest_opening_in;
+      self.last_page_number = other.last_page_number;
+    }
+    self.in_history |= other.in_history;
+    self.tags = self.tags.iter().chain(&other.tags).unique().cloned().collect();
+    self.collections = self.collections.iter().chain(&other.collections).unique().cloned().collect();
+  }
+}
+
+impl RemovedBook {
+  /// Bookmarks of the removed book are kept under this key
+  pub(crate) fn bookmarks_key(&self) -> String {
+    format!("removed:{}", self.book_path)
+  }
+}
+
+/// Terms are made of alphanumeric chars, so the separator can't be a part of them
+const TERM_SEPARATOR: char = '\0';
+
+impl TermPostings {
+  pub(crate) fn new(term: &str, book_data_key: String, postings: Vec<Posting>) -> Self {
+    Self { id: format!("{term}{TERM_SEPARATOR}{book_data_key}"), book_data_key, postings }
+  }
+  /// Start of the keys of the books containing the term
+  pub(crate) fn key_prefix(term: &str) -> String {
+    format!("{term}{TERM_SEPARATOR}")
+  }
+  pub(crate) fn term(&self) -> &str {
+    self.id.split(TERM_SEPARATOR).next().unwrap_or_default()
+  }
 }
 
 impl TargetExt {
@@ -169,8 +278,14 @@ impl TargetExt {
       mobi: false,
     }
   }
-  pub(crate) fn from_db() -> Option<TargetExt> {
-    crud::get_primary::<TargetExt>(1)
+  pub(crate) fn from_db(ctx: &Context) -> CoreResult<Self> {
+    match crud::get_primary::<TargetExt>(ctx, 1)? {
+      None => {
+        crud::insert::<Self>(ctx, TargetExt::new())?;
+        Ok(TargetExt::new())
+      }
+      Some(res) => { Ok(res) }
+    }
   }
   pub fn contains(&self, ext: &str) -> bool {
     let ext_is_pdf = ext.eq("pdf") && self.pdf;
@@ -182,42 +297,4 @@ impl TargetExt {
       false
     }
   }
-  pub fn set_pdf(&mut self, value: bool) {
-    let old_self = Self::get_self();
-    let mut new_self = old_self.clone();
-    new_self.pdf = value.clone();
-    crud::update(old_self, new_self).unwrap();
-    self.pdf = value;
-  }
-  pub fn set_epub(&mut self, value: bool) {
-    let old_self = Self::get_self();
-    let mut new_self = old_self.clone();
-    new_self.epub = value.clone();
-    crud::update(old_self, new_self).unwrap();
-    self.epub = value;
-  }
-  pub fn set_mobi(&mut self, value: bool) {
-    let old_self = Self::get_self();
-    let mut new_self = old_self.clone();
-    new_self.mobi = value.clone();
-    crud::update(old_self, new_self).unwrap();
-    self.mobi = value;
-  }
-  pub(crate) fn get_self() -> Self {
-    crud::get_primary::<Self>(1).unwrap()
-  }
 }
-impl Default for TargetExt {
-  fn default() -> Self {
-    match TargetExt::from_db() {
-      None => {
-        crud::insert::<Self>(TargetExt::new()).unwrap();
-        TargetExt::from_db().unwrap()
-      }
-      Some(res) => {
-        res
-      }
-    }
-  }
-}
-
@@ -0,0 +1,364 @@
+use crate::db::models::{Book, BookData, BookDataType, BookMark, DataOfHashedBook, DataOfUnhashedBook, IndexedBook,
+                        Language, LibraryRoot, Settings, Theme};
+use crate::types::{BookHash, BookPath, BookSize};
+use native_db::*;
+#[allow(unused_imports)]
+use native_model::{native_model, Model};
+use serde::{Deserialize, Serialize};
+
+/// Size of the book in megabytes rounded to 6 decimals, e.g. `1.177375`
+pub(crate) type BookSizeInMb = String;
+
+
+#[derive(Serialize, Deserialize, Clone)]
+#[native_model(id = 1, version = 1)]
+#[native_db]
+pub(crate) struct SettingsV1 {
+  #[primary_key]
+  pub id: i32,
+  pub language: Language,
+  pub theme: Theme,
+  pub path_to_scan: Option<String>,
+  pub number_of_columns: i32,
+  pub page_scaling_factor: f64,
+  pub thumbnails_scaling_factor: f64,
+  pub workers_num: i32,
+}
+
+/// The dir to scan becomes the only library root. Nothing is excluded from it,
+/// the first versions scanned the hidden files too. Partial hashing is off, the books of the first versions
+/// are hashed whole, the pools are sized by their defaults
+impl From<SettingsV1> for Settings {
+  fn from(settings: SettingsV1) -> Self {
+    Self {
+      id: settings.id,
+      language: settings.language,
+      theme: settings.theme,
+      library_roots: match settings.path_to_scan {
+        None => vec![],
+        Some(path_to_scan) => vec![LibraryRoot::new(path_to_scan)],
+      },
+      skip_hidden: false,
+      number_of_columns: settings.number_of_columns,
+      page_scaling_factor: settings.page_scaling_factor,
+      thumbnails_scaling_factor: settings.thumbnails_scaling_factor,
+      workers_num: settings.workers_num,
+      ..Settings::default()
+    }
+  }
+}
+
+impl From<Settings> for SettingsV1 {
+  fn from(settings: Settings) -> Self {
+    Self {
+      id: settings.id,
+      language: settings.language,
+      theme: settings.theme,
+      path_to_scan: settings.library_roots.into_iter().next().map(|library_root| library_root.path),
+      number_of_columns: settings.number_of_columns,
+      page_scaling_factor: settings.page_scaling_factor,
+      thumbnails_scaling_factor: settings.thumbnails_scaling_factor,
+      workers_num: settings.workers_num,
+    }
+  }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[native_model(id = 2, version = 1)]
+#[native_db]
+pub(crate) struct BookMarkV1 {
+  #[primary_key]
+  pub id: i32,
+  pub title: String,
+  pub content: String,
+  pub page_number: i32,
+  pub book_data_link: String,
+  pub time_created: String,
+  pub time_updated: String,
+}
+
+impl From<BookMarkV1> for BookMark {
+  fn from(bookmark: BookMarkV1) -> Self {
+    Self {
+      id: bookmark.id as u64,
+      title: bookmark.title,
+      content: bookmark.content,
+      page_number: bookmark.page_number,
+      book_data_key: bookmark.book_data_link,
+      time_created: parse_timestamp(&bookmark.time_created).unwrap_or(0),
+      time_updated: parse_timestamp(&bookmark.time_updated).unwrap_or(0),
+    }
+  }
+}
+
+impl From<BookMark> for BookMarkV1 {
+  fn from(bookmark: BookMark) -> Self {
+    Self {
+      id: bookmark.id as i32,
+      title: bookmark.title,
+      content: bookmark.content,
+      page_number: bookmark.page_number,
+      book_data_link: bookmark.book_data_key,
+      time_created: bookmark.time_created.to_string(),
+      time_updated: bookmark.time_updated.to_string(),
+    }
+  }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(crate) struct BookDataV1 {
+  pub cached: bool,
+  pub title: Option<String>,
+  pub author: Option<String>,
+  pub page_count: Option<i32>,
+  pub in_history: bool,
+  pub favorite: bool,
+  pub last_page_number: i32,
+  pub latest_opening_in: Option<String>,
+  pub books_pk: Vec<BookPath>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[native_model(id = 3, version = 1)]
+#[native_db]
+pub(crate) struct DataOfUnhashedBookV1 {
+  #[primary_key]
+  pub book_size: BookSizeInMb,
+  pub book_hash: Option<BookHash>,
+  pub book_data: BookDataV1,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[native_model(id = 4, version = 1)]
+#[native_db]
+pub(crate) struct DataOfHashedBookV1 {
+  #[secondary_key]
+  pub book_size: BookSizeInMb,
+  #[primary_key]
+  pub book_hash: BookHash,
+  pub book_data: BookDataV1,
+}
+
+/// The metadata is extracted again along with the thumbnail. The time the book was last opened becomes
+/// unix seconds, a time that can't be read is dropped. Favorites have no order yet, they are listed
+/// in the order of the db until one is moved. The books have no tags and aren't in any collection
+impl From<BookDataV1> for BookData {
+  fn from(book_data: BookDataV1) -> Self {
+    Self {
+      cached: false,
+      title: book_data.title,
+      author: book_data.author,
+      subject: None,
+      keywords: None,
+      format: None,
+      page_count: book_data.page_count,
+      has_outline: false,
+      in_history: book_data.in_history,
+      favorite: book_data.favorite,
+      favorite_order: 0,
+      last_page_number: book_data.last_page_number,
+      latest_opening_in: book_data.latest_opening_in.as_deref().and_then(parse_timestamp),
+      tags: vec![],
+      collections: vec![],
+      books_pk: book_data.books_pk,
+    }
+  }
+}
+
+impl From<BookData> for BookDataV1 {
+  fn from(book_data: BookData) -> Self {
+    Self {
+      cached: book_data.cached,
+      title: book_data.title,
+      author: book_data.author,
+      page_count: book_data.page_count,
+      in_history: book_data.in_history,
+      favorite: book_data.favorite,
+      last_page_number: book_data.last_page_number,
+      latest_opening_in: book_data.latest_opening_in.map(|time| time.to_string()),
+      books_pk: book_data.books_pk,
+    }
+  }
+}
+
+/// The exact size is only an estimate, the migration measures the books still on disk
+impl From<DataOfUnhashedBookV1> for DataOfUnhashedBook {
+  fn from(data: DataOfUnhashedBookV1) -> Self {
+    Self { book_size: mb_to_bytes(&data.book_size), book_hash: data.book_hash, book_data: data.book_data.into() }
+  }
+}
+
+impl From<DataOfUnhashedBook> for DataOfUnhashedBookV1 {
+  fn from(data: DataOfUnhashedBook) -> Self {
+    Self { book_size: bytes_to_mb(data.book_size), book_hash: data.book_hash, book_data: data.book_data.into() }
+  }
+}
+
+impl From<DataOfHashedBookV1> for DataOfHashedBook {
+  fn from(data: DataOfHashedBookV1) -> Self {
+    Self { book_size: mb_to_bytes(&data.book_size), book_hash: data.book_hash, book_data: data.book_data.into() }
+  }
+}
+
+impl From<DataOfHashedBook> for DataOfHashedBookV1 {
+  fn from(data: DataOfHashedBook) -> Self {
+    Self { book_size: bytes_to_mb(data.book_size), book_hash: data.book_hash, book_data: data.book_data.into() }
+  }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+pub(crate) enum BookDataTypeV1 {
+  UniqueSize(BookSizeInMb),
+  RepeatingSize(BookHash),
+}
+
+impl BookDataTypeV1 {
+  pub(crate) fn as_key(&self) -> String {
+    match self {
+      BookDataTypeV1::UniqueSize(book_size) => book_size.clone(),
+      BookDataTypeV1::RepeatingSize(book_hash) => book_hash.clone(),
+    }
+  }
+}
+
+impl From<BookDataTypeV1> for BookDataType {
+  fn from(book_data_pk: BookDataTypeV1) -> Self {
+    match book_data_pk {
+      BookDataTypeV1::UniqueSize(book_size) => BookDataType::UniqueSize(mb_to_bytes(&book_size)),
+      BookDataTypeV1::RepeatingSize(book_hash) => BookDataType::RepeatingSize(book_hash),
+    }
+  }
+}
+
+impl From<BookDataType> for BookDataTypeV1 {
+  fn from(book_data_pk: BookDataType) -> Self {
+    match book_data_pk {
+      BookDataType::UniqueSize(book_size) => BookDataTypeV1::UniqueSize(bytes_to_mb(book_size)),
+      BookDataType::RepeatingSize(book_hash) => BookDataTypeV1::RepeatingSize(book_hash),
+    }
+  }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[native_model(id = 5, version = 1)]
+#[native_db]
+pub(crate) struct BookV1 {
+  #[primary_key]
+  pub path_to_book: String,
+  pub path_to_dir: String,
+  pub dir_name: String,
+  pub book_name: String,
+  pub ext: String,
+  pub path_is_valid: bool,
+  pub book_data_pk: BookDataTypeV1,
+}
+
+/// The time the book was added is unknown, such books go first when sorted by it.
+/// The migration finds the library root of the book in the settings
+impl From<BookV1> for Book {
+  fn from(book: BookV1) -> Self {
+    Self {
+      path_to_book: book.path_to_book,
+      path_to_dir: book.path_to_dir,
+      dir_name: book.dir_name,
+      book_name: book.book_name,
+      ext: book.ext,
+      path_is_valid: book.path_is_valid,
+      library_root: String::new(),
+      book_data_pk: book.book_data_pk.into(),
+      added_at: 0,
+    }
+  }
+}
+
+impl From<Book> for BookV1 {
+  fn from(book: Book) -> Self {
+    Self {
+      path_to_book: book.path_to_book,
+      path_to_dir: book.path_to_dir,
+      dir_name: book.dir_name,
+      book_name: book.book_name,
+      ext: book.ext,
+      path_is_valid: book.path_is_valid,
+      book_data_pk: book.book_data_pk.into(),
+    }
+  }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[native_model(id = 9, version = 1)]
+#[native_db]
+pub(crate) struct IndexedBookV1 {
+  #[primary_key]
+  pub book_data_key: String,
+  pub book_data_pk: BookDataTypeV1,
+  pub pages: Vec<String>,
+}
+
+/// The key is only an estimate, the migration moves the index to the key of the migrated book data
+impl From<IndexedBookV1> for IndexedBook {
+  fn from(indexed_book: IndexedBookV1) -> Self {
+    let book_data_pk: BookDataType = indexed_book.book_data_pk.into();
+    Self { book_data_key: book_data_pk.as_key(), book_data_pk, pages: indexed_book.pages }
+  }
+}
+
+impl From<IndexedBook> for IndexedBookV1 {
+  fn from(indexed_book: IndexedBook) -> Self {
+    let book_data_pk: BookDataTypeV1 = indexed_book.book_data_pk.into();
+    Self { book_data_key: book_data_pk.as_key(), book_data_pk, pages: indexed_book.pages }
+  }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub(crate) struct PostingV1 {
+  pub book_data_key: String,
+  pub page_number: u32,
+  pub count: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[native_model(id = 10, version = 1)]
+#[native_db]
+pub(crate) struct SearchTermV1 {
+  #[primary_key]
+  pub term: String,
+  pub postings: Vec<PostingV1>,
+}
+
+pub(crate) fn mb_to_bytes(book_size: &str) -> BookSize {
+  (book_size.parse::<f64>().unwrap_or(0.0) * 1024.0 * 1024.0).round() as BookSize
+}
+
+fn bytes_to_mb(book_size: BookSize) -> BookSizeInMb {
+  ((book_size as f64 / (1024.0 * 1024.0) * 1e6).round() / 1e6).to_string()
+}
+
+/// Unix time in seconds from the way the first schemas stored time: the seconds themselves
+/// or a UTC date and time like `2024-05-17 09:30:00` or `2024-05-17T09:30:00Z`
+pub(crate) fn parse_timestamp(time: &str) -> Option<u64> {
+  let time = time.trim();
+  if let Ok(secs) = time.parse::<u64>() {
+    return Some(secs);
+  }
+  let time = time.trim_end_matches('Z');
+  let (date, clock) = time.split_once(|c| c == 'T' || c == ' ').unwrap_or((time, "00:00:00"));
+  let mut date = date.splitn(3, '-').map(|part| part.parse::<i64>().ok());
+  let (year, month, day) = (date.next()??, date.next()??, date.next()??);
+  // Fractions of a second are dropped
+  let mut clock = clock.splitn(3, ':').map(|part| part.split('.').next().unwrap_or(part).parse::<i64>().ok());
+  let hours = clock.next()??;
+  let minutes = clock.next().flatten().unwrap_or(0);
+  let secs = clock.next().flatten().unwrap_or(0);
+  if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+    return None;
+  }
+  // Days since 1970-01-01 in the proleptic Gregorian calendar
+  let shifted_year = if month <= 2 { year - 1 } else { year };
+  let era = shifted_year.div_euclid(400);
+  let year_of_era = shifted_year - era * 400;
+  let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+  let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+  let days = era * 146097 + day_of_era - 719468;
+  u64::try_from(days * 86400 + hours * 3600 + minutes * 60 + secs).ok()
+}
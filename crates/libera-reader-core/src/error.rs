@@ -0,0 +1,63 @@
+use native_db::db_type;
+use std::fmt::{Display, Formatter};
+use std::path::PathBuf;
+
+
+pub type CoreResult<T> = Result<T, CoreError>;
+
+#[derive(Debug)]
+pub enum CoreError {
+  Db(db_type::Error),
+  Io(std::io::Error),
+  /// The file system watcher failed to watch a library root
+  Watcher(notify::Error),
+  /// The file couldn't be moved to the trash of the system
+  Trash(trash::Error),
+  /// MuPDF failed to open or read the document
+  Document(String),
+  /// The path isn't valid UTF-8 or has no file name, parent dir or extension
+  InvalidPath(PathBuf),
+  /// A record the db is expected to contain is missing
+  NotFound(String),
+  InvalidArgument(String),
+}
+
+impl Display for CoreError {
+  fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+    match self {
+      CoreError::Db(e) => write!(f, "db error: {e}"),
+      CoreError::Io(e) => write!(f, "io error: {e}"),
+      CoreError::Watcher(e) => write!(f, "watcher error: {e}"),
+      CoreError::Trash(e) => write!(f, "trash error: {e}"),
+      CoreError::Document(e) => write!(f, "document error: {e}"),
+      CoreError::InvalidPath(path) => write!(f, "invalid path: {}", path.display()),
+      CoreError::NotFound(what) => write!(f, "not found: {what}"),
+      CoreError::InvalidArgument(e) => write!(f, "invalid argument: {e}"),
+    }
+  }
+}
+
+impl std::error::Error for CoreError {
+  fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+    match self {
+      CoreError::Db(e) => Some(e),
+      CoreError::Io(e) => Some(e),
+      CoreError::Watcher(e) => Some(e),
+      CoreError::Trash(e) => Some(e),
+      _ => None,
+    }
+  }
+}
+
+impl From<db_type::Error> for CoreError {
+  fn from(e: db_type::Error) -> Self { CoreError::Db(e) }
+}
+impl From<std::io::Error> for CoreError {
+  fn from(e: std::io::Error) -> Self { CoreError::Io(e) }
+}
+impl From<notify::Error> for CoreError {
+  fn from(e: notify::Error) -> Self { CoreError::Watcher(e) }
+}
+impl From<trash::Error> for CoreError {
+  fn from(e: trash::Error) -> Self { CoreError::Trash(e) }
+}
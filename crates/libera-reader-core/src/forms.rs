@@ -0,0 +1,60 @@
+use crate::error::{CoreError, CoreResult};
+use crate::models::{FormField, FormValue};
+use itertools::Itertools;
+use mupdf::document::Document;
+use mupdf::widget::Widget;
+
+
+pub(crate) fn get_form_fields(path_to_book: &str) -> CoreResult<Vec<FormField>> {
+  let doc = Document::open(path_to_book, 20).map_err(CoreError::Document)?;
+  let mut form_fields = vec![];
+  for page_number in 0..doc.page_count().map_err(CoreError::Document)? {
+    let page = doc.load_page(page_number as i32).map_err(CoreError::Document)?;
+    for widget in page.widgets().map_err(CoreError::Document)? {
+      form_fields.push(FormField {
+        page_number,
+        name: widget.name,
+        widget_type: widget.widget_type,
+        value: widget.value,
+        rect: widget.rect,
+        options: widget.options,
+      });
+    }
+  }
+  Ok(form_fields)
+}
+
+/// Fills the fields and saves the book into `path_to_out`, the changes are appended to the book
+/// if it is saved over itself. Returns the fields that weren't found in the book
+pub(crate) fn fill_form(path_to_book: &str, values: &[(FormField, FormValue)], path_to_out: &str)
+                        -> CoreResult<Vec<FormField>> {
+  let doc = Document::open(path_to_book, 20).map_err(CoreError::Document)?;
+  let mut not_found = values.iter().map(|(form_field, _)| form_field).collect_vec();
+  for (page_number, values) in &values.iter().sorted_by_key(|(form_field, _)| form_field.page_number)
+    .chunk_by(|(form_field, _)| form_field.page_number) {
+    let page = match doc.load_page(page_number as i32) {
+      Ok(page) => page,
+      Err(_) => { continue; }
+    };
+    let mut widgets = page.widgets().map_err(CoreError::Document)?;
+    for (form_field, value) in values {
+      // The rect tells apart the fields sharing a name, e.g. the buttons of a radio group
+      let widget = widgets.iter_mut().find(|widget| widget.name == form_field.name && widget.rect == form_field.rect);
+      if let Some(widget) = widget {
+        set_value(widget, value).map_err(CoreError::Document)?;
+        not_found.retain(|not_found_field| *not_found_field != form_field);
+      }
+    }
+  }
+  let incremental = path_to_out == path_to_book;
+  doc.save(path_to_out, incremental).map_err(CoreError::Document)?;
+  Ok(not_found.into_iter().cloned().collect())
+}
+
+fn set_value(widget: &mut Widget, value: &FormValue) -> Result<(), String> {
+  match value {
+    FormValue::Text(text) => widget.set_text(text),
+    FormValue::Checked(checked) => widget.set_checked(*checked),
+    FormValue::Choice(options) => widget.set_choice(&options.iter().map(String::as_str).collect_vec()),
+  }
+}
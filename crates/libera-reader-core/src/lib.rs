@@ -2,12 +2,24 @@ mod db;
 mod services;
 
 mod app_dirs;
+mod context;
+mod progress;
+mod workers;
 
 mod utils;
+mod forms;
+mod scan_rules;
 mod book_api;
+mod settings_api;
+pub mod book_query;
 mod types;
-pub mod vars;
+pub mod error;
 pub mod core;
 
 
 pub use crate::db::models;
+pub use crate::error::{CoreError, CoreResult};
+pub use crate::scan_rules::IGNORE_FILE_NAME;
+pub use mupdf::attachment;
+pub use mupdf::outline;
+pub use mupdf::widget;
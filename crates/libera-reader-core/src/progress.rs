@@ -0,0 +1,112 @@
+use crate::models::{ProgressPhase, ServiceProgress, ServicesProgress};
+use crate::types::BookPath;
+use std::sync::Mutex;
+use std::time::Instant;
+
+
+struct ProgressState {
+  phase: ProgressPhase,
+  done: usize,
+  total: usize,
+  current_file: Option<BookPath>,
+  phase_started_at: Instant,
+}
+
+/// Progress of one service, updated from its worker threads
+pub(crate) struct Progress {
+  state: Mutex<ProgressState>,
+}
+
+impl Progress {
+  fn new() -> Self {
+    Self {
+      state: Mutex::new(ProgressState {
+        phase: ProgressPhase::Idle,
+        done: 0,
+        total: 0,
+        current_file: None,
+        phase_started_at: Instant::now(),
+      }),
+    }
+  }
+  /// Starts the phase of a known size, `0` if the size is not known yet
+  pub(crate) fn start(&self, phase: ProgressPhase, total: usize) {
+    let mut state = self.state.lock().unwrap();
+    *state = ProgressState { phase, done: 0, total, current_file: None, phase_started_at: Instant::now() };
+  }
+  /// Adds queued items to the phase, starting it if the service is idle
+  pub(crate) fn add_to_total(&self, phase: ProgressPhase, num_of_items: usize) {
+    let mut state = self.state.lock().unwrap();
+    if state.phase == ProgressPhase::Idle {
+      *state = ProgressState { phase, done: 0, total: 0, current_file: None, phase_started_at: Instant::now() };
+    }
+    state.total += num_of_items;
+  }
+  pub(crate) fn set_current_file(&self, book_path: &BookPath) {
+    self.state.lock().unwrap().current_file = Some(book_path.clone());
+  }
+  pub(crate) fn advance(&self, num_of_items: usize) {
+    self.state.lock().unwrap().done += num_of_items;
+  }
+  pub(crate) fn finish(&self) {
+    self.start(ProgressPhase::Idle, 0);
+  }
+  /// Goes idle once every queued item is done, items queued meanwhile keep the phase going
+  pub(crate) fn finish_if_done(&self) {
+    let mut state = self.state.lock().unwrap();
+    if state.phase != ProgressPhase::Idle && state.done >= state.total {
+      *state = ProgressState {
+        phase: ProgressPhase::Idle, done: 0, total: 0, current_file: None, phase_started_at: Instant::now(),
+      };
+    }
+  }
+  pub(crate) fn snapshot(&self) -> ServiceProgress {
+    let state = self.state.lock().unwrap();
+    let eta_secs = match state.done {
+      0 => None,
+      done => {
+        let secs_per_item = state.phase_started_at.elapsed().as_secs_f64() / done as f64;
+        Some((secs_per_item * state.total.saturating_sub(done) as f64).round() as u64)
+      }
+    };
+    ServiceProgress {
+      phase: state.phase,
+      done: state.done,
+      total: state.total,
+      current_file: state.current_file.clone(),
+      eta_secs,
+    }
+  }
+}
+
+pub(crate) struct ServicesProgressTracker {
+  pub(crate) notify: Progress,
+  pub(crate) dir_scan: Progress,
+  pub(crate) data_extraction: Progress,
+  pub(crate) search_index: Progress,
+  #[cfg(feature = "ocr")]
+  pub(crate) ocr: Progress,
+}
+
+impl ServicesProgressTracker {
+  pub(crate) fn new() -> Self {
+    Self {
+      notify: Progress::new(),
+      dir_scan: Progress::new(),
+      data_extraction: Progress::new(),
+      search_index: Progress::new(),
+      #[cfg(feature = "ocr")]
+      ocr: Progress::new(),
+    }
+  }
+  pub(crate) fn snapshot(&self) -> ServicesProgress {
+    ServicesProgress {
+      notify: self.notify.snapshot(),
+      dir_scan: self.dir_scan.snapshot(),
+      data_extraction: self.data_extraction.snapshot(),
+      search_index: self.search_index.snapshot(),
+      #[cfg(feature = "ocr")]
+      ocr: self.ocr.snapshot(),
+    }
+  }
+}
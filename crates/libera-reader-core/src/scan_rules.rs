@@ -0,0 +1,149 @@
+use crate::context::Context;
+use crate::models::{LibraryRoot, Settings};
+use gxhash::{HashMap, HashMapExt};
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use ignore::{Match, WalkBuilder};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use tracing::error;
+
+
+/// Per-directory file with gitignore-style patterns, applies to the dir and its subdirs
+pub const IGNORE_FILE_NAME: &str = ".liberaignore";
+
+/// Which files of a library root are skipped by the dir scan and notify services.
+/// The matchers are built once per library root and per dir with an ignore file, so the rules are made
+/// for one scan or one batch of changes and see the ignore files as they were then
+pub(crate) struct ScanRules {
+  ignore_patterns: Vec<String>,
+  skip_hidden: bool,
+  max_depth: Option<usize>,
+  global_ignores: Mutex<HashMap<String, Arc<Gitignore>>>,
+  dir_ignores: Mutex<HashMap<PathBuf, Option<Arc<Gitignore>>>>,
+}
+
+impl ScanRules {
+  pub(crate) fn from_settings(ctx: &Context) -> Self {
+    let settings = Settings::from_db(ctx).unwrap_or_else(|e| {
+      error!("failed to read the scan rules, using the default ones: {e}");
+      Settings::default()
+    });
+    Self {
+      ignore_patterns: settings.ignore_patterns,
+      skip_hidden: settings.skip_hidden,
+      max_depth: settings.max_scan_depth,
+      global_ignores: Mutex::new(HashMap::new()),
+      dir_ignores: Mutex::new(HashMap::new()),
+    }
+  }
+
+  /// Files of the root that aren't excluded by the rules
+  pub(crate) fn walk(&self, library_root: &LibraryRoot) -> Vec<PathBuf> {
+    let global_ignore = self.get_global_ignore(&library_root.path);
+    let mut builder = WalkBuilder::new(&library_root.path);
+    builder
+      .standard_filters(false)
+      .hidden(self.skip_hidden)
+      .max_depth(self.max_depth)
+      .add_custom_ignore_filename(IGNORE_FILE_NAME)
+      .filter_entry(move |entry| {
+        let is_dir = entry.file_type().is_some_and(|file_type| file_type.is_dir());
+        !global_ignore.matched(entry.path(), is_dir).is_ignore()
+      });
+    builder.build()
+      .filter_map(|entry| match entry {
+        Ok(entry) => Some(entry),
+        Err(e) => {
+          error!("{:?}", e);
+          None
+        }
+      })
+      .filter(|entry| entry.file_type().is_some_and(|file_type| file_type.is_file()))
+      .map(|entry| entry.into_path())
+      .collect()
+  }
+
+  /// The same rules as in [`ScanRules::walk`] applied to a single path, used for notify events
+  pub(crate) fn is_ignored(&self, library_root: &LibraryRoot, path: &Path, is_dir: bool) -> bool {
+    let root = Path::new(&library_root.path);
+    let relative_path = match path.strip_prefix(root) {
+      Ok(relative_path) => { relative_path }
+      Err(_) => { return true; }
+    };
+    if self.max_depth.is_some_and(|max_depth| relative_path.components().count() > max_depth) {
+      return true;
+    }
+    if self.skip_hidden && relative_path.components()
+      .any(|component| component.as_os_str().to_string_lossy().starts_with('.')) {
+      return true;
+    }
+    if self.get_global_ignore(&library_root.path).matched_path_or_any_parents(path, is_dir).is_ignore() {
+      return true;
+    }
+    // Deeper ignore files take precedence, as in git
+    let mut dirs = vec![root.to_path_buf()];
+    if let Some(parent) = relative_path.parent() {
+      for component in parent.components() {
+        dirs.push(dirs.last().unwrap().join(component));
+      }
+    }
+    let mut ignored = false;
+    for dir in dirs {
+      if let Some(dir_ignore) = self.get_dir_ignore(dir) {
+        match dir_ignore.matched_path_or_any_parents(path, is_dir) {
+          Match::Ignore(_) => { ignored = true; }
+          Match::Whitelist(_) => { ignored = false; }
+          Match::None => {}
+        }
+      }
+    }
+    ignored
+  }
+
+  fn get_global_ignore(&self, path_to_root: &str) -> Arc<Gitignore> {
+    let mut global_ignores = self.global_ignores.lock().unwrap();
+    match global_ignores.get(path_to_root) {
+      None => {
+        let global_ignore = Arc::new(self.build_global_ignore(path_to_root));
+        global_ignores.insert(path_to_root.to_string(), global_ignore.clone());
+        global_ignore
+      }
+      Some(global_ignore) => { global_ignore.clone() }
+    }
+  }
+
+  /// `None` if the dir has no ignore file or it can't be read
+  fn get_dir_ignore(&self, dir: PathBuf) -> Option<Arc<Gitignore>> {
+    let mut dir_ignores = self.dir_ignores.lock().unwrap();
+    if let Some(dir_ignore) = dir_ignores.get(&dir) {
+      return dir_ignore.clone();
+    }
+    let ignore_file = dir.join(IGNORE_FILE_NAME);
+    let dir_ignore = match ignore_file.is_file() {
+      false => None,
+      true => {
+        let mut builder = GitignoreBuilder::new(&dir);
+        builder.add(&ignore_file);
+        match builder.build() {
+          Ok(dir_ignore) => Some(Arc::new(dir_ignore)),
+          Err(e) => {
+            error!("{}: {:?}", ignore_file.display(), e);
+            None
+          }
+        }
+      }
+    };
+    dir_ignores.insert(dir, dir_ignore.clone());
+    dir_ignore
+  }
+
+  fn build_global_ignore(&self, path_to_root: &str) -> Gitignore {
+    let mut builder = GitignoreBuilder::new(path_to_root);
+    for pattern in &self.ignore_patterns {
+      if let Err(e) = builder.add_line(None, pattern) {
+        error!("invalid ignore pattern {pattern}: {:?}", e);
+      }
+    }
+    builder.build().unwrap_or_else(|_| Gitignore::empty())
+  }
+}
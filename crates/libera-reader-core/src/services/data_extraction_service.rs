@@ -1,48 +1,135 @@
-use crate::models::Book;
-use crate::utils::RayonTaskType::ImgExtract;
-use crate::utils::{get_num_of_threads, NotCachedBook};
-use crate::vars::NOT_CACHED_BOOKS;
+use crate::context::Context;
+use crate::db::crud;
+use crate::error::{CoreError, CoreResult};
+use crate::models::{Book, BookData, CoreEvent};
+use crate::services::lifecycle::ServiceControl;
+use crate::services::search_index_service;
+use crate::types::BookPath;
+use crate::utils::{get_thumbnail_path, NotCachedBook};
+use crate::workers::RayonTaskType::ImgExtract;
+use crate::workers::WorkerPool;
 use gxhash::HashSet;
-use mupdf::document::Document;
+use itertools::Itertools;
+use mupdf::document::{Document, MetadataKey};
 use rayon::prelude::*;
-use rayon::ThreadPoolBuilder;
-use std::thread::sleep;
+use std::path::Path;
 use std::time::Duration;
-use tracing::debug;
+use tracing::{debug, error};
 
 
-pub(crate) fn fill_storage_of_non_cached_books(general_books: HashSet<Book>) {
+pub(crate) fn fill_storage_of_non_cached_books(ctx: &Context, general_books: HashSet<Book>) {
   for i in general_books {
-    if !i.get_book_data().cached {
-      NotCachedBook::new(i.path_to_book).push_to_storage();
+    let book_data = match i.get_book_data(ctx) {
+      Ok(book_data) => { book_data }
+      Err(e) => {
+        error!("failed to read the data of {}: {e}", i.path_to_book);
+        continue;
+      }
+    };
+    if !book_data.cached {
+      NotCachedBook::new(i.path_to_book).push_to_storage(ctx);
+    } else if !crud::search_index::is_indexed(ctx, &i.book_data_pk.as_key()).unwrap_or(true) {
+      search_index_service::push_book_for_indexing(ctx, i.path_to_book);
     }
   }
-  debug!("Number of uncached books: {:?}", &NOT_CACHED_BOOKS.len());
+  debug!("Number of uncached books: {:?}", &ctx.not_cached_books.len());
 }
 
+struct BookMetadata {
+  title: Option<String>,
+  author: Option<String>,
+  subject: Option<String>,
+  keywords: Option<String>,
+  format: Option<String>,
+  page_count: Option<i32>,
+  has_outline: bool,
+}
+
+impl BookMetadata {
+  fn extract(doc: &Document, book_path: &BookPath) -> Self {
+    let lookup = |key: MetadataKey| -> Option<String> {
+      doc.metadata(key).ok().map(|value| value.trim().to_string()).filter(|value| !value.is_empty())
+    };
+    Self {
+      title: lookup(MetadataKey::Title).or_else(|| title_from_file_name(book_path)),
+      author: lookup(MetadataKey::Author),
+      subject: lookup(MetadataKey::Subject),
+      keywords: lookup(MetadataKey::Keywords),
+      format: lookup(MetadataKey::Format),
+      page_count: doc.page_count().ok().map(|page_count| page_count as i32),
+      has_outline: doc.outlines().map_or(false, |outlines| !outlines.is_empty()),
+    }
+  }
+  fn fill(&self, book_data: &mut BookData) {
+    book_data.title = self.title.clone();
+    book_data.author = self.author.clone();
+    book_data.subject = self.subject.clone();
+    book_data.keywords = self.keywords.clone();
+    book_data.format = self.format.clone();
+    book_data.page_count = self.page_count;
+    book_data.has_outline = self.has_outline;
+  }
+}
+
+/// Turns `some_book-name.pdf` into `some book-name`
+fn title_from_file_name(book_path: &BookPath) -> Option<String> {
+  let file_stem = Path::new(book_path).file_stem()?.to_string_lossy();
+  let title = file_stem.replace('_', " ").trim().to_string();
+  if title.is_empty() { None } else { Some(title) }
+}
 
-pub(crate) fn run() {
-  let num_of_threads = get_num_of_threads(ImgExtract);
-  debug!("Number of threads for data extraction service: {:?}", &num_of_threads);
-  ThreadPoolBuilder::new().num_threads(num_of_threads).build().unwrap().install(|| {
-    loop {
-      NOT_CACHED_BOOKS.try_iter().par_bridge().for_each(|not_cached_book| {
-        match Document::open(&not_cached_book.book_path, 20) {
-          Ok(doc) => {
-            let page = doc.load_page(0).unwrap();
-            match page.to_pixmap(0.4) {
-              Ok(mut pixmap) => {
-                let out_file_name = not_cached_book.get_out_file_name();
-                pixmap.save_as_jpeg(70, format!("{}.jpeg", out_file_name));
-                not_cached_book.mark_as_cached();
-              }
-              Err(_err) => {}
-            };
-          }
-          Err(_e) => {}
+pub(crate) fn run(ctx: &Context, control: &ServiceControl) {
+  let mut pool = WorkerPool::new(&ctx.workers, ImgExtract);
+  while control.keep_going() {
+    pool.refresh(&ctx.workers);
+    // A book per thread, so a changed number of threads applies to the next batch
+    let not_cached_books = ctx.not_cached_books.try_iter().take(pool.num_of_threads()).collect_vec();
+    if not_cached_books.is_empty() {
+      ctx.progress.data_extraction.finish_if_done();
+      control.idle(Duration::from_secs(1));
+      continue;
+    }
+    pool.install(|| {
+      not_cached_books.into_par_iter().for_each(|not_cached_book| {
+        let book_path = not_cached_book.book_path.clone();
+        ctx.progress.data_extraction.set_current_file(&book_path);
+        // The book stays uncached and is retried on the next start
+        if let Err(e) = cache_book(ctx, not_cached_book) {
+          error!("failed to extract the data of {book_path}: {e}");
         }
+        ctx.progress.data_extraction.advance(1);
+      });
+    });
+    ctx.workers.throttle(control);
+  }
+  debug!("data extraction service has been stopped");
+}
+
+fn cache_book(ctx: &Context, not_cached_book: NotCachedBook) -> CoreResult<()> {
+  let doc = Document::open(&not_cached_book.book_path, 20).map_err(CoreError::Document)?;
+  let page = doc.load_page(0).map_err(CoreError::Document)?;
+  let mut pixmap = page.to_pixmap(0.4).map_err(CoreError::Document)?;
+  let book_data_pk = not_cached_book.get_book(ctx)?.book_data_pk;
+  let book_data_key = book_data_pk.as_key();
+  let thumbnail_path = get_thumbnail_path(ctx, &book_data_pk);
+  match pixmap.save_as_jpeg(70, thumbnail_path.to_string_lossy().to_string()) {
+    None => {
+      ctx.emit(CoreEvent::ThumbnailReady {
+        book_path: not_cached_book.book_path.clone(),
+        book_data_key: book_data_key.clone(),
       });
-      sleep(Duration::from_secs(1));
     }
-  });
+    Some(e) => { error!("failed to save the thumbnail of {}: {e}", not_cached_book.book_path); }
+  }
+  // A first page without a text layer means the book is most likely a scan
+  #[cfg(feature = "ocr")]
+  if page.get_text().map_or(true, |text| text.trim().is_empty()) {
+    crate::services::ocr_service::push_book_for_ocr(ctx, not_cached_book.book_path.clone());
+  }
+  let book_metadata = BookMetadata::extract(&doc, &not_cached_book.book_path);
+  let book_path = not_cached_book.book_path.clone();
+  not_cached_book.mark_as_cached(ctx, |book_data| book_metadata.fill(book_data))?;
+  ctx.emit(CoreEvent::MetadataExtracted { book_path: book_path.clone(), book_data_key });
+  search_index_service::push_book_for_indexing(ctx, book_path);
+  Ok(())
 }
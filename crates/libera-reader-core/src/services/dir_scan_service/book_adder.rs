@@ -1,50 +1,110 @@
+use crate::context::Context;
 use crate::db::crud;
-use crate::models::{Book, BookDataType, DataOfUnhashedBook};
+use crate::db::crud::book::BookHasher;
+use crate::models::{Book, BookDataType, CoreEvent, DataOfUnhashedBook, ProgressPhase};
+use crate::progress::Progress;
+use crate::services::lifecycle::ServiceControl;
 use crate::types::{BookPath, BookSize};
-use crate::utils::RayonTaskType::HashCalc;
-use crate::utils::{calc_file_size_in_mb, get_num_of_threads};
+use crate::utils::{calc_file_size, path_to_string, NotCachedBook};
+use crate::workers::RayonTaskType::HashCalc;
+use crate::workers::WorkerPool;
 use gxhash::{HashMap, HashMapExt, HashSet};
 use itertools::Itertools;
 use rayon::prelude::*;
-use rayon::ThreadPoolBuilder;
 use std::path::PathBuf;
-use tracing::debug;
+use tracing::{debug, error};
 
 type DBBookCount = usize;
 type BooksGroupedBySize = HashMap<BookSize, (Vec<PathBuf>, DBBookCount)>;
 type BooksForHashing = Vec<(BookSize, Vec<PathBuf>)>;
 
 
-pub(crate) fn run(new_books: HashSet<PathBuf>) {
+/// Adds the books of a unique size with their data in one transaction. The other books are grouped by size,
+/// the books of a size are hashed in parallel, but they aren't added in a batch:
+/// each of them is added in its own transaction, along with the relinking of its copies.
+/// Used by the dir scan and by the notify service for the changes of a batch
+pub(crate) fn run(ctx: &Context, control: &ServiceControl, progress: &Progress, new_books: HashSet<PathBuf>) {
   let start_time = std::time::Instant::now();
   let num_of_new_books = new_books.len();
-  let books_grouped_by_size = get_books_grouped_by_size(new_books);
-  let (unique_books, books_for_hashing) = get_hashed_and_unique_books(books_grouped_by_size);
+  let books_grouped_by_size = get_books_grouped_by_size(ctx, new_books);
+  let (unique_books, books_for_hashing) = get_hashed_and_unique_books(ctx, books_grouped_by_size);
   let num_of_unique_books = unique_books.books.len();
 
   debug!("Number of books for hashing: {:?}", num_of_new_books - num_of_unique_books);
   debug!("Number of books of a unique size: {:?}", num_of_unique_books);
-  crud::insert_batch::<Book>(unique_books.books);
-  crud::insert_batch::<DataOfUnhashedBook>(unique_books.data);
+  if !control.keep_going() {
+    return;
+  }
+  progress.start(ProgressPhase::Adding, num_of_unique_books);
+  let added_books = unique_books.books.iter()
+    .map(|book| (book.path_to_book.clone(), book.book_data_pk.as_key()))
+    .collect_vec();
+  match crud::book::add_books_of_unique_size(ctx, unique_books.books, unique_books.data) {
+    Ok(_) => {
+      for (book_path, book_data_key) in added_books {
+        ctx.emit(CoreEvent::BookAdded { book_path: book_path.clone(), book_data_key });
+        NotCachedBook::new(book_path).push_to_storage(ctx);
+      }
+    }
+    Err(e) => { error!("failed to add books of a unique size: {e}"); }
+  }
+  progress.advance(num_of_unique_books);
   debug!("Time to add unique size books: {:?}", start_time.elapsed());
 
-  let num_of_threads = get_num_of_threads(HashCalc);
-  debug!("Number of threads for hash calculation: {:?}", &num_of_threads);
-  ThreadPoolBuilder::new().num_threads(num_of_threads).build().unwrap().install(|| {
-    for (book_size, books) in books_for_hashing {
-      books.par_iter().for_each(|bookbuf| crud::book::add_book(bookbuf, book_size.clone()));
+  let mut pool = WorkerPool::new(&ctx.workers, HashCalc);
+  let num_of_books_for_hashing = books_for_hashing.iter().map(|(_, books)| books.len()).sum();
+  progress.start(ProgressPhase::Hashing, num_of_books_for_hashing);
+  for (book_size, books) in books_for_hashing {
+    pool.refresh(&ctx.workers);
+    // A failed hash is calculated again when the book is added, the error comes from there
+    let mut book_hashers = pool.install(|| {
+      books.par_iter().map(|bookbuf| {
+        let mut book_hasher = BookHasher::new(bookbuf);
+        if control.keep_going() {
+          if let Ok(book_path) = path_to_string(bookbuf) {
+            progress.set_current_file(&book_path);
+          }
+          let _ = book_hasher.hash_by_settings(ctx, book_size);
+          progress.advance(1);
+          ctx.workers.throttle(control);
+        }
+        book_hasher
+      }).collect::<Vec<_>>()
+    });
+    // The books of a size are added one after another, each of them has to see the records of the ones before
+    for (bookbuf, book_hasher) in books.iter().zip(book_hashers.iter_mut()) {
+      if !control.keep_going() {
+        return;
+      }
+      if let Err(e) = crud::book::add_book_with_hasher(ctx, bookbuf, book_size, book_hasher) {
+        error!("failed to add {:?}: {e}", bookbuf);
+      }
     }
-  });
+  }
 }
 
-fn get_books_grouped_by_size(new_books: HashSet<PathBuf>) -> BooksGroupedBySize {
+fn get_books_grouped_by_size(ctx: &Context, new_books: HashSet<PathBuf>) -> BooksGroupedBySize {
   let mut books_grouped_by_size: BooksGroupedBySize = HashMap::new();
 
   for new_book_path in new_books {
-    let book_size = calc_file_size_in_mb(&new_book_path);
-    let db_book_count = match crud::get_primary::<DataOfUnhashedBook>(book_size.clone()) {
-      None => { 0 }
-      Some(res) => { res.book_data.books_pk.len() }
+    let book_size = match calc_file_size(&new_book_path) {
+      Ok(book_size) => { book_size }
+      Err(e) => {
+        error!("skipping {:?}: {e}", new_book_path);
+        continue;
+      }
+    };
+    // Hashed, missing and recently removed books of this size count too,
+    // so a new copy of them is hashed and takes their data
+    let db_book_count = crud::book::get_num_of_books_of_this_size(ctx, book_size).and_then(|(db_book_count, _)| {
+      Ok(db_book_count + crud::removed_books::get_removed_books_of_this_size(ctx, book_size)?.len())
+    });
+    let db_book_count = match db_book_count {
+      Ok(db_book_count) => { db_book_count }
+      Err(e) => {
+        error!("skipping {:?}: {e}", new_book_path);
+        continue;
+      }
     };
 
     match books_grouped_by_size.get_mut(&book_size) {
@@ -56,18 +116,27 @@ fn get_books_grouped_by_size(new_books: HashSet<PathBuf>) -> BooksGroupedBySize
   }
   books_grouped_by_size
 }
-fn get_hashed_and_unique_books(books_grouped_by_size: BooksGroupedBySize) -> (UniqueBooks, BooksForHashing) {
+fn get_hashed_and_unique_books(ctx: &Context, books_grouped_by_size: BooksGroupedBySize)
+                               -> (UniqueBooks, BooksForHashing) {
   let mut books_for_hashing: BooksForHashing = vec![];
   let mut unique_books = UniqueBooks::new();
   for (book_size, (books_paths, db_book_count)) in books_grouped_by_size {
     let num_books_of_this_size = db_book_count + books_paths.len();
 
     if num_books_of_this_size == 1 {
-      let primary_keys: Vec<BookPath> = books_paths.iter().map(|i| i.to_str().unwrap().to_string()).collect_vec();
-
-      let new_books = books_paths.iter().map(|book_path| {
-        Book::from_pathbuf(book_path, BookDataType::UniqueSize(book_size.clone()))
+      let new_books = books_paths.iter().filter_map(|book_path| {
+        match Book::from_pathbuf(ctx, book_path, BookDataType::UniqueSize(book_size)) {
+          Ok(book) => Some(book),
+          Err(e) => {
+            error!("skipping {:?}: {e}", book_path);
+            None
+          }
+        }
       }).collect_vec();
+      if new_books.is_empty() {
+        continue;
+      }
+      let primary_keys: Vec<BookPath> = new_books.iter().map(|book| book.path_to_book.clone()).collect_vec();
 
       unique_books.books.extend(new_books);
       unique_books.data.push(DataOfUnhashedBook::new(book_size, primary_keys));
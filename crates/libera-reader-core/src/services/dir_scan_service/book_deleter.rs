@@ -1,12 +1,23 @@
+use crate::context::Context;
 use crate::db::crud;
-use crate::models::Book;
+use crate::models::{Book, ProgressPhase};
+use crate::services::lifecycle::ServiceControl;
 use measure_time_macro::measure_time;
-use tracing::debug;
+use tracing::{debug, error};
 
 
 #[measure_time]
-pub(crate) fn del_outdated_books(outdated_books: Vec<Book>) {
+pub(crate) fn del_outdated_books(ctx: &Context, control: &ServiceControl, outdated_books: Vec<Book>) {
+  ctx.progress.dir_scan.start(ProgressPhase::Deleting, outdated_books.len());
   for outdated_book in outdated_books {
-    crud::book::del_book_and_its_data(outdated_book);
+    if !control.keep_going() {
+      break;
+    }
+    let book_path = outdated_book.path_to_book.clone();
+    ctx.progress.dir_scan.set_current_file(&book_path);
+    if let Err(e) = crud::book::del_book_and_its_data(ctx, outdated_book) {
+      error!("dir scan: failed to delete {book_path}: {e}");
+    }
+    ctx.progress.dir_scan.advance(1);
   }
 }
\ No newline at end of file
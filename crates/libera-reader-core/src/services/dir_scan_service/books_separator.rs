@@ -1,10 +1,13 @@
+use crate::context::Context;
 use crate::db::crud;
-use crate::models::Book;
+use crate::error::CoreResult;
+use crate::models::{Book, LibraryRoot};
+use crate::scan_rules::ScanRules;
 use crate::types::BookPath;
-use crate::utils::get_books_from_disk;
+use crate::utils::{get_books_from_disk, path_to_string};
 use gxhash::{HashMap, HashSet};
 use std::path::PathBuf;
-use tracing::debug;
+use tracing::{debug, error};
 
 
 pub(crate) struct BookSeparator {
@@ -16,10 +19,19 @@ pub(crate) struct BookSeparator {
 }
 
 impl BookSeparator {
-  pub(crate) fn new(path_to_scan: &BookPath) -> Self {
-    let mut books_on_disk: HashMap<BookPath, PathBuf> = get_books_from_disk(path_to_scan)
-      .into_iter().map(|i| (i.to_str().unwrap().to_string(), i)).collect();
-    let mut books_in_db: HashMap<BookPath, Book> = crud::book::get_all_from_db().into_iter()
+  pub(crate) fn new(ctx: &Context, library_roots: &[LibraryRoot]) -> CoreResult<Self> {
+    // Nested roots yield the same books twice, the map keeps one of them
+    let scan_rules = ScanRules::from_settings(ctx);
+    let mut books_on_disk: HashMap<BookPath, PathBuf> = library_roots.iter()
+      .flat_map(|library_root| get_books_from_disk(ctx, library_root, &scan_rules))
+      .filter_map(|i| match path_to_string(&i) {
+        Ok(book_path) => Some((book_path, i)),
+        Err(e) => {
+          error!("dir scan: skipping the book: {e}");
+          None
+        }
+      }).collect();
+    let mut books_in_db: HashMap<BookPath, Book> = crud::book::get_all_from_db(ctx)?.into_iter()
       .map(|i| (i.path_to_book.clone(), i)).collect();
 
     let books_paths_on_disk: HashSet<BookPath> = books_on_disk.keys().cloned().collect();
@@ -40,12 +52,12 @@ impl BookSeparator {
     debug!("Number of new books: {:?}", new_books.len());
     debug!("Number of general_books: {:?}", general_books.len());
     debug!("Number of outdated books: {:?}", outdated_books.len());
-    Self {
+    Ok(Self {
       new_books,
       general_books,
       outdated_books,
       num_of_books_on_disk,
       num_of_books_in_db,
-    }
+    })
   }
 }
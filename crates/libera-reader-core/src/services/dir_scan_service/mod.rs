@@ -1,11 +1,14 @@
+use crate::context::Context;
+use crate::db::crud;
 use crate::services::data_extraction_service;
-use crate::types::BookPath;
+use crate::services::lifecycle::ServiceControl;
+use crate::models::{LibraryRoot, ProgressPhase};
 use books_separator::BookSeparator;
-use tracing::info;
+use tracing::{error, info};
 
 
 mod book_deleter;
-mod book_adder;
+pub(crate) mod book_adder;
 mod books_separator;
 
 enum BooksLocation {
@@ -15,23 +18,38 @@ enum BooksLocation {
   None,
 }
 
-pub(crate) fn run(path_to_scan: BookPath) {
-  let book_separator = BookSeparator::new(&path_to_scan);
-  data_extraction_service::fill_storage_of_non_cached_books(book_separator.general_books);
+/// Syncs the db with the books of all enabled library roots,
+/// books of removed or disabled roots are treated as deleted.
+/// A stopped scan leaves the rest of the changes to the next one
+pub(crate) fn run(ctx: &Context, control: &ServiceControl, library_roots: Vec<LibraryRoot>) {
+  ctx.progress.dir_scan.start(ProgressPhase::Scanning, 0);
+  if let Err(e) = crud::removed_books::remove_expired(ctx) {
+    error!("dir scan: failed to forget the books removed long ago: {e}");
+  }
+  let book_separator = match BookSeparator::new(ctx, &library_roots) {
+    Ok(book_separator) => { book_separator }
+    Err(e) => {
+      error!("dir scan: failed to compare the library with the db: {e}");
+      ctx.progress.dir_scan.finish();
+      return;
+    }
+  };
+  data_extraction_service::fill_storage_of_non_cached_books(ctx, book_separator.general_books);
   let start_time = std::time::Instant::now();
   match get_books_location(book_separator.num_of_books_in_db, book_separator.num_of_books_on_disk) {
     BooksLocation::Disk => {
-      book_adder::run(book_separator.new_books);
+      book_adder::run(ctx, control, &ctx.progress.dir_scan, book_separator.new_books);
     }
     BooksLocation::DB => {
-      book_deleter::del_outdated_books(book_separator.outdated_books);
+      book_deleter::del_outdated_books(ctx, control, book_separator.outdated_books);
     }
     BooksLocation::DiskAndDB => {
-      book_deleter::del_outdated_books(book_separator.outdated_books);
-      book_adder::run(book_separator.new_books);
+      book_deleter::del_outdated_books(ctx, control, book_separator.outdated_books);
+      book_adder::run(ctx, control, &ctx.progress.dir_scan, book_separator.new_books);
     }
     BooksLocation::None => {}
   };
+  ctx.progress.dir_scan.finish();
   info!("Dir scan service execution time is: {:?}", start_time.elapsed());
 }
 
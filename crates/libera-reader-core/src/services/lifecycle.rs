@@ -0,0 +1,99 @@
+use crate::services::ServiceStatus;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::Duration;
+use tracing::error;
+
+
+/// Status requested for a running service, checked by its thread between items
+pub(crate) struct ServiceControl {
+  status: Mutex<ServiceStatus>,
+  status_changed: Condvar,
+}
+
+impl ServiceControl {
+  pub(crate) fn new() -> Self {
+    Self { status: Mutex::new(ServiceStatus::Working), status_changed: Condvar::new() }
+  }
+  fn status(&self) -> ServiceStatus {
+    *self.status.lock().unwrap()
+  }
+  /// Whether the service is neither paused nor stopped, unlike [`keep_going`](Self::keep_going) it doesn't block
+  pub(crate) fn is_working(&self) -> bool {
+    self.status() == ServiceStatus::Working
+  }
+  /// Changes the status if it is `from`, a stopped service stays stopped
+  fn change_status(&self, from: ServiceStatus, to: ServiceStatus) {
+    let mut status = self.status.lock().unwrap();
+    if *status == from {
+      *status = to;
+      self.status_changed.notify_all();
+    }
+  }
+  fn stop(&self) {
+    *self.status.lock().unwrap() = ServiceStatus::NotWorking;
+    self.status_changed.notify_all();
+  }
+  /// Blocks while the service is paused, returns `false` once it has to stop
+  pub(crate) fn keep_going(&self) -> bool {
+    let mut status = self.status.lock().unwrap();
+    while *status == ServiceStatus::Paused {
+      status = self.status_changed.wait(status).unwrap();
+    }
+    *status == ServiceStatus::Working
+  }
+  /// Sleeps when there is nothing to do, a pause or a stop wakes the service up earlier
+  pub(crate) fn idle(&self, timeout: Duration) {
+    let status = self.status.lock().unwrap();
+    let _ = self.status_changed.wait_timeout_while(status, timeout, |status| *status == ServiceStatus::Working)
+      .unwrap();
+  }
+}
+
+/// Thread of a service together with the control shared with it
+pub(crate) struct ServiceHandle {
+  control: Arc<ServiceControl>,
+  thread: Option<JoinHandle<()>>,
+}
+
+impl ServiceHandle {
+  pub(crate) fn new() -> Self {
+    Self { control: Arc::new(ServiceControl::new()), thread: None }
+  }
+  pub(crate) fn status(&self) -> ServiceStatus {
+    match &self.thread {
+      // A finished thread means the service is done, e.g. a dir scan that has synced the library
+      Some(thread) if !thread.is_finished() => self.control.status(),
+      _ => ServiceStatus::NotWorking,
+    }
+  }
+  /// Spawns the service, does nothing if it is already working or paused
+  pub(crate) fn start(&mut self, run: impl FnOnce(&ServiceControl) + Send + 'static) {
+    if self.status() != ServiceStatus::NotWorking {
+      return;
+    }
+    self.join();
+    self.control = Arc::new(ServiceControl::new());
+    let control = self.control.clone();
+    self.thread = Some(thread::spawn(move || run(&control)));
+  }
+  pub(crate) fn pause(&self) {
+    self.control.change_status(ServiceStatus::Working, ServiceStatus::Paused);
+  }
+  pub(crate) fn resume(&self) {
+    self.control.change_status(ServiceStatus::Paused, ServiceStatus::Working);
+  }
+  /// Asks the service to stop and waits until it finishes the item it is busy with
+  pub(crate) fn stop(&mut self) {
+    self.control.stop();
+    self.join();
+  }
+  pub(crate) fn join(&mut self) {
+    if let Some(thread) = self.thread.take() {
+      if thread.join().is_err() {
+        error!("service thread has panicked");
+      }
+    }
+  }
+}
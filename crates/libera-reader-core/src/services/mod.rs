@@ -1,105 +1,171 @@
-use crate::vars::{PATH_TO_SCAN, SHUTDOWN};
-use std::ops::Deref;
-use std::sync::atomic::Ordering;
-use std::thread;
+use crate::context::Context;
+use crate::models::ServicesProgress;
+use crate::services::lifecycle::ServiceHandle;
+use crate::utils::get_enabled_library_roots;
+use std::sync::Arc;
 
 
 mod dir_scan_service;
 mod data_extraction_service;
+pub(crate) mod lifecycle;
 pub(crate) mod notify_service;
+#[cfg(feature = "ocr")]
+pub(crate) mod ocr_service;
+pub(crate) mod search_index_service;
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ServiceStatus {
   Working,
+  Paused,
   NotWorking,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Service {
+  Notify,
+  DirScan,
+  DataExtraction,
+  SearchIndex,
+  #[cfg(feature = "ocr")]
+  Ocr,
+}
+
 pub struct Services {
-  notify_working_status: ServiceStatus,
-  data_extraction_service_working_status: ServiceStatus,
+  ctx: Arc<Context>,
+  notify: ServiceHandle,
+  dir_scan: ServiceHandle,
+  data_extraction: ServiceHandle,
+  search_index: ServiceHandle,
+  #[cfg(feature = "ocr")]
+  ocr: ServiceHandle,
 }
 
 impl Services {
-  pub(crate) fn new() -> Self {
+  pub(crate) fn new(ctx: Arc<Context>) -> Self {
     Self {
-      notify_working_status: ServiceStatus::NotWorking,
-      data_extraction_service_working_status: ServiceStatus::NotWorking,
+      ctx,
+      notify: ServiceHandle::new(),
+      dir_scan: ServiceHandle::new(),
+      data_extraction: ServiceHandle::new(),
+      search_index: ServiceHandle::new(),
+      #[cfg(feature = "ocr")]
+      ocr: ServiceHandle::new(),
     }
   }
+  /// Starts every service if the library has enabled roots
   pub fn run(&mut self) {
-    match PATH_TO_SCAN.read().unwrap().deref().clone() {
-      None => {}
-      Some(path_to_scan) => {
-        match self.notify_working_status {
-          ServiceStatus::NotWorking => {
-            notify_service::run_watcher(&path_to_scan);
-            thread::spawn(|| { notify_service::run() });
-            self.notify_working_status = ServiceStatus::Working;
-          }
-          _ => {}
-        }
-        thread::spawn(|| { dir_scan_service::run(path_to_scan) });
-        self.run_data_extraction();
+    match get_enabled_library_roots(&self.ctx).is_empty() {
+      true => {}
+      false => {
+        self.start(Service::Notify);
+        self.start(Service::DirScan);
+        self.start(Service::DataExtraction);
+        self.start(Service::SearchIndex);
+        #[cfg(feature = "ocr")]
+        self.start(Service::Ocr);
       }
     }
   }
-
-  pub fn run_notify(&mut self) {
-    match self.notify_working_status {
-      ServiceStatus::NotWorking => {
-        match PATH_TO_SCAN.read().unwrap().deref() {
-          None => {}
-          Some(path_to_scan) => {
-            notify_service::run_watcher(path_to_scan);
-            thread::spawn(|| { notify_service::run() });
-          }
+  pub fn status(&self, service: Service) -> ServiceStatus {
+    self.handle(service).status()
+  }
+  /// Does nothing if the service is already working or paused,
+  /// the dir scan isn't started if there are no enabled library roots
+  pub fn start(&mut self, service: Service) {
+    let ctx = self.ctx.clone();
+    match service {
+      Service::Notify => {
+        self.notify.start(move |control| notify_service::run(&ctx, control));
+      }
+      Service::DirScan => {
+        let library_roots = get_enabled_library_roots(&ctx);
+        if !library_roots.is_empty() {
+          self.dir_scan.start(move |control| dir_scan_service::run(&ctx, control, library_roots));
         }
-        self.notify_working_status = ServiceStatus::Working;
       }
-      _ => {}
-    }
-  }
-  pub fn stop_notify(&mut self) {
-    match self.notify_working_status {
-      ServiceStatus::NotWorking => {
-        match PATH_TO_SCAN.read().unwrap().deref() {
-          None => {}
-          Some(path_to_scan) => notify_service::stop_watcher(path_to_scan)
-        };
-        self.notify_working_status = ServiceStatus::Working;
+      Service::DataExtraction => {
+        self.data_extraction.start(move |control| data_extraction_service::run(&ctx, control));
+      }
+      Service::SearchIndex => {
+        self.search_index.start(move |control| search_index_service::run(&ctx, control));
+      }
+      #[cfg(feature = "ocr")]
+      Service::Ocr => {
+        self.ocr.start(move |control| ocr_service::run(&ctx, control));
       }
-      _ => {}
     }
   }
+  /// The service finishes the item it is busy with and waits for [`Services::resume`]
+  pub fn pause(&self, service: Service) {
+    self.handle(service).pause();
+  }
+  pub fn resume(&self, service: Service) {
+    self.handle(service).resume();
+  }
+  /// Waits until the service finishes the item it is busy with, its db writes included
+  pub fn stop(&mut self, service: Service) {
+    self.handle_mut(service).stop();
+  }
+  /// Progress of every service, cheap enough to be polled by the ui
+  pub fn progress(&self) -> ServicesProgress {
+    self.ctx.progress.snapshot()
+  }
 
+  pub fn run_notify(&mut self) {
+    self.start(Service::Notify);
+  }
+  pub fn stop_notify(&mut self) {
+    self.stop(Service::Notify);
+  }
+  /// Syncs the db with the library roots, a blocking call waits until the scan is done
   pub fn launch_dir_scan_service(&mut self, is_blocking: bool) {
-    match PATH_TO_SCAN.read().unwrap().deref().clone() {
-      None => {}
-      Some(path_to_scan) => {
-        match is_blocking {
-          true => { dir_scan_service::run(path_to_scan); }
-          false => { thread::spawn(|| { dir_scan_service::run(path_to_scan) }); }
-        }
-      }
+    self.start(Service::DirScan);
+    if is_blocking {
+      self.dir_scan.join();
     }
   }
   pub fn run_data_extraction(&mut self) {
-    match self.data_extraction_service_working_status {
-      ServiceStatus::NotWorking => {
-        thread::spawn(|| { data_extraction_service::run() });
-        self.data_extraction_service_working_status = ServiceStatus::Working;
-      }
-      _ => {}
-    }
+    self.start(Service::DataExtraction);
   }
+  pub fn run_search_indexer(&mut self) {
+    self.start(Service::SearchIndex);
+  }
+  #[cfg(feature = "ocr")]
+  pub fn run_ocr(&mut self) {
+    self.start(Service::Ocr);
+  }
+  /// Stops every service and waits for their threads
   pub fn stop_all_services(&mut self) {
-    self.stop_notify();
-    SHUTDOWN.swap(true, Ordering::Relaxed);
+    self.stop(Service::Notify);
+    self.stop(Service::DirScan);
+    self.stop(Service::DataExtraction);
+    self.stop(Service::SearchIndex);
+    #[cfg(feature = "ocr")]
+    self.stop(Service::Ocr);
   }
-}
 
-impl Default for Services {
-  fn default() -> Self { Self::new() }
+  fn handle(&self, service: Service) -> &ServiceHandle {
+    match service {
+      Service::Notify => &self.notify,
+      Service::DirScan => &self.dir_scan,
+      Service::DataExtraction => &self.data_extraction,
+      Service::SearchIndex => &self.search_index,
+      #[cfg(feature = "ocr")]
+      Service::Ocr => &self.ocr,
+    }
+  }
+  fn handle_mut(&mut self, service: Service) -> &mut ServiceHandle {
+    match service {
+      Service::Notify => &mut self.notify,
+      Service::DirScan => &mut self.dir_scan,
+      Service::DataExtraction => &mut self.data_extraction,
+      Service::SearchIndex => &mut self.search_index,
+      #[cfg(feature = "ocr")]
+      Service::Ocr => &mut self.ocr,
+    }
+  }
 }
+
 impl Drop for Services {
   fn drop(&mut self) {
     self.stop_all_services();
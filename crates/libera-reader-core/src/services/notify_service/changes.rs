@@ -0,0 +1,188 @@
+use gxhash::{HashMap, HashMapExt};
+use itertools::Itertools;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+
+/// What has happened to a path since its changes were applied last time
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(super) enum PathChange {
+  /// A book or a dir has appeared, e.g. it is being copied into the library
+  Added,
+  /// The content of the book has been rewritten in place
+  Modified,
+  Removed,
+  /// The book or dir has been moved here, `modified` if it was also rewritten
+  Renamed { old_path: PathBuf, modified: bool },
+}
+
+/// Changes coalesced by path, a change is applied once no event has come for its path for the settle time
+pub(super) struct PendingChanges {
+  settle_time: Duration,
+  changes: HashMap<PathBuf, (PathChange, Instant)>,
+}
+
+impl PendingChanges {
+  pub(super) fn new(settle_time: Duration) -> Self {
+    Self { settle_time, changes: HashMap::new() }
+  }
+  pub(super) fn add(&mut self, path: PathBuf, change: PathChange) {
+    let coalesced = match self.changes.remove(&path) {
+      None => Some(change),
+      Some((pending, _)) => {
+        match (pending, change) {
+          (PathChange::Added, PathChange::Removed) => None,
+          (PathChange::Added, _) => Some(PathChange::Added),
+          // Deleted and created again, e.g. saved by an editor that replaces the file
+          (PathChange::Removed, PathChange::Removed) => Some(PathChange::Removed),
+          (PathChange::Removed, _) => Some(PathChange::Modified),
+          (PathChange::Modified, PathChange::Removed) => Some(PathChange::Removed),
+          (PathChange::Modified, _) => Some(PathChange::Modified),
+          // The moved book is gone, so is the one at its old path
+          (PathChange::Renamed { old_path, .. }, PathChange::Removed) => {
+            self.add(old_path, PathChange::Removed);
+            None
+          }
+          (PathChange::Renamed { old_path, .. }, _) => Some(PathChange::Renamed { old_path, modified: true }),
+        }
+      }
+    };
+    if let Some(change) = coalesced {
+      self.changes.insert(path, (change, Instant::now()));
+    }
+  }
+  /// The pending changes of the old path and of everything inside it move to the new path
+  pub(super) fn rename(&mut self, old_path: PathBuf, new_path: PathBuf) {
+    let nested_paths = self.changes.keys()
+      .filter(|path| **path != old_path && path.starts_with(&old_path))
+      .cloned()
+      .collect_vec();
+    for nested_path in nested_paths {
+      let pending = self.changes.remove(&nested_path);
+      if let (Ok(relative_path), Some(pending)) = (nested_path.strip_prefix(&old_path), pending) {
+        self.changes.insert(new_path.join(relative_path), pending);
+      }
+    }
+    let change = match self.changes.remove(&old_path).map(|(pending, _)| pending) {
+      Some(PathChange::Added) => Some(PathChange::Added),
+      Some(PathChange::Modified) => Some(PathChange::Renamed { old_path, modified: true }),
+      // Moved back to where it was
+      Some(PathChange::Renamed { old_path: first_path, modified }) if first_path == new_path => {
+        modified.then_some(PathChange::Modified)
+      }
+      Some(PathChange::Renamed { old_path: first_path, modified }) => {
+        Some(PathChange::Renamed { old_path: first_path, modified })
+      }
+      Some(PathChange::Removed) | None => Some(PathChange::Renamed { old_path, modified: false }),
+    };
+    // A rename over an existing path replaces whatever was pending there
+    match change {
+      None => { self.changes.remove(&new_path); }
+      Some(change) => { self.changes.insert(new_path, (change, Instant::now())); }
+    }
+  }
+  /// Time until the next change settles
+  pub(super) fn time_to_settle(&self) -> Option<Duration> {
+    self.changes.values()
+      .map(|(_, last_event_at)| self.settle_time.saturating_sub(last_event_at.elapsed()))
+      .min()
+  }
+  pub(super) fn take_all(&mut self) -> Vec<(PathBuf, PathChange)> {
+    self.changes.drain().map(|(path, (change, _))| (path, change)).collect()
+  }
+  pub(super) fn take_settled(&mut self) -> Vec<(PathBuf, PathChange)> {
+    let settled_paths = self.changes.iter()
+      .filter(|(_, (_, last_event_at))| last_event_at.elapsed() >= self.settle_time)
+      .map(|(path, _)| path.clone())
+      .collect_vec();
+    settled_paths.into_iter()
+      .filter_map(|path| self.changes.remove(&path).map(|(change, _)| (path, change)))
+      .collect()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn settled(pending_changes: &mut PendingChanges) -> Vec<(PathBuf, PathChange)> {
+    pending_changes.take_settled().into_iter().sorted_by(|(a, _), (b, _)| a.cmp(b)).collect()
+  }
+
+  fn renamed(old_path: &str, modified: bool) -> PathChange {
+    PathChange::Renamed { old_path: PathBuf::from(old_path), modified }
+  }
+
+  #[test]
+  fn coalesces_the_changes_of_a_path() {
+    let cases = [
+      (PathChange::Added, PathChange::Modified, Some(PathChange::Added)),
+      (PathChange::Added, PathChange::Removed, None),
+      (PathChange::Removed, PathChange::Added, Some(PathChange::Modified)),
+      (PathChange::Removed, PathChange::Removed, Some(PathChange::Removed)),
+      (PathChange::Modified, PathChange::Added, Some(PathChange::Modified)),
+      (PathChange::Modified, PathChange::Removed, Some(PathChange::Removed)),
+      (renamed("/lib/a.pdf", false), PathChange::Modified, Some(renamed("/lib/a.pdf", true))),
+    ];
+    for (first, second, expected) in cases {
+      let mut pending_changes = PendingChanges::new(Duration::ZERO);
+      pending_changes.add(PathBuf::from("/lib/b.pdf"), first.clone());
+      pending_changes.add(PathBuf::from("/lib/b.pdf"), second.clone());
+      let expected = expected.map(|change| (PathBuf::from("/lib/b.pdf"), change)).into_iter().collect_vec();
+      assert_eq!(settled(&mut pending_changes), expected, "{first:?} then {second:?}");
+    }
+  }
+
+  #[test]
+  fn removal_of_a_moved_book_removes_its_old_path() {
+    let mut pending_changes = PendingChanges::new(Duration::ZERO);
+    pending_changes.rename(PathBuf::from("/lib/a.pdf"), PathBuf::from("/lib/b.pdf"));
+    pending_changes.add(PathBuf::from("/lib/b.pdf"), PathChange::Removed);
+    assert_eq!(settled(&mut pending_changes), vec![(PathBuf::from("/lib/a.pdf"), PathChange::Removed)]);
+  }
+
+  #[test]
+  fn coalesces_renames() {
+    let mut pending_changes = PendingChanges::new(Duration::ZERO);
+    // A new book is moved before it settles
+    pending_changes.add(PathBuf::from("/lib/a.pdf"), PathChange::Added);
+    pending_changes.rename(PathBuf::from("/lib/a.pdf"), PathBuf::from("/lib/b.pdf"));
+    // A modified book is moved
+    pending_changes.add(PathBuf::from("/lib/c.pdf"), PathChange::Modified);
+    pending_changes.rename(PathBuf::from("/lib/c.pdf"), PathBuf::from("/lib/d.pdf"));
+    // Moved twice
+    pending_changes.rename(PathBuf::from("/lib/e.pdf"), PathBuf::from("/lib/f.pdf"));
+    pending_changes.rename(PathBuf::from("/lib/f.pdf"), PathBuf::from("/lib/g.pdf"));
+    // Moved back to where it was
+    pending_changes.rename(PathBuf::from("/lib/h.pdf"), PathBuf::from("/lib/i.pdf"));
+    pending_changes.rename(PathBuf::from("/lib/i.pdf"), PathBuf::from("/lib/h.pdf"));
+    assert_eq!(settled(&mut pending_changes), vec![
+      (PathBuf::from("/lib/b.pdf"), PathChange::Added),
+      (PathBuf::from("/lib/d.pdf"), renamed("/lib/c.pdf", true)),
+      (PathBuf::from("/lib/g.pdf"), renamed("/lib/e.pdf", false)),
+    ]);
+  }
+
+  #[test]
+  fn rename_of_a_dir_moves_the_changes_inside_it() {
+    let mut pending_changes = PendingChanges::new(Duration::ZERO);
+    pending_changes.add(PathBuf::from("/lib/old/a.pdf"), PathChange::Added);
+    pending_changes.add(PathBuf::from("/lib/old/sub/b.pdf"), PathChange::Modified);
+    pending_changes.add(PathBuf::from("/lib/new/c.pdf"), PathChange::Added);
+    pending_changes.rename(PathBuf::from("/lib/old"), PathBuf::from("/lib/new"));
+    assert_eq!(settled(&mut pending_changes), vec![
+      (PathBuf::from("/lib/new"), renamed("/lib/old", false)),
+      (PathBuf::from("/lib/new/a.pdf"), PathChange::Added),
+      (PathBuf::from("/lib/new/c.pdf"), PathChange::Added),
+      (PathBuf::from("/lib/new/sub/b.pdf"), PathChange::Modified),
+    ]);
+  }
+
+  #[test]
+  fn changes_wait_for_the_settle_time() {
+    let mut pending_changes = PendingChanges::new(Duration::from_secs(60));
+    pending_changes.add(PathBuf::from("/lib/a.pdf"), PathChange::Added);
+    assert!(pending_changes.take_settled().is_empty());
+    assert!(pending_changes.time_to_settle().is_some_and(|time_to_settle| time_to_settle > Duration::from_secs(59)));
+  }
+}
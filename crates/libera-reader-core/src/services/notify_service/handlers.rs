@@ -1,52 +1,195 @@
+use crate::context::Context;
 use crate::db::crud;
+use crate::error::CoreResult;
 use crate::models::Book;
-use crate::utils::calc_file_size_in_mb;
-use crate::vars::TARGET_EXT;
+use crate::scan_rules::ScanRules;
+use crate::services::dir_scan_service::book_adder;
+use crate::services::lifecycle::ServiceControl;
+use crate::types::BookPath;
+use crate::utils::{calc_file_size, find_library_root, path_to_string};
+use gxhash::{HashSet, HashSetExt};
+use ignore::WalkBuilder;
+use itertools::Itertools;
 use measure_time_macro::measure_time;
-use std::path::PathBuf;
-use std::time::Duration;
+use std::path::{Path, PathBuf};
 use tracing::{debug, error};
 
 
-#[measure_time]
-pub(crate) fn book_adding_handler(bookbuf: &PathBuf) -> Duration {
-  let start_time = std::time::Instant::now();
-  let ext = bookbuf.extension().unwrap().to_str().unwrap().to_string();
-  if TARGET_EXT.read().unwrap().contains(&ext) {
-    let book_size = calc_file_size_in_mb(bookbuf);
-    crud::book::add_book(bookbuf, book_size);
+/// Whether the path is inside a library root and isn't excluded by its format filter or the ignore rules
+fn is_tracked_by(ctx: &Context, scan_rules: &ScanRules, path: &Path, is_dir: bool) -> bool {
+  match find_library_root(ctx, path) {
+    None => false,
+    Some(library_root) => {
+      let ext_is_accepted = is_dir || path.extension()
+        .is_some_and(|ext| library_root.accepts_ext(&ext.to_string_lossy(), &ctx.target_ext.read().unwrap()));
+      ext_is_accepted && !scan_rules.is_ignored(&library_root, path, is_dir)
+    }
+  }
+}
+/// A tracked file that isn't in the db yet. A missing book at this path is taken back by `add_book`
+fn is_new_book(ctx: &Context, scan_rules: &ScanRules, bookbuf: &Path) -> bool {
+  if !bookbuf.is_file() || !is_tracked_by(ctx, scan_rules, bookbuf, false) {
+    return false;
+  }
+  match path_to_string(bookbuf).and_then(|book_path| crud::get_primary::<Book>(ctx, book_path)) {
+    Ok(book) => !book.is_some_and(|book| book.path_is_valid),
+    Err(e) => {
+      error!("failed to look up {}: {e}", bookbuf.display());
+      false
+    }
   }
-  let total_time = start_time.elapsed();
-  debug!("Function book_adding_handler executed in: {:?}", &total_time);
-  total_time
 }
 
 #[measure_time]
-pub(crate) fn book_deletion_handler(path_to_book: &str) {
-  match crud::get_primary::<Book>(path_to_book) {
-    None => { debug!("book_deletion_handler: book not found: {path_to_book}") }
-    Some(old_book) => { crud::book::del_book_and_its_data(old_book); }
-  };
+pub(crate) fn book_adding_handler(ctx: &Context, bookbuf: &PathBuf) -> CoreResult<()> {
+  if !is_new_book(ctx, &ScanRules::from_settings(ctx), bookbuf) {
+    debug!("book_adding_handler: not a new book: {:?}", bookbuf);
+    return Ok(());
+  }
+  let book_size = calc_file_size(bookbuf)?;
+  crud::book::add_book(ctx, bookbuf, book_size)
 }
 
+/// Adds the books and the dirs that have appeared in one go, the same way the dir scan does.
+/// A dir moved in from outside the library comes without events for its books, so it is walked
 #[measure_time]
-pub(crate) fn book_path_update_handler(old_path: &PathBuf, new_path: &PathBuf) {
-  match crud::get_primary::<Book>(old_path.to_str().unwrap()) {
-    None => {
-      error!("book_path_update_handler: book not found: {:?}", old_path);
-      book_adding_handler(new_path);
+pub(crate) fn books_adding_handler(ctx: &Context, control: &ServiceControl, added_paths: Vec<PathBuf>) {
+  let scan_rules = ScanRules::from_settings(ctx);
+  let mut new_books = HashSet::new();
+  for path in added_paths {
+    match path.is_dir() {
+      true if is_tracked_by(ctx, &scan_rules, &path, true) => {
+        let books = WalkBuilder::new(&path).standard_filters(false).build()
+          .filter_map(|entry| entry.ok())
+          .map(|entry| entry.into_path())
+          .filter(|bookbuf| is_new_book(ctx, &scan_rules, bookbuf));
+        new_books.extend(books);
+      }
+      true => {}
+      false => {
+        if is_new_book(ctx, &scan_rules, &path) {
+          new_books.insert(path);
+        }
+      }
     }
-    Some(book_from_db) => {
-      let new_book = Book::from_pathbuf(&new_path, book_from_db.book_data_pk.clone());
-      crud::update(book_from_db, new_book).unwrap();
+  }
+  if !new_books.is_empty() {
+    book_adder::run(ctx, control, &ctx.progress.notify, new_books);
+    ctx.progress.notify.finish();
+  }
+}
+
+/// The book was rewritten in place, it gets a new size and hash if its content has changed
+#[measure_time]
+pub(crate) fn book_modification_handler(ctx: &Context, bookbuf: &PathBuf) -> CoreResult<()> {
+  if !bookbuf.is_file() {
+    return Ok(());
+  }
+  match crud::get_primary::<Book>(ctx, path_to_string(bookbuf)?)? {
+    None => book_adding_handler(ctx, bookbuf),
+    Some(book) => crud::book::reidentify_book(ctx, book, bookbuf),
+  }
+}
+
+/// The book at the path or the books of the dir, the events of removals and renames don't tell them apart
+fn get_books_at(ctx: &Context, path: &Path) -> CoreResult<Vec<Book>> {
+  let path = path_to_string(path)?;
+  match crud::get_primary::<Book>(ctx, path.clone())? {
+    None => crud::book::get_books_located_in_dir(ctx, path),
+    Some(book) => Ok(vec![book]),
+  }
+}
+
+/// Deletes the books of the removed books and dirs in one transaction
+#[measure_time]
+pub(crate) fn paths_removal_handler(ctx: &Context, paths: Vec<PathBuf>) {
+  let mut books = vec![];
+  for path in paths {
+    match get_books_at(ctx, &path) {
+      Ok(books_at_path) => { books.extend(books_at_path); }
+      Err(e) => { error!("failed to look up {}: {e}", path.display()); }
     }
   }
+  books_deletion_handler(ctx, books);
 }
 
+/// Moves the books of the renamed books and dirs in one transaction. The books renamed out of the library
+/// or over other books are deleted. Returns the new paths unknown to the db, they are added as new books
 #[measure_time]
-pub(crate) fn dir_deletion_handler(path_to_dir: String) {
-  for old_book in crud::book::get_books_located_in_dir(path_to_dir) {
-    book_deletion_handler(old_book.path_to_book.as_str());
+pub(crate) fn paths_renaming_handler(ctx: &Context, renamed_paths: &[(PathBuf, PathBuf)]) -> Vec<PathBuf> {
+  let scan_rules = ScanRules::from_settings(ctx);
+  let mut moved_books = vec![];
+  let mut deleted_books = vec![];
+  let mut added_paths = vec![];
+  for (old_path, new_path) in renamed_paths {
+    let books = match get_books_at(ctx, old_path) {
+      Ok(books) => books,
+      Err(e) => {
+        error!("failed to look up {}: {e}", old_path.display());
+        continue;
+      }
+    };
+    // Renaming into an ignored dir or to another extension takes the books out of the library
+    if !is_tracked_by(ctx, &scan_rules, new_path, new_path.is_dir()) {
+      deleted_books.extend(books);
+    } else if books.is_empty() {
+      added_paths.push(new_path.clone());
+    } else {
+      for book in books {
+        let new_book_path = match Path::new(&book.path_to_book).strip_prefix(old_path) {
+          Ok(relative_path) if relative_path.as_os_str().is_empty() => new_path.clone(),
+          Ok(relative_path) => new_path.join(relative_path),
+          Err(_) => { continue; }
+        };
+        moved_books.push((book, new_book_path));
+      }
+    }
+  }
+  // The book renamed over another one replaces it, unless that one is moved away too
+  let moved_paths: HashSet<&BookPath> = moved_books.iter().map(|(book, _)| &book.path_to_book).collect();
+  for (_, new_book_path) in &moved_books {
+    match path_to_string(new_book_path).and_then(|book_path| crud::get_primary::<Book>(ctx, book_path)) {
+      Ok(Some(replaced_book)) if !moved_paths.contains(&replaced_book.path_to_book) => {
+        deleted_books.push(replaced_book);
+      }
+      Ok(_) => {}
+      Err(e) => { error!("failed to look up {}: {e}", new_book_path.display()); }
+    }
+  }
+  books_deletion_handler(ctx, deleted_books);
+  books_moving_handler(ctx, moved_books);
+  added_paths
+}
+
+/// If the batch fails, the books are deleted one by one, so one broken book doesn't keep the rest in the library
+fn books_deletion_handler(ctx: &Context, books: Vec<Book>) {
+  let books = books.into_iter().unique_by(|book| book.path_to_book.clone()).collect_vec();
+  if books.is_empty() {
+    return;
+  }
+  if let Err(e) = crud::book::del_books_and_their_data(ctx, books.clone()) {
+    error!("failed to delete {} books at once, deleting them one by one: {e}", books.len());
+    for book in books {
+      let book_path = book.path_to_book.clone();
+      if let Err(e) = crud::book::del_book_and_its_data(ctx, book) {
+        error!("failed to delete {book_path}: {e}");
+      }
+    }
   }
 }
 
+/// If the batch fails, the books are moved one by one
+fn books_moving_handler(ctx: &Context, moved_books: Vec<(Book, PathBuf)>) {
+  if moved_books.is_empty() {
+    return;
+  }
+  if let Err(e) = crud::book::move_books(ctx, moved_books.clone()) {
+    error!("failed to move {} books at once, moving them one by one: {e}", moved_books.len());
+    for (book, new_path) in moved_books {
+      let book_path = book.path_to_book.clone();
+      if let Err(e) = crud::book::move_book(ctx, book, &new_path) {
+        error!("failed to move {book_path}: {e}");
+      }
+    }
+  }
+}
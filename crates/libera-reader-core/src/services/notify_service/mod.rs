@@ -1,89 +1,370 @@
-use crate::db::crud;
-use crate::vars::WATCHER;
-use crate::vars::{NOTIFY_EVENTS, SHUTDOWN};
-use notify::event::{CreateKind, ModifyKind, RemoveKind, RenameMode};
-use notify::{Event, EventKind, RecursiveMode, Watcher};
-use std::sync::atomic::Ordering;
-use tracing::debug;
+use crate::context::Context;
+use crate::error::CoreResult;
+use crate::services::lifecycle::ServiceControl;
+use changes::{PathChange, PendingChanges};
+use notify::event::{ModifyKind, RenameMode};
+use crate::utils::get_enabled_library_roots;
+use notify::{Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use std::collections::VecDeque;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::RecvTimeoutError;
+use std::time::{Duration, Instant};
+use tracing::{debug, error};
+mod changes;
 mod handlers;
 
+/// How long the old path of a rename waits for the new one,
+/// after that the path is considered moved out of the watched dirs
+const RENAME_TIMEOUT: Duration = Duration::from_millis(250);
+/// How long a path has to stay quiet before its changes are applied, so a book being copied is added once
+const SETTLE_TIME: Duration = Duration::from_millis(500);
+/// Longest wait for an event, the service checks whether it has to pause or stop in between
+const MAX_WAIT: Duration = Duration::from_millis(250);
 
-fn event_processing(event: Event) {
+
+/// Watcher of the enabled library roots, they are watched only while the notify service runs
+pub(crate) struct RootsWatcher {
+  watcher: RecommendedWatcher,
+  /// `None` while the notify service is stopped
+  watched_roots: Option<Vec<String>>,
+}
+
+impl RootsWatcher {
+  pub(crate) fn new(watcher: RecommendedWatcher) -> Self {
+    Self { watcher, watched_roots: None }
+  }
+  /// Starts watching the root if the notify service runs
+  pub(crate) fn watch(&mut self, path_to_root: &String) -> CoreResult<()> {
+    match &mut self.watched_roots {
+      None => Ok(()),
+      Some(watched_roots) => {
+        self.watcher.watch(path_to_root.as_ref(), RecursiveMode::Recursive)?;
+        watched_roots.push(path_to_root.clone());
+        Ok(())
+      }
+    }
+  }
+  pub(crate) fn unwatch(&mut self, path_to_root: &String) {
+    if let Some(watched_roots) = &mut self.watched_roots {
+      watched_roots.retain(|watched_root| watched_root != path_to_root);
+      let _ = self.watcher.unwatch(path_to_root.as_ref());
+    }
+  }
+  fn watch_all(&mut self, paths_to_roots: Vec<String>) {
+    self.watched_roots = Some(vec![]);
+    for path_to_root in paths_to_roots {
+      if let Err(e) = self.watch(&path_to_root) {
+        error!("failed to watch {path_to_root}: {e}");
+      }
+    }
+  }
+  fn unwatch_all(&mut self) {
+    for path_to_root in self.watched_roots.take().unwrap_or_default() {
+      let _ = self.watcher.unwatch(path_to_root.as_ref());
+    }
+  }
+}
+
+/// Old paths of renames reported in two halves, paired with the new paths by the tracker id of the backend
+/// or, if the backend has none, by the order of the events
+struct PendingRenames {
+  rename_timeout: Duration,
+  old_paths: VecDeque<(Option<usize>, PathBuf, Instant)>,
+  last_paired: Option<usize>,
+}
+
+impl PendingRenames {
+  fn new(rename_timeout: Duration) -> Self {
+    Self { rename_timeout, old_paths: VecDeque::new(), last_paired: None }
+  }
+  fn push(&mut self, tracker: Option<usize>, old_path: PathBuf) {
+    self.old_paths.push_back((tracker, old_path, Instant::now()));
+  }
+  fn take(&mut self, tracker: Option<usize>) -> Option<PathBuf> {
+    let position = self.old_paths.iter().position(|(old_tracker, _, _)| *old_tracker == tracker)?;
+    self.last_paired = tracker;
+    self.old_paths.remove(position).map(|(_, old_path, _)| old_path)
+  }
+  /// Some backends report a rename both in halves and as a whole
+  fn is_paired(&self, tracker: Option<usize>) -> bool {
+    tracker.is_some() && tracker == self.last_paired
+  }
+  fn time_to_expire(&self) -> Option<Duration> {
+    self.old_paths.front().map(|(_, _, pushed_at)| self.rename_timeout.saturating_sub(pushed_at.elapsed()))
+  }
+  fn take_all(&mut self) -> Vec<PathBuf> {
+    self.old_paths.drain(..).map(|(_, old_path, _)| old_path).collect()
+  }
+  fn take_expired(&mut self) -> Vec<PathBuf> {
+    let mut expired = vec![];
+    while self.old_paths.front().is_some_and(|(_, _, pushed_at)| pushed_at.elapsed() >= self.rename_timeout) {
+      expired.extend(self.old_paths.pop_front().map(|(_, old_path, _)| old_path));
+    }
+    expired
+  }
+}
+
+/// Turns the event into a pending change of its path, nothing touches the db until the path settles
+fn event_processing(pending_renames: &mut PendingRenames, pending_changes: &mut PendingChanges, event: Event) {
+  if event.paths.is_empty() {
+    return;
+  }
+  let tracker = event.tracker();
   match event {
-    Event { kind, paths, attrs: _attrs } => {
+    Event { kind, mut paths, attrs: _attrs } => {
+      let path = paths.swap_remove(0);
       match kind {
-        EventKind::Create(create_kind) => {
-          match create_kind {
-            CreateKind::File => {
-              handlers::book_adding_handler(&paths[0]);
-            }
-            _ => {}
-          }
-        }
+        EventKind::Create(_) => { pending_changes.add(path, PathChange::Added); }
         EventKind::Modify(modify_kind) => {
           match modify_kind {
             ModifyKind::Name(rename_mode) => {
               match rename_mode {
-                RenameMode::Both => {
-                  let old_path = &paths[0];
-                  let new_path = &paths[1];
-                  if new_path.is_dir() {
-                    crud::book::update_the_books_directory(old_path, new_path);
-                  } else {
-                    handlers::book_path_update_handler(old_path, new_path);
-                  }
+                RenameMode::Both if !paths.is_empty() && !pending_renames.is_paired(tracker) => {
+                  pending_changes.rename(path, paths.swap_remove(0));
+                }
+                RenameMode::From => { pending_renames.push(tracker, path); }
+                RenameMode::To => { new_path_of_rename(pending_renames, pending_changes, tracker, path); }
+                // Backends that don't tell the halves apart report the path that still exists as the new one
+                RenameMode::Any if path.exists() => {
+                  new_path_of_rename(pending_renames, pending_changes, tracker, path);
                 }
-                RenameMode::From => {}
-                RenameMode::To => {}
+                RenameMode::Any => { pending_renames.push(tracker, path); }
                 _ => {}
               }
             }
+            ModifyKind::Data(_) | ModifyKind::Any => { pending_changes.add(path, PathChange::Modified); }
             _ => {}
           }
         }
-        EventKind::Remove(remove_kind) => {
-          match remove_kind {
-            RemoveKind::File => {
-              handlers::book_deletion_handler(paths[0].to_str().unwrap());
-            }
-            RemoveKind::Folder => {
-              handlers::dir_deletion_handler(paths[0].to_str().unwrap().to_string());
-            }
-            _ => {}
-          }
-        }
+        EventKind::Remove(_) => { pending_changes.add(path, PathChange::Removed); }
         _ => {}
       }
     }
   }
 }
 
-pub fn run() {
+/// A new path without an old one has been moved in from outside the watched dirs
+fn new_path_of_rename(pending_renames: &mut PendingRenames, pending_changes: &mut PendingChanges,
+                      tracker: Option<usize>, new_path: PathBuf) {
+  match pending_renames.take(tracker) {
+    None => pending_changes.add(new_path, PathChange::Added),
+    Some(old_path) => pending_changes.rename(old_path, new_path),
+  }
+}
+
+/// Removals go first, so a renamed or new book can take the place of a removed one.
+/// The removed and the moved books are changed in one transaction each,
+/// the books that have appeared are added together in batched transactions
+fn apply_changes(ctx: &Context, control: &ServiceControl, changes: Vec<(PathBuf, PathChange)>) {
+  let mut removed_paths = vec![];
+  let mut renamed_paths = vec![];
+  let mut modified_paths = vec![];
+  let mut added_paths = vec![];
+  for (path, change) in changes {
+    match change {
+      PathChange::Removed => removed_paths.push(path),
+      PathChange::Renamed { old_path, modified } => {
+        if modified {
+          modified_paths.push(path.clone());
+        }
+        renamed_paths.push((old_path, path));
+      }
+      PathChange::Modified => modified_paths.push(path),
+      PathChange::Added => added_paths.push(path),
+    }
+  }
+  handlers::paths_removal_handler(ctx, removed_paths);
+  added_paths.extend(handlers::paths_renaming_handler(ctx, &renamed_paths));
+  for path in modified_paths {
+    log_error(handlers::book_modification_handler(ctx, &path), "update", &path);
+  }
+  handlers::books_adding_handler(ctx, control, added_paths);
+}
+
+/// Applies the pending changes without waiting for them to settle,
+/// the old paths still waiting for their new ones are taken as moved out of the watched dirs
+fn flush_changes(ctx: &Context, pending_renames: &mut PendingRenames, pending_changes: &mut PendingChanges) {
+  for old_path in pending_renames.take_all() {
+    pending_changes.add(old_path, PathChange::Removed);
+  }
+  let changes = pending_changes.take_all();
+  if !changes.is_empty() {
+    // A control of its own, so a stop of the service doesn't cut the adding of the books short
+    apply_changes(ctx, &ServiceControl::new(), changes);
+  }
+}
+
+/// A failing change must not stop the watcher, the next dir scan catches up with it
+fn log_error(res: CoreResult<()>, action: &str, path: &Path) {
+  if let Err(e) = res {
+    error!("failed to {action} {}: {e}", path.display());
+  }
+}
+
+/// The library roots are watched from the start of the service till its end.
+/// The changes noticed before a pause or a stop are applied right away, they don't wait for the service to resume
+pub(crate) fn run(ctx: &Context, control: &ServiceControl) {
+  let notify_events = ctx.notify_events.lock().unwrap();
+  {
+    // The roots are read under the lock, so a root changed meanwhile isn't missed
+    let mut watcher = ctx.watcher.lock().unwrap();
+    watcher.watch_all(get_enabled_library_roots(ctx).into_iter().map(|library_root| library_root.path).collect());
+  }
+  let mut pending_renames = PendingRenames::new(RENAME_TIMEOUT);
+  let mut pending_changes = PendingChanges::new(SETTLE_TIME);
   loop {
-    match NOTIFY_EVENTS.pop() {
+    if !control.is_working() {
+      flush_changes(ctx, &mut pending_renames, &mut pending_changes);
+    }
+    if !control.keep_going() {
+      break;
+    }
+    let timeout = [pending_renames.time_to_expire(), pending_changes.time_to_settle()].into_iter()
+      .flatten()
+      .fold(MAX_WAIT, Duration::min);
+    match notify_events.recv_timeout(timeout) {
       Ok(res) => {
-        match res {
-          Ok(event) => {
-            event_processing(event);
+        // A burst of events, e.g. a copied dir, is taken at once
+        for res in std::iter::once(res).chain(notify_events.try_iter()) {
+          match res {
+            Ok(event) => { event_processing(&mut pending_renames, &mut pending_changes, event); }
+            Err(e) => { error!("file system watcher error: {e}"); }
           }
-          Err(_) => {}
         }
       }
-      Err(_) => {}
+      Err(RecvTimeoutError::Timeout) => {}
+      Err(RecvTimeoutError::Disconnected) => { break; }
+    }
+    // The new path never came, the book or dir has been moved out of the watched dirs
+    for old_path in pending_renames.take_expired() {
+      pending_changes.add(old_path, PathChange::Removed);
+    }
+    let settled_changes = pending_changes.take_settled();
+    if !settled_changes.is_empty() {
+      apply_changes(ctx, control, settled_changes);
     }
-    if SHUTDOWN.load(Ordering::Relaxed) == true {
-      debug!("notify has been stopped");
-      break;
-    } else { continue; }
   }
+  flush_changes(ctx, &mut pending_renames, &mut pending_changes);
+  ctx.watcher.lock().unwrap().unwatch_all();
+  debug!("notify has been stopped");
 }
 
-pub fn run_watcher(path_to_scan: &String) {
-  WATCHER.lock().unwrap().watch(path_to_scan.as_ref(), RecursiveMode::Recursive).unwrap();
-}
-pub fn stop_watcher(path_to_scan: &String) {
-  match WATCHER.lock().unwrap().unwatch(path_to_scan.as_ref()) {
-    Ok(_) => {}
-    Err(_) => {}
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use itertools::Itertools;
+  use notify::event::{CreateKind, DataChange, RemoveKind};
+
+  fn rename_event(rename_mode: RenameMode, paths: &[&str], tracker: Option<usize>) -> Event {
+    let event = Event::new(EventKind::Modify(ModifyKind::Name(rename_mode)));
+    let event = paths.iter().fold(event, |event, path| event.add_path(PathBuf::from(*path)));
+    match tracker {
+      None => event,
+      Some(tracker) => event.set_tracker(tracker),
+    }
+  }
+
+  fn process(pending_renames: &mut PendingRenames, events: Vec<Event>) -> Vec<(PathBuf, PathChange)> {
+    let mut pending_changes = PendingChanges::new(Duration::ZERO);
+    for event in events {
+      event_processing(pending_renames, &mut pending_changes, event);
+    }
+    for old_path in pending_renames.take_expired() {
+      pending_changes.add(old_path, PathChange::Removed);
+    }
+    pending_changes.take_settled().into_iter().sorted_by(|(a, _), (b, _)| a.cmp(b)).collect()
+  }
+
+  fn renamed(old_path: &str) -> PathChange {
+    PathChange::Renamed { old_path: PathBuf::from(old_path), modified: false }
+  }
+
+  #[test]
+  fn takes_the_kind_of_change_from_the_event() {
+    let changes = process(&mut PendingRenames::new(RENAME_TIMEOUT), vec![
+      Event::new(EventKind::Create(CreateKind::Any)).add_path("/lib/a.pdf".into()),
+      Event::new(EventKind::Modify(ModifyKind::Data(DataChange::Content))).add_path("/lib/b.pdf".into()),
+      Event::new(EventKind::Modify(ModifyKind::Any)).add_path("/lib/c.pdf".into()),
+      Event::new(EventKind::Remove(RemoveKind::File)).add_path("/lib/d.pdf".into()),
+      Event::new(EventKind::Access(notify::event::AccessKind::Any)).add_path("/lib/e.pdf".into()),
+    ]);
+    assert_eq!(changes, vec![
+      (PathBuf::from("/lib/a.pdf"), PathChange::Added),
+      (PathBuf::from("/lib/b.pdf"), PathChange::Modified),
+      (PathBuf::from("/lib/c.pdf"), PathChange::Modified),
+      (PathBuf::from("/lib/d.pdf"), PathChange::Removed),
+    ]);
+  }
+
+  #[test]
+  fn pairs_the_halves_of_renames_by_tracker() {
+    let mut pending_renames = PendingRenames::new(RENAME_TIMEOUT);
+    let changes = process(&mut pending_renames, vec![
+      rename_event(RenameMode::From, &["/lib/a.pdf"], Some(1)),
+      rename_event(RenameMode::From, &["/lib/b.pdf"], Some(2)),
+      rename_event(RenameMode::To, &["/lib/d.pdf"], Some(2)),
+      rename_event(RenameMode::To, &["/lib/c.pdf"], Some(1)),
+      // The same rename reported as a whole is skipped
+      rename_event(RenameMode::Both, &["/lib/a.pdf", "/lib/c.pdf"], Some(1)),
+    ]);
+    assert_eq!(changes, vec![
+      (PathBuf::from("/lib/c.pdf"), renamed("/lib/a.pdf")),
+      (PathBuf::from("/lib/d.pdf"), renamed("/lib/b.pdf")),
+    ]);
+    assert!(pending_renames.time_to_expire().is_none());
+  }
+
+  #[test]
+  fn pairs_the_halves_of_renames_without_tracker_by_order() {
+    let changes = process(&mut PendingRenames::new(RENAME_TIMEOUT), vec![
+      rename_event(RenameMode::From, &["/lib/a.pdf"], None),
+      rename_event(RenameMode::To, &["/lib/b.pdf"], None),
+      rename_event(RenameMode::Both, &["/lib/c.pdf", "/lib/d.pdf"], None),
+    ]);
+    assert_eq!(changes, vec![
+      (PathBuf::from("/lib/b.pdf"), renamed("/lib/a.pdf")),
+      (PathBuf::from("/lib/d.pdf"), renamed("/lib/c.pdf")),
+    ]);
+  }
+
+  #[test]
+  fn unpaired_halves_of_renames_are_moves_in_and_out() {
+    let events = || vec![
+      rename_event(RenameMode::From, &["/lib/a.pdf"], Some(1)),
+      rename_event(RenameMode::To, &["/lib/b.pdf"], Some(2)),
+    ];
+    // The old path waits for its new path
+    let mut pending_renames = PendingRenames::new(RENAME_TIMEOUT);
+    let changes = process(&mut pending_renames, events());
+    assert_eq!(changes, vec![(PathBuf::from("/lib/b.pdf"), PathChange::Added)]);
+    assert!(pending_renames.time_to_expire().is_some());
+    // and is moved out once the timeout is over
+    let changes = process(&mut PendingRenames::new(Duration::ZERO), events());
+    assert_eq!(changes, vec![
+      (PathBuf::from("/lib/a.pdf"), PathChange::Removed),
+      (PathBuf::from("/lib/b.pdf"), PathChange::Added),
+    ]);
+  }
+
+  /// Backends that don't tell the halves apart report the old path, that no longer exists, and then the new one
+  #[test]
+  fn tells_apart_the_halves_of_renames_of_any_mode() {
+    let dir = std::env::temp_dir().join(format!("libera_reader_notify_rename_any_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let old_path = dir.join("old.pdf");
+    let new_path = dir.join("new.pdf");
+    let moved_in_path = dir.join("moved_in.pdf");
+    std::fs::write(&new_path, b"book").unwrap();
+    std::fs::write(&moved_in_path, b"book").unwrap();
+    let changes = process(&mut PendingRenames::new(RENAME_TIMEOUT), vec![
+      rename_event(RenameMode::Any, &[old_path.to_str().unwrap()], None),
+      rename_event(RenameMode::Any, &[new_path.to_str().unwrap()], None),
+      rename_event(RenameMode::Any, &[moved_in_path.to_str().unwrap()], None),
+    ]);
+    std::fs::remove_dir_all(&dir).unwrap();
+    assert_eq!(changes, vec![
+      (moved_in_path, PathChange::Added),
+      (new_path, PathChange::Renamed { old_path, modified: false }),
+    ]);
   }
 }
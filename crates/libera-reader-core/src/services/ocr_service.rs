@@ -0,0 +1,82 @@
+use crate::context::Context;
+use crate::db::crud;
+use crate::error::{CoreError, CoreResult};
+use crate::models::{Book, OcrText, ProgressPhase, Settings};
+use crate::services::lifecycle::ServiceControl;
+use crate::types::BookPath;
+use mupdf::document::Document;
+use std::time::Duration;
+use tracing::{debug, error};
+
+
+pub(crate) fn push_book_for_ocr(ctx: &Context, book_path: BookPath) {
+  ctx.progress.ocr.add_to_total(ProgressPhase::Recognizing, 1);
+  ctx.books_for_ocr.push(book_path).unwrap();
+}
+
+pub(crate) fn run(ctx: &Context, control: &ServiceControl) {
+  while control.keep_going() {
+    match ctx.books_for_ocr.pop() {
+      Ok(book_path) => {
+        ctx.progress.ocr.set_current_file(&book_path);
+        match recognize_book(ctx, control, &book_path) {
+          Ok(true) => {}
+          // Stopped in the middle of the book, it's recognized from the start next time
+          Ok(false) => {
+            ctx.books_for_ocr.push(book_path).unwrap();
+            break;
+          }
+          Err(e) => { error!("ocr: failed to recognize {book_path}: {e}"); }
+        }
+        ctx.progress.ocr.advance(1);
+        ctx.workers.throttle(control);
+      }
+      Err(_) => {
+        ctx.progress.ocr.finish_if_done();
+        control.idle(Duration::from_secs(1));
+      }
+    }
+  }
+  debug!("ocr service has been stopped");
+}
+
+/// Returns `false` if the service has been stopped before the whole book is recognized,
+/// the text of the pages recognized so far is dropped
+fn recognize_book(ctx: &Context, control: &ServiceControl, book_path: &BookPath) -> CoreResult<bool> {
+  let book = match crud::get_primary::<Book>(ctx, book_path.clone())? {
+    None => { return Ok(true); }
+    Some(book) => { book }
+  };
+  let book_hash = crud::book::get_or_calc_book_hash(ctx, &book)?;
+  if crud::get_primary::<OcrText>(ctx, book_hash.clone())?.is_some() {
+    return Ok(true);
+  }
+  let settings = Settings::from_db(ctx)?;
+  let path_to_tessdata = settings.get_path_to_tessdata(ctx);
+  let path_to_tessdata = path_to_tessdata.to_str().ok_or(CoreError::InvalidPath(path_to_tessdata.clone()))?;
+
+  let doc = Document::open(book_path, 20).map_err(CoreError::Document)?;
+  let page_count = doc.page_count().unwrap_or(0);
+  let mut pages: Vec<String> = Vec::with_capacity(page_count as usize);
+  for page_num in 0..page_count {
+    if !control.keep_going() {
+      return Ok(false);
+    }
+    let page = doc.load_page(page_num as i32)
+      .map_err(|e| CoreError::Document(format!("failed to load page {page_num}: {e}")))?;
+    // Pages that already have a text layer don't need to be recognized
+    let text = match page.get_text() {
+      Ok(text) if !text.trim().is_empty() => { text }
+      _ => {
+        page.ocr_text(&settings.ocr_language, path_to_tessdata)
+          .map_err(|e| CoreError::Document(format!("failed to recognize page {page_num}: {e}")))?
+      }
+    };
+    pages.push(text);
+  }
+  // Scans have little or no text layer, so the index is rebuilt from the recognized text
+  crud::search_index::index_book(ctx, book.book_data_pk.clone(), pages.clone())?;
+  crud::insert(ctx, OcrText { book_hash, pages })?;
+  debug!("ocr: book recognized: {book_path}");
+  Ok(true)
+}
@@ -0,0 +1,87 @@
+use crate::context::Context;
+use crate::db::crud;
+use crate::error::{CoreError, CoreResult};
+use crate::models::{Book, BookDataType, DataOfUnhashedBook, OcrText, ProgressPhase};
+use crate::services::lifecycle::ServiceControl;
+use crate::types::{BookHash, BookPath};
+use crate::workers::RayonTaskType::Indexing;
+use crate::workers::WorkerPool;
+use itertools::Itertools;
+use mupdf::document::Document;
+use rayon::prelude::*;
+use std::time::Duration;
+use tracing::{debug, error};
+
+
+pub(crate) fn push_book_for_indexing(ctx: &Context, book_path: BookPath) {
+  ctx.progress.search_index.add_to_total(ProgressPhase::Indexing, 1);
+  ctx.books_for_indexing.push(book_path).unwrap();
+}
+
+pub(crate) fn run(ctx: &Context, control: &ServiceControl) {
+  let mut pool = WorkerPool::new(&ctx.workers, Indexing);
+  while control.keep_going() {
+    pool.refresh(&ctx.workers);
+    let books_for_indexing = ctx.books_for_indexing.try_iter().take(pool.num_of_threads()).collect_vec();
+    if books_for_indexing.is_empty() {
+      ctx.progress.search_index.finish_if_done();
+      control.idle(Duration::from_secs(1));
+      continue;
+    }
+    // Only the text is extracted in parallel, the index itself is written by one transaction at a time
+    pool.install(|| {
+      books_for_indexing.into_par_iter().for_each(|book_path| {
+        ctx.progress.search_index.set_current_file(&book_path);
+        if let Err(e) = index_book(ctx, &book_path) {
+          error!("search index: failed to index {book_path}: {e}");
+        }
+        ctx.progress.search_index.advance(1);
+      });
+    });
+    ctx.workers.throttle(control);
+  }
+  debug!("search index service has been stopped");
+}
+
+fn index_book(ctx: &Context, book_path: &BookPath) -> CoreResult<()> {
+  let book = match crud::get_primary::<Book>(ctx, book_path.clone())? {
+    None => { return Ok(()); }
+    Some(book) => { book }
+  };
+  // Duplicates share the record, so the group is indexed once
+  if crud::search_index::is_indexed(ctx, &book.book_data_pk.as_key())? {
+    return Ok(());
+  }
+  let pages = match get_recognized_text(ctx, &book.book_data_pk)? {
+    Some(pages) => { pages }
+    None => { extract_text(book_path).map_err(CoreError::Document)? }
+  };
+  crud::search_index::index_book(ctx, book.book_data_pk, pages)?;
+  debug!("search index: book indexed: {book_path}");
+  Ok(())
+}
+
+/// Text recognized by the ocr service, the hash isn't calculated just for the lookup
+fn get_recognized_text(ctx: &Context, book_data_pk: &BookDataType) -> CoreResult<Option<Vec<String>>> {
+  let book_hash: BookHash = match book_data_pk {
+    BookDataType::RepeatingSize(book_hash) => book_hash.clone(),
+    BookDataType::UniqueSize(book_size) => {
+      match crud::get_primary::<DataOfUnhashedBook>(ctx, *book_size)?.and_then(|data| data.book_hash) {
+        None => { return Ok(None); }
+        Some(book_hash) => { book_hash }
+      }
+    }
+  };
+  Ok(crud::get_primary::<OcrText>(ctx, book_hash)?.map(|ocr_text| ocr_text.pages))
+}
+
+fn extract_text(book_path: &BookPath) -> Result<Vec<String>, String> {
+  let doc = Document::open(book_path, 20)?;
+  let page_count = doc.page_count()?;
+  let mut pages: Vec<String> = Vec::with_capacity(page_count as usize);
+  for page_num in 0..page_count {
+    let text = doc.load_page(page_num as i32)?.get_text().unwrap_or_default();
+    pages.push(text);
+  }
+  Ok(pages)
+}
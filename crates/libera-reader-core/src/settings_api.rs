@@ -0,0 +1,150 @@
+use crate::context::Context;
+use crate::db::crud;
+use crate::error::CoreResult;
+use crate::models::{LibraryRoot, Settings, TargetExt};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+
+pub struct SettingsApi {
+  ctx: Arc<Context>,
+}
+
+impl SettingsApi {
+  pub(crate) fn new(ctx: Arc<Context>) -> Self { Self { ctx } }
+  /// Current settings of the library
+  pub fn get(&self) -> CoreResult<Settings> {
+    Settings::from_db(&self.ctx)
+  }
+  /// Adds the dir to the library and starts watching it,
+  /// returns `false` if it is already a library root
+  pub fn add_library_root(&self, path: String) -> CoreResult<bool> {
+    self.update_library_roots(|library_roots| {
+      if library_roots.iter().any(|library_root| library_root.path == path) {
+        return false;
+      }
+      library_roots.push(LibraryRoot::new(path));
+      true
+    })
+  }
+  pub fn remove_library_root(&self, path: &str) -> CoreResult<bool> {
+    self.update_library_roots(|library_roots| {
+      let old_len = library_roots.len();
+      library_roots.retain(|library_root| library_root.path != path);
+      library_roots.len() != old_len
+    })
+  }
+  /// Books of a disabled root are removed from the library on the next dir scan
+  pub fn set_library_root_enabled(&self, path: &str, enabled: bool) -> CoreResult<bool> {
+    self.update_library_root(path, |library_root| library_root.enabled = enabled)
+  }
+  /// Extensions of the books taken from the root, `None` to use the global [`TargetExt`]
+  pub fn set_library_root_formats(&self, path: &str, formats: Option<Vec<String>>) -> CoreResult<bool> {
+    let formats = formats.map(|formats| formats.iter().map(|format| format.to_lowercase()).collect());
+    self.update_library_root(path, |library_root| library_root.formats = formats.clone())
+  }
+  pub fn get_library_roots(&self) -> Vec<LibraryRoot> {
+    self.ctx.library_roots.read().unwrap().clone()
+  }
+  /// Whether there is at least one enabled library root
+  pub fn has_library_roots(&self) -> bool {
+    self.ctx.library_roots.read().unwrap().iter().any(|library_root| library_root.enabled)
+  }
+  /// Extensions of the books taken from roots that have no formats of their own
+  pub fn get_target_ext(&self) -> TargetExt {
+    self.ctx.target_ext.read().unwrap().clone()
+  }
+  pub fn set_target_ext(&self, pdf: bool, epub: bool, mobi: bool) -> CoreResult<()> {
+    let old_target_ext = TargetExt::from_db(&self.ctx)?;
+    let new_target_ext = TargetExt { id: old_target_ext.id, pdf, epub, mobi };
+    crud::update(&self.ctx, old_target_ext, new_target_ext.clone())?;
+    *self.ctx.target_ext.write().unwrap() = new_target_ext;
+    Ok(())
+  }
+  /// The ignore rules are applied on the next dir scan and to new notify events
+  pub fn set_ignore_patterns(&self, ignore_patterns: Vec<String>) -> CoreResult<()> {
+    Settings::update(&self.ctx, |settings| settings.ignore_patterns = ignore_patterns).map(|_| ())
+  }
+  pub fn set_skip_hidden(&self, skip_hidden: bool) -> CoreResult<()> {
+    Settings::update(&self.ctx, |settings| settings.skip_hidden = skip_hidden).map(|_| ())
+  }
+  pub fn set_max_scan_depth(&self, max_scan_depth: Option<usize>) -> CoreResult<()> {
+    Settings::update(&self.ctx, |settings| settings.max_scan_depth = max_scan_depth).map(|_| ())
+  }
+  pub fn set_ocr_language(&self, ocr_language: String) -> CoreResult<()> {
+    Settings::update(&self.ctx, |settings| settings.ocr_language = ocr_language).map(|_| ())
+  }
+  pub fn set_path_to_tessdata(&self, path_to_tessdata: Option<String>) -> CoreResult<()> {
+    Settings::update(&self.ctx, |settings| settings.path_to_tessdata = path_to_tessdata).map(|_| ())
+  }
+  /// Threads extracting thumbnails and metadata, `0` to pick them by the number of CPUs
+  pub fn set_workers_num(&self, workers_num: i32) -> CoreResult<()> {
+    self.update_workers(|settings| settings.workers_num = workers_num)
+  }
+  /// Threads hashing books of the same size, applied from the next group of books being hashed
+  pub fn set_hashing_workers_num(&self, hashing_workers_num: i32) -> CoreResult<()> {
+    self.update_workers(|settings| settings.hashing_workers_num = hashing_workers_num)
+  }
+  pub fn set_indexing_workers_num(&self, indexing_workers_num: i32) -> CoreResult<()> {
+    self.update_workers(|settings| settings.indexing_workers_num = indexing_workers_num)
+  }
+  /// Throttles the background services while a book is open to save the battery
+  pub fn set_low_priority(&self, low_priority: bool) -> CoreResult<()> {
+    self.update_workers(|settings| settings.low_priority = low_priority)
+  }
+  /// Speeds up the hashing of huge books, `None` hashes the whole file
+  pub fn set_partial_hash_min_size(&self, partial_hash_min_size: Option<u64>) -> CoreResult<()> {
+    Settings::update(&self.ctx, |settings| settings.partial_hash_min_size = partial_hash_min_size).map(|_| ())
+  }
+  /// Directory with the `*.traineddata` files, the `tessdata` app dir is used if no other is set
+  pub fn get_path_to_tessdata(&self) -> CoreResult<PathBuf> {
+    Ok(self.get()?.get_path_to_tessdata(&self.ctx))
+  }
+
+  /// Saves the settings and resizes the pools of the running services
+  fn update_workers(&self, change_settings: impl FnOnce(&mut Settings)) -> CoreResult<()> {
+    let settings = Settings::update(&self.ctx, change_settings)?;
+    self.ctx.workers.update(&settings);
+    Ok(())
+  }
+  fn update_library_root(&self, path: &str, change_library_root: impl Fn(&mut LibraryRoot)) -> CoreResult<bool> {
+    self.update_library_roots(|library_roots| {
+      match library_roots.iter_mut().find(|library_root| library_root.path == path) {
+        None => false,
+        Some(library_root) => {
+          change_library_root(library_root);
+          true
+        }
+      }
+    })
+  }
+  /// Saves the changed roots and starts or stops watching them if the notify service runs
+  fn update_library_roots(&self, change_library_roots: impl FnOnce(&mut Vec<LibraryRoot>) -> bool)
+                          -> CoreResult<bool> {
+    // Held till the end, so the notify service starting meanwhile sees the new roots
+    let mut watcher = self.ctx.watcher.lock().unwrap();
+    let old_settings = Settings::from_db(&self.ctx)?;
+    let mut new_settings = old_settings.clone();
+    if !change_library_roots(&mut new_settings.library_roots) {
+      return Ok(false);
+    }
+    let was_watched = |path: &String| old_settings.library_roots.iter()
+      .any(|library_root| library_root.enabled && &library_root.path == path);
+    let is_watched = |path: &String| new_settings.library_roots.iter()
+      .any(|library_root| library_root.enabled && &library_root.path == path);
+    for library_root in &old_settings.library_roots {
+      if was_watched(&library_root.path) && !is_watched(&library_root.path) {
+        watcher.unwatch(&library_root.path);
+      }
+    }
+    for library_root in &new_settings.library_roots {
+      if is_watched(&library_root.path) && !was_watched(&library_root.path) {
+        watcher.watch(&library_root.path)?;
+      }
+    }
+    let library_roots = new_settings.library_roots.clone();
+    crud::update::<Settings>(&self.ctx, old_settings, new_settings)?;
+    *self.ctx.library_roots.write().unwrap() = library_roots;
+    Ok(true)
+  }
+}
@@ -1,4 +1,5 @@
 pub(crate) type BookPath = String;
-pub(crate) type BookSize = String;
+/// Size of the book file in bytes
+pub(crate) type BookSize = u64;
 pub(crate) type BookHash = String;
 pub(crate) type NotifyEvents = notify::Result<notify::Event>;
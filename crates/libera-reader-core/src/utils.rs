@@ -1,60 +1,115 @@
+use crate::context::Context;
 use crate::db::crud;
-use crate::models::{Book, BookDataType, DataOfHashedBook, DataOfUnhashedBook};
-use crate::types::BookPath;
-use crate::vars::{APP_DIRS, NOT_CACHED_BOOKS, TARGET_EXT};
-use gxhash::GxBuildHasher;
+use crate::error::{CoreError, CoreResult};
+use crate::models::{Book, BookData, BookDataType, LibraryRoot, ProgressPhase};
+use crate::scan_rules::ScanRules;
+use crate::types::{BookHash, BookPath, BookSize};
+use data_encoding::HEXLOWER;
 use measure_time_macro::measure_time;
 use std::fs;
-use std::hash::{BuildHasher, Hasher};
-use std::io::Read;
-use std::path::PathBuf;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
 use tracing::debug;
-use walkdir::WalkDir;
+use xxhash_rust::xxh3::Xxh3;
 
+/// Files are hashed in chunks of 1 MB
+const HASH_CHUNK_SIZE: usize = 1024 * 1024;
+const PARTIAL_HASH_PREFIX: &str = "p";
 
-fn round_num(x: f64, decimals: u32) -> f64 {
-  let y = 10i32.pow(decimals) as f64;
-  (x * y).round() / y
-}
 
-pub(crate) fn calc_file_size_in_mb(path_to_file: &PathBuf) -> String {
-  let metadata = fs::metadata(path_to_file).unwrap();
-  let size_mb = metadata.len() as f64 / (1024.0 * 1024.0);
-  round_num(size_mb, 6).to_string()
+pub(crate) fn calc_file_size(path_to_file: &Path) -> CoreResult<BookSize> {
+  Ok(fs::metadata(path_to_file)?.len())
 }
 
-pub(crate) fn calc_file_hash(path_to_file: &PathBuf) -> String {
-  let mut hasher = GxBuildHasher::default().build_hasher();
-  let mut file = fs::File::open(path_to_file).unwrap();
-  loop {
-    // Read the file in 1 MB chunks
-    let mut buffer = [0; 1024 * 1024];
-    let bytes_read = file.read(&mut buffer).unwrap();
-    if bytes_read == 0 {
-      break;
+/// XXH3-128 of the file in hex, the same on every platform.
+/// Files of at least `partial_hash_min_size` bytes are identified by their size and three chunks
+/// from the head, the middle and the tail, such hashes start with `p`
+pub(crate) fn calc_file_hash(path_to_file: &Path, partial_hash_min_size: Option<u64>) -> CoreResult<BookHash> {
+  let mut file = fs::File::open(path_to_file)?;
+  let file_size = file.metadata()?.len();
+  let mut hasher = Xxh3::new();
+  let mut buffer = vec![0; HASH_CHUNK_SIZE];
+  match is_hashed_partially(file_size, partial_hash_min_size) {
+    true => {
+      hasher.update(&file_size.to_le_bytes());
+      let chunk_size = HASH_CHUNK_SIZE as u64;
+      for offset in [0, (file_size - chunk_size) / 2, file_size - chunk_size] {
+        file.seek(SeekFrom::Start(offset))?;
+        file.read_exact(&mut buffer)?;
+        hasher.update(&buffer);
+      }
+      Ok(format!("{PARTIAL_HASH_PREFIX}{}", HEXLOWER.encode(&hasher.digest128().to_be_bytes())))
+    }
+    false => {
+      loop {
+        let bytes_read = file.read(&mut buffer)?;
+        if bytes_read == 0 {
+          break;
+        }
+        hasher.update(&buffer[..bytes_read]);
+      }
+      Ok(HEXLOWER.encode(&hasher.digest128().to_be_bytes()))
     }
-    hasher.write(&buffer[..bytes_read]);
   }
-  data_encoding::HEXLOWER.encode(&hasher.finish().to_ne_bytes())
+}
+
+pub(crate) fn is_hashed_partially(file_size: u64, partial_hash_min_size: Option<u64>) -> bool {
+  partial_hash_min_size.is_some_and(|min_size| file_size >= min_size && file_size >= 3 * HASH_CHUNK_SIZE as u64)
+}
+
+/// Hex digits never make the prefix of a partial hash
+pub(crate) fn is_partial_hash(book_hash: &BookHash) -> bool {
+  book_hash.starts_with(PARTIAL_HASH_PREFIX)
+}
+
+/// Paths are stored as strings, so non-UTF-8 paths can't be added to the library
+pub(crate) fn path_to_string(path: &Path) -> CoreResult<String> {
+  path.to_str().map(|path| path.to_string()).ok_or_else(|| CoreError::InvalidPath(path.to_path_buf()))
+}
+
+pub(crate) fn get_thumbnail_path(ctx: &Context, book_data_type: &BookDataType) -> PathBuf {
+  let app_dirs = &ctx.app_dirs;
+  let dir = match book_data_type {
+    BookDataType::UniqueSize(_) => &app_dirs.dir_of_unhashed_books,
+    BookDataType::RepeatingSize(_) => &app_dirs.dir_of_hashed_books,
+  };
+  dir.join(format!("{}.jpeg", book_data_type.as_key()))
+}
+
+/// Current unix time in seconds
+pub(crate) fn get_timestamp() -> u64 {
+  SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+}
+
+pub(crate) fn get_enabled_library_roots(ctx: &Context) -> Vec<LibraryRoot> {
+  ctx.library_roots.read().unwrap().iter().filter(|library_root| library_root.enabled).cloned().collect()
+}
+
+/// The enabled root the path belongs to, the innermost one if the roots are nested
+pub(crate) fn find_library_root(ctx: &Context, path: &Path) -> Option<LibraryRoot> {
+  find_library_root_in(&ctx.library_roots.read().unwrap(), path).cloned()
+}
+pub(crate) fn find_library_root_in<'a>(library_roots: &'a [LibraryRoot], path: &Path) -> Option<&'a LibraryRoot> {
+  library_roots.iter()
+    .filter(|library_root| library_root.enabled && library_root.contains(path))
+    .max_by_key(|library_root| library_root.path.len())
 }
 
 #[measure_time]
-pub(crate) fn get_books_from_disk(path_to_scan: &String) -> Vec<PathBuf> {
+pub(crate) fn get_books_from_disk(ctx: &Context, library_root: &LibraryRoot, scan_rules: &ScanRules) -> Vec<PathBuf> {
   let mut books_from_disk: Vec<PathBuf> = vec![];
-  for entry in WalkDir::new(path_to_scan) {
-    let entry = entry.unwrap();
-    if entry.file_type().is_file() {
-      let path = entry.path();
-      match path.extension() {
-        Some(res) => {
-          let file_ext = res.to_str().unwrap();
-          if TARGET_EXT.read().unwrap().contains(file_ext) {
-            books_from_disk.push(path.to_path_buf());
-          }
+  let target_ext = ctx.target_ext.read().unwrap();
+  for path in scan_rules.walk(library_root) {
+    match path.extension() {
+      Some(res) => {
+        let file_ext = res.to_string_lossy();
+        if library_root.accepts_ext(&file_ext, &target_ext) {
+          books_from_disk.push(path);
         }
-        None => {}
-      };
-    }
+      }
+      None => {}
+    };
   };
   books_from_disk
 }
@@ -68,63 +123,20 @@ impl NotCachedBook {
   pub(crate) fn new(book_path: BookPath) -> Self {
     Self { book_path }
   }
-  pub(crate) fn push_to_storage(self) {
-    NOT_CACHED_BOOKS.push(self).unwrap();
+  pub(crate) fn push_to_storage(self, ctx: &Context) {
+    ctx.progress.data_extraction.add_to_total(ProgressPhase::Extracting, 1);
+    ctx.not_cached_books.push(self).unwrap();
   }
-  pub(crate) fn mark_as_cached(self) {
-    let book = crud::get_primary::<Book>(self.book_path).unwrap();
-    match book.book_data_pk {
-      BookDataType::UniqueSize(book_size) => {
-        let old_book_data = crud::get_primary::<DataOfUnhashedBook>(book_size).unwrap();
-        let mut new_book_data = old_book_data.clone();
-        new_book_data.book_data.cached = true;
-        crud::update(old_book_data, new_book_data).unwrap();
-      }
-      BookDataType::RepeatingSize(book_hash) => {
-        let old_book_data = crud::get_primary::<DataOfHashedBook>(book_hash).unwrap();
-        let mut new_book_data = old_book_data.clone();
-        new_book_data.book_data.cached = true;
-        crud::update(old_book_data, new_book_data).unwrap();
-      }
-    }
+  /// Marks the book data as cached and saves the data extracted along with the thumbnail
+  pub(crate) fn mark_as_cached(self, ctx: &Context, fill_book_data: impl Fn(&mut BookData)) -> CoreResult<()> {
+    let book = self.get_book(ctx)?;
+    crud::book::update_book_data(ctx, &book, |book_data| {
+      book_data.cached = true;
+      fill_book_data(book_data);
+    })
   }
-  pub(crate) fn get_out_file_name(&self) -> String {
-    let book = crud::get_primary::<Book>(self.book_path.clone()).unwrap();
-    match &book.book_data_pk {
-      BookDataType::UniqueSize(book_size) => {
-        APP_DIRS.read().unwrap().dir_of_unhashed_books.join(book_size).to_str().unwrap().to_string()
-      }
-      BookDataType::RepeatingSize(book_hash) => {
-        APP_DIRS.read().unwrap().dir_of_hashed_books.join(book_hash).to_str().unwrap().to_string()
-      }
-    }
-  }
-}
-
-pub(crate) enum RayonTaskType {
-  ImgExtract,
-  HashCalc,
-}
-
-pub(crate) fn get_num_of_threads(rayon_task_type: RayonTaskType) -> usize {
-  let num_of_cpus = num_cpus::get();
-  let num_threads_for_task: usize;
-  if num_of_cpus >= 6 {
-    match rayon_task_type {
-      RayonTaskType::ImgExtract => { num_threads_for_task = num_of_cpus - 2; }
-      RayonTaskType::HashCalc => { num_threads_for_task = 2; }
-    }
-  } else {
-    match rayon_task_type {
-      RayonTaskType::ImgExtract => {
-        if num_of_cpus == 1 {
-          num_threads_for_task = 1;
-        } else {
-          num_threads_for_task = num_of_cpus - 1;
-        }
-      }
-      RayonTaskType::HashCalc => { num_threads_for_task = 2; }
-    }
+  pub(crate) fn get_book(&self, ctx: &Context) -> CoreResult<Book> {
+    crud::get_primary::<Book>(ctx, self.book_path.clone())?
+      .ok_or_else(|| CoreError::NotFound(format!("book {}", self.book_path)))
   }
-  num_threads_for_task
 }
@@ -0,0 +1,121 @@
+use crate::models::Settings;
+use crate::services::lifecycle::ServiceControl;
+use crate::types::BookPath;
+use gxhash::HashSet;
+use rayon::{ThreadPool, ThreadPoolBuilder};
+use std::sync::{Mutex, RwLock};
+use std::time::Duration;
+use tracing::debug;
+
+/// Pause between the items of a low priority service
+const LOW_PRIORITY_PAUSE: Duration = Duration::from_millis(500);
+
+
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum RayonTaskType {
+  ImgExtract,
+  HashCalc,
+  Indexing,
+}
+
+#[derive(Default)]
+struct WorkersSettings {
+  img_extract: i32,
+  hash_calc: i32,
+  indexing: i32,
+  low_priority: bool,
+}
+
+/// Number of threads of the background services and whether they are throttled
+pub(crate) struct Workers {
+  settings: RwLock<WorkersSettings>,
+  open_books: Mutex<HashSet<BookPath>>,
+}
+
+impl Workers {
+  pub(crate) fn new() -> Self {
+    Self { settings: Default::default(), open_books: Default::default() }
+  }
+  /// Picks up the changed settings, the pools are rebuilt before their next batch
+  pub(crate) fn update(&self, settings: &Settings) {
+    *self.settings.write().unwrap() = WorkersSettings {
+      img_extract: settings.workers_num,
+      hash_calc: settings.hashing_workers_num,
+      indexing: settings.indexing_workers_num,
+      low_priority: settings.low_priority,
+    };
+  }
+  pub(crate) fn book_opened(&self, book_path: &BookPath) {
+    self.open_books.lock().unwrap().insert(book_path.clone());
+  }
+  pub(crate) fn book_closed(&self, book_path: &BookPath) {
+    self.open_books.lock().unwrap().remove(book_path);
+  }
+  /// Whether the low priority mode is on and the user is reading a book
+  pub(crate) fn is_throttled(&self) -> bool {
+    self.settings.read().unwrap().low_priority && !self.open_books.lock().unwrap().is_empty()
+  }
+  /// One thread while throttled, the number from the settings if it is positive, a split by the CPUs otherwise
+  pub(crate) fn num_of_threads(&self, rayon_task_type: RayonTaskType) -> usize {
+    if self.is_throttled() {
+      return 1;
+    }
+    let settings = self.settings.read().unwrap();
+    let num_from_settings = match rayon_task_type {
+      RayonTaskType::ImgExtract => settings.img_extract,
+      RayonTaskType::HashCalc => settings.hash_calc,
+      RayonTaskType::Indexing => settings.indexing,
+    };
+    if num_from_settings > 0 {
+      return num_from_settings as usize;
+    }
+    let num_of_cpus = num_cpus::get();
+    match rayon_task_type {
+      RayonTaskType::ImgExtract => {
+        if num_of_cpus >= 6 { num_of_cpus - 2 } else { (num_of_cpus - 1).max(1) }
+      }
+      RayonTaskType::HashCalc => 2,
+      RayonTaskType::Indexing => 1,
+    }
+  }
+  /// Slows the service down while throttled, a pause or a stop of the service cuts the wait short
+  pub(crate) fn throttle(&self, control: &ServiceControl) {
+    if self.is_throttled() {
+      control.idle(LOW_PRIORITY_PAUSE);
+    }
+  }
+}
+
+/// Rayon pool of a background service that follows the number of threads in the settings
+pub(crate) struct WorkerPool {
+  rayon_task_type: RayonTaskType,
+  num_of_threads: usize,
+  pool: ThreadPool,
+}
+
+impl WorkerPool {
+  pub(crate) fn new(workers: &Workers, rayon_task_type: RayonTaskType) -> Self {
+    let num_of_threads = workers.num_of_threads(rayon_task_type);
+    debug!("Number of threads for {:?}: {:?}", rayon_task_type, num_of_threads);
+    Self { rayon_task_type, num_of_threads, pool: build_pool(num_of_threads) }
+  }
+  /// Rebuilds the pool if the number of threads has changed, called between batches
+  pub(crate) fn refresh(&mut self, workers: &Workers) {
+    let num_of_threads = workers.num_of_threads(self.rayon_task_type);
+    if num_of_threads != self.num_of_threads {
+      debug!("Number of threads for {:?}: {:?}", self.rayon_task_type, num_of_threads);
+      self.num_of_threads = num_of_threads;
+      self.pool = build_pool(num_of_threads);
+    }
+  }
+  pub(crate) fn num_of_threads(&self) -> usize {
+    self.num_of_threads
+  }
+  pub(crate) fn install<R: Send>(&self, op: impl FnOnce() -> R + Send) -> R {
+    self.pool.install(op)
+  }
+}
+
+fn build_pool(num_of_threads: usize) -> ThreadPool {
+  ThreadPoolBuilder::new().num_threads(num_of_threads).build().unwrap()
+}
@@ -0,0 +1,38 @@
+use libera_reader_core::core::{Core, CoreConfig};
+use libera_reader_core::models::DuplicateAction;
+use libera_reader_core::CoreError;
+use std::fs::{create_dir_all, remove_dir_all, remove_file, write};
+use std::path::PathBuf;
+
+
+#[test]
+fn keeps_the_copies_if_the_kept_one_is_missing() {
+  let test_files = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("test_files");
+  let library_dir = test_files.join("tmp_duplicates");
+  let data_dir = test_files.join("tmp_duplicates_data");
+  let _ = remove_dir_all(&library_dir);
+  let _ = remove_dir_all(&data_dir);
+  create_dir_all(&library_dir).unwrap();
+  let kept_book = library_dir.join("kept_book.pdf");
+  let other_book = library_dir.join("other_book.pdf");
+  write(&kept_book, b"the same content").unwrap();
+  write(&other_book, b"the same content").unwrap();
+
+  let mut core = Core::open(CoreConfig::new().data_dir(data_dir.clone())).unwrap();
+  core.settings.add_library_root(library_dir.to_str().unwrap().to_string()).unwrap();
+  core.services.launch_dir_scan_service(true);
+  let groups = core.book_api.duplicates().unwrap();
+  assert_eq!(groups.len(), 1);
+
+  // The kept copy is gone, but the db doesn't know it yet
+  remove_file(&kept_book).unwrap();
+  let res = core.book_api.remove_duplicates(&groups[0].book_hash, &kept_book.to_str().unwrap().to_string(),
+                                            &DuplicateAction::Delete);
+  assert!(matches!(res, Err(CoreError::InvalidArgument(_))));
+  assert!(other_book.exists());
+  assert_eq!(core.book_api.duplicates().unwrap()[0].copies.len(), 2);
+
+  drop(core);
+  let _ = remove_dir_all(&library_dir);
+  let _ = remove_dir_all(&data_dir);
+}
@@ -1,6 +1,6 @@
-use libera_reader_core::{core::Core, models::Book, vars, vars::DB_NAME};
-use std::env::set_var;
-use std::fs::{create_dir, remove_dir_all, remove_file, rename, File};
+use libera_reader_core::core::{Core, CoreConfig};
+use libera_reader_core::models::{Book, CoreEvent, ProgressPhase};
+use std::fs::{create_dir, remove_dir_all, remove_file, rename, write, File};
 use std::path::PathBuf;
 use std::process::Command;
 use std::thread::sleep;
@@ -14,7 +14,7 @@ pub enum TestMode {
   DirScan,
 }
 
-const TIME_BETWEEN_TESTS: u64 = 300;
+const TIME_BETWEEN_TESTS: u64 = 1000;
 const FIRST_BOOK: &str = "first_book.pdf";
 pub const SECOND_BOOK: &str = "second_book.pdf";
 const FIRST_DIR: &str = "first_dir";
@@ -29,7 +29,6 @@ pub struct FileCrudLib {
   second_dir: PathBuf,
 
   tmp_dir: PathBuf,
-  proj_root_dir: PathBuf,
   core: Core,
   test_mode: TestMode,
 }
@@ -37,9 +36,10 @@ impl FileCrudLib {
   pub fn new(test_mode: TestMode, tmp_dir_name: &str) -> Self {
     let proj_root_dir = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
     let tmp_dir = proj_root_dir.join("test_files").join(tmp_dir_name);
-    set_var("libera_reader_data_dir", tmp_dir.to_string2());
-    let core = Core::new();
-    vars::APP_DIRS.write().unwrap().change_base_dir(Some(proj_root_dir.clone())).unwrap();
+    // Every test gets a fresh db of its own
+    let data_dir = proj_root_dir.join("test_files").join(format!("{tmp_dir_name}_data"));
+    let _ = remove_dir_all(&data_dir);
+    let core = Core::open(CoreConfig::new().data_dir(data_dir)).unwrap();
     Self {
       first_book: tmp_dir.join(&FIRST_BOOK),
       second_book: tmp_dir.join(&SECOND_BOOK),
@@ -48,19 +48,25 @@ impl FileCrudLib {
       second_dir: tmp_dir.join(&SECOND_DIR),
 
       tmp_dir,
-      proj_root_dir,
       core,
       test_mode,
     }
   }
   pub fn create_first_book(&mut self) {
     info!("Create first book");
+    let events = self.core.subscribe();
     assert!(File::create(&self.first_book).is_ok());
     match self.test_mode {
       TestMode::Notify => { sleep(Duration::from_millis(TIME_BETWEEN_TESTS)); }
-      TestMode::DirScan => { self.core.services.launch_dir_scan_service(true); }
+      TestMode::DirScan => {
+        self.core.services.launch_dir_scan_service(true);
+        assert_eq!(ProgressPhase::Idle, self.core.services.progress().dir_scan.phase);
+      }
     };
     self.test_fn(&self.first_book.to_string2(), |book: &Book| assert_eq!(&FIRST_BOOK, &book.book_name));
+    assert!(events.try_iter().any(|event| {
+      matches!(event, CoreEvent::BookAdded { book_path, .. } if book_path == self.first_book.to_string2())
+    }));
   }
   pub fn rename_first_book_to_second(&mut self) {
     info!("File rename test: rename first book to second");
@@ -132,15 +138,63 @@ impl FileCrudLib {
       Err(_) => {}
     };
   }
+  pub fn recreate_book_in_another_dir(&mut self) {
+    info!("Move by delete and create test: the book keeps its bookmarks");
+    let book = self.tmp_dir.join(&FIRST_BOOK);
+    assert!(File::create(&book).is_ok());
+    match self.test_mode {
+      TestMode::Notify => { sleep(Duration::from_millis(TIME_BETWEEN_TESTS)); }
+      TestMode::DirScan => { self.core.services.launch_dir_scan_service(true); }
+    }
+    let bookmark = self.core.book_api.add_bookmark(&book.to_string2(), "bookmark".to_string(), String::new(), 1);
+    assert!(bookmark.unwrap().is_some());
+
+    assert!(create_dir(&self.fist_dir).is_ok());
+    let moved_book = self.fist_dir.join(&FIRST_BOOK);
+    assert!(remove_file(&book).is_ok());
+    if let TestMode::Notify = self.test_mode {
+      sleep(Duration::from_millis(TIME_BETWEEN_TESTS));
+    }
+    assert!(File::create(&moved_book).is_ok());
+    match self.test_mode {
+      TestMode::Notify => { sleep(Duration::from_millis(TIME_BETWEEN_TESTS)); }
+      TestMode::DirScan => { self.core.services.launch_dir_scan_service(true); }
+    }
+    let bookmarks = self.core.book_api.get_bookmarks(&moved_book.to_string2()).unwrap();
+    assert_eq!(1, bookmarks.len(), "the moved book should keep its bookmark");
+    self.first_book = moved_book;
+  }
+  pub fn rewrite_first_book_in_place(&mut self) {
+    info!("File modification test: the rewritten book gets a new key and keeps its bookmarks");
+    let old_book = self.core.book_api.get_book_by_path(&self.first_book.to_string2()).unwrap().unwrap();
+    assert!(write(&self.first_book, "new content").is_ok());
+    sleep(Duration::from_millis(TIME_BETWEEN_TESTS));
+    self.test_fn(&self.first_book.to_string2(), |book: &Book| assert_ne!(old_book.book_data_pk, book.book_data_pk));
+    let bookmarks = self.core.book_api.get_bookmarks(&self.first_book.to_string2()).unwrap();
+    assert_eq!(1, bookmarks.len(), "the rewritten book should keep its bookmark");
+  }
+  pub fn move_first_dir_out_and_back(&mut self) {
+    info!("Dir movement test: a dir moved out of the library and back");
+    let outside_dir = PathBuf::from(format!("{}_outside", self.tmp_dir.to_string2()));
+    let _ = remove_dir_all(&outside_dir);
+    assert!(create_dir(&outside_dir).is_ok());
+    // Only the old path of the rename is reported, the book goes missing once nothing follows it
+    assert!(rename(&self.fist_dir, outside_dir.join(&FIRST_DIR)).is_ok());
+    sleep(Duration::from_millis(TIME_BETWEEN_TESTS * 2));
+    self.test_fn(&self.first_book.to_string2(), |book: &Book| assert!(!book.path_is_valid));
+    // Only the new path is reported, the books of the dir are added without events of their own
+    assert!(rename(outside_dir.join(&FIRST_DIR), &self.fist_dir).is_ok());
+    sleep(Duration::from_millis(TIME_BETWEEN_TESTS));
+    self.test_fn(&self.first_book.to_string2(), |book: &Book| assert!(book.path_is_valid));
+    let bookmarks = self.core.book_api.get_bookmarks(&self.first_book.to_string2()).unwrap();
+    assert_eq!(1, bookmarks.len(), "the book moved back should keep its bookmark");
+    let _ = remove_dir_all(&outside_dir);
+  }
   pub fn drop_files(&self) {
     match remove_dir_all(&self.tmp_dir) {
       Ok(_) => {}
       Err(e) => error!("error when deleting tests_files_dir: {:?}", e),
     };
-    match remove_file(&self.proj_root_dir.join(DB_NAME)) {
-      Ok(_) => {}
-      Err(_) => {}
-    };
     match create_dir(&self.tmp_dir) {
       Ok(_) => {}
       Err(e) => error!("error when creating tests_files_dir: {:?}", e),
@@ -168,7 +222,7 @@ impl FileCrudLib {
   }
   pub fn run_tests(&mut self) {
     self.drop_files();
-    self.core.settings.set_path_to_scan(self.tmp_dir.to_str().unwrap().to_string());
+    self.core.settings.add_library_root(self.tmp_dir.to_str().unwrap().to_string()).unwrap();
 
     match self.test_mode {
       TestMode::Notify => {
@@ -182,6 +236,12 @@ impl FileCrudLib {
     self.rename_first_dir_to_second();
     self.rename_second_book_to_first_in_second_dir();
     self.drop_second_dir();
+    self.recreate_book_in_another_dir();
+    // The dir scan doesn't look at the content of known books nor at halves of renames
+    if let TestMode::Notify = self.test_mode {
+      self.rewrite_first_book_in_place();
+      self.move_first_dir_out_and_back();
+    }
   }
 }
 pub trait EasyString {
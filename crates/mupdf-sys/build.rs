@@ -1,54 +1,105 @@
+// With a system libmupdf nothing has to be fetched or built
+#[cfg(not(feature = "system"))]
 use std::fs::File;
-use std::path::{Path, PathBuf};
-use std::process::Command;
-use std::process::Stdio;
-use std::{env, fs};
+#[cfg(not(feature = "system"))]
+use std::path::Path;
+use std::path::PathBuf;
+#[cfg(not(feature = "system"))]
+use std::process::{Command, Stdio};
+use std::env;
+#[cfg(not(feature = "system"))]
+use std::fs;
+#[cfg(not(feature = "system"))]
 use zip::ZipArchive;
 
-fn extract_from_zip_archive(path_to_file: &str) {
+// The system libmupdf is built without Tesseract
+#[cfg(all(feature = "system", feature = "ocr"))]
+compile_error!("the `ocr` feature needs the vendored MuPDF, it can't be combined with the `system` feature");
+
+#[cfg(not(feature = "system"))]
+const MUPDF_VERSION: &str = "1.24.9";
+/// Path to an unpacked MuPDF source tree or to a zip archive with it
+#[cfg(not(feature = "system"))]
+const MUPDF_SRC_ENV: &str = "MUPDF_SRC";
+
+#[cfg(not(feature = "system"))]
+fn extract_from_zip_archive(path_to_file: &Path, out_dir: &Path) {
   let path_to_file = File::open(path_to_file).unwrap();
   let archive = ZipArchive::new(path_to_file);
-  let out_dir = Path::new(".");
   archive
     .unwrap()
-    .extract(&out_dir)
+    .extract(out_dir)
     .expect("Failed to extract archive");
 }
+#[cfg(all(not(feature = "system"), feature = "download"))]
 fn download_mupdf(path_to_out_dir: &str) {
-  let url = "https://github.com/ArtifexSoftware/mupdf/archive/refs/tags/1.24.9.zip";
+  let url = format!("https://github.com/ArtifexSoftware/mupdf/archive/refs/tags/{}.zip", MUPDF_VERSION);
   let mut response = reqwest::blocking::get(url).expect("request failed");
   let mut file = File::create(path_to_out_dir).expect("Failed to open file");
   response.copy_to(&mut file).unwrap();
 }
-fn get_mupdf_if_necessary() {
+#[cfg(not(any(feature = "system", feature = "download")))]
+fn download_mupdf(_path_to_out_dir: &str) {
+  panic!(
+    "MuPDF sources not found: set {} to a MuPDF {} source tree or zip archive, \
+    enable the `system` feature or the `download` feature",
+    MUPDF_SRC_ENV, MUPDF_VERSION
+  );
+}
+/// Unpacks the archive into the `mupdf` dir of the crate,
+/// the archive is expected to contain a single top-level directory
+#[cfg(not(feature = "system"))]
+fn unpack_mupdf_archive(mupdf_archive: &Path) {
+  let extract_dir = Path::new("mupdf-extract");
+  extract_from_zip_archive(mupdf_archive, extract_dir);
+  let top_level_dir = fs::read_dir(extract_dir).unwrap()
+    .map(|entry| entry.unwrap().path())
+    .find(|path| path.is_dir())
+    .expect("MuPDF archive doesn't contain a directory");
+  fs::rename(top_level_dir, "mupdf").unwrap();
+  fs::remove_dir_all(extract_dir).expect("Failed: delete mupdf-extract dir");
+}
+#[cfg(not(feature = "system"))]
+fn get_mupdf_if_necessary() -> PathBuf {
+  println!("cargo:rerun-if-env-changed={}", MUPDF_SRC_ENV);
+  if let Ok(mupdf_src) = env::var(MUPDF_SRC_ENV) {
+    let mupdf_src = PathBuf::from(mupdf_src);
+    if mupdf_src.is_dir() {
+      return mupdf_src;
+    }
+    if !Path::new("mupdf").exists() {
+      unpack_mupdf_archive(&mupdf_src);
+    }
+    return env::current_dir().unwrap().join("mupdf");
+  }
   match fs::read_dir("mupdf") {
     Ok(_) => {}
     Err(_) => {
-      let mupdf_archive = "mupdf-1.24.9.zip";
-      match File::open(mupdf_archive) {
+      let mupdf_archive = format!("mupdf-{}.zip", MUPDF_VERSION);
+      match File::open(&mupdf_archive) {
         Ok(_) => {}
         Err(_) => {
-          download_mupdf(mupdf_archive);
+          download_mupdf(&mupdf_archive);
         }
       };
-      extract_from_zip_archive(mupdf_archive);
-
-      fs::rename("mupdf-1.24.9", "mupdf").unwrap();
-      fs::remove_file(mupdf_archive).expect("Failed: delete mupdf tar archive");
+      unpack_mupdf_archive(Path::new(&mupdf_archive));
+      fs::remove_file(&mupdf_archive).expect("Failed: delete mupdf tar archive");
     }
   }
+  env::current_dir().unwrap().join("mupdf")
 }
 
-fn build_libmupdf() {
+#[cfg(not(feature = "system"))]
+fn build_libmupdf(mupdf_dir: &Path) {
   let out_dir = PathBuf::from(env::var("OUT_DIR").unwrap());
   if !out_dir.exists() {
     fs::create_dir(&out_dir).unwrap();
   }
 
-  let current_dir = env::current_dir().unwrap();
-  let mupdf_dir = current_dir.join("mupdf");
-
-  let mupdf_release_dir = mupdf_dir.join("build").join("release");
+  // OCR builds go to a separate directory so that toggling the feature
+  // doesn't reuse libraries built without Tesseract and vice versa
+  let release_dir_name = if cfg!(feature = "ocr") { "release-ocr" } else { "release" };
+  let mupdf_release_dir = mupdf_dir.join("build").join(release_dir_name);
   let libmupdf_file = mupdf_release_dir.join("libmupdf.a").exists();
   let libmupdf_third_file = mupdf_release_dir.join("libmupdf-third.a").exists();
   let build_is_need = !libmupdf_file && !libmupdf_third_file;
@@ -59,7 +110,15 @@ fn build_libmupdf() {
       "HAVE_X11=no".to_owned(),
       "HAVE_GLUT=no".to_owned(),
       "HAVE_CURL=no".to_owned(),
+      format!("OUT=build/{}", release_dir_name),
     ];
+    if cfg!(feature = "ocr") {
+      make_flags.push("HAVE_TESSERACT=yes".to_owned());
+      make_flags.push("HAVE_LEPTONICA=yes".to_owned());
+    } else {
+      make_flags.push("HAVE_TESSERACT=no".to_owned());
+      make_flags.push("HAVE_LEPTONICA=no".to_owned());
+    }
 
     // Enable parallel compilation
     if let Ok(n) = std::thread::available_parallelism() {
@@ -68,7 +127,7 @@ fn build_libmupdf() {
 
     let output: std::process::Output = Command::new("make")
       .args(&make_flags)
-      .current_dir(&mupdf_dir)
+      .current_dir(mupdf_dir)
       .stdout(Stdio::inherit())
       .stderr(Stdio::inherit())
       .output()
@@ -81,11 +140,36 @@ fn build_libmupdf() {
   println!("cargo:rustc-link-search=native={}", mupdf_release_dir.display());
   println!("cargo:rustc-link-lib=static=mupdf");
   println!("cargo:rustc-link-lib=static=mupdf-third");
+  if cfg!(feature = "ocr") {
+    // Tesseract is written in C++
+    let target_os = env::var("CARGO_CFG_TARGET_OS").unwrap();
+    let target_env = env::var("CARGO_CFG_TARGET_ENV").unwrap();
+    if target_os == "macos" {
+      println!("cargo:rustc-link-lib=c++");
+    } else if target_env != "msvc" {
+      println!("cargo:rustc-link-lib=stdc++");
+    }
+  }
 }
 
-fn generate_bindings() {
-  let bindings = bindgen::Builder::default()
-    .clang_arg("-I./mupdf/include/mupdf")
+/// Links against the libmupdf installed in the system and returns its include dirs
+#[cfg(feature = "system")]
+fn find_system_libmupdf() -> Vec<PathBuf> {
+  let library = pkg_config::Config::new()
+    .atleast_version("1.24")
+    .probe("mupdf")
+    .expect("libmupdf not found by pkg-config");
+  library.include_paths
+}
+
+fn generate_bindings(include_dirs: &[PathBuf]) {
+  let mut builder = bindgen::Builder::default();
+  for include_dir in include_dirs {
+    builder = builder
+      .clang_arg(format!("-I{}", include_dir.display()))
+      .clang_arg(format!("-I{}", include_dir.join("mupdf").display()));
+  }
+  let bindings = builder
     .header("wrapper.c")
     .header("wrapper.h")
     .allowlist_function("fz_.*")
@@ -113,15 +197,24 @@ fn generate_bindings() {
 }
 
 fn main() {
-  get_mupdf_if_necessary();
   println!("cargo:rerun-if-changed=wrapper.c");
   println!("cargo:rerun-if-changed=wrapper.h");
 
-  build_libmupdf();
+  #[cfg(feature = "system")]
+  let include_dirs = find_system_libmupdf();
+  #[cfg(not(feature = "system"))]
+  let include_dirs = {
+    let mupdf_dir = get_mupdf_if_necessary();
+    build_libmupdf(&mupdf_dir);
+    vec![mupdf_dir.join("include")]
+  };
 
   let mut build = cc::Build::new();
-  build.file("wrapper.c").include("./mupdf/include");
+  build.file("wrapper.c");
+  for include_dir in &include_dirs {
+    build.include(include_dir);
+  }
   build.compile("libmupdf-wrapper.a");
 
-  generate_bindings();
+  generate_bindings(&include_dirs);
 }
@@ -7,6 +7,10 @@
 #include <mupdf/fitz/store.h>
 #include <mupdf/fitz/write-pixmap.h>
 #include <stdbool.h>
+#include <string.h>
+
+/* Pages are rendered for tesseract at this resolution, at 72 dpi scanned text is mostly lost */
+#define OCR_DPI 300.0f
 
 
 typedef struct {
@@ -108,6 +112,83 @@ typedef struct {
   } value;
 } mupdf_metadata;
 
+typedef struct {
+  bool status;
+
+  union {
+    pdf_annot *widget;
+    const char *err_msg;
+  } value;
+} mupdf_widget;
+
+/*
+  name, value and every option are allocated with fz_strdup,
+  options itself with fz_malloc, all of them must be freed with fz_free.
+*/
+typedef struct {
+  pdf_widget_type widget_type;
+  char *name;
+  char *value;
+  fz_rect rect;
+  int options_count;
+  char **options;
+} mupdf_widget_info;
+
+typedef struct {
+  bool status;
+
+  union {
+    mupdf_widget_info info;
+    const char *err_msg;
+  } value;
+} mupdf_widget_info_res;
+
+/*
+  Every string is allocated with fz_strdup and must be freed with fz_free,
+  mime_type and description are NULL if the file specification lacks them.
+  size is -1 if the document doesn't tell the size of the decoded file.
+*/
+typedef struct {
+  char *name;
+  char *file_name;
+  char *mime_type;
+  char *description;
+  int64_t size;
+} mupdf_attachment_info;
+
+/*
+  infos must be freed with fz_free once the strings of every info are freed.
+*/
+typedef struct {
+  bool status;
+
+  union {
+    struct {
+      mupdf_attachment_info *infos;
+      int count;
+    } list;
+
+    const char *err_msg;
+  } value;
+} mupdf_attachment_list;
+
+/*
+  buf must be dropped with fz_drop_buffer, data points into it.
+*/
+typedef struct {
+  bool status;
+
+  union {
+    struct {
+      fz_buffer *buf;
+      unsigned char *data;
+      size_t data_len;
+    } value;
+
+    const char *err_msg;
+  } inner;
+} mupdf_attachment_data;
+
 void set_err_in_poss_ctx(mupdf_ctx *res, const char *msg, fz_context *ctx) {
   res->status = false;
   res->value.err_msg = msg;
@@ -182,12 +263,19 @@ mupdf_outline mupdf_load_outline(fz_context *ctx, fz_document *doc) {
   return res;
 }
 
+/*
+  The returned value is allocated with fz_malloc and must be freed with fz_free.
+*/
 mupdf_metadata mupdf_lookup_metadata(fz_context *ctx, fz_document *doc,
                                      const char *key) {
   mupdf_metadata res;
-  static char buf[500];
+  char *buf = NULL;
+  fz_var(buf);
   fz_try(ctx) {
-    if (fz_lookup_metadata(ctx, doc, key, buf, sizeof buf) > 0) {
+    const int size = fz_lookup_metadata(ctx, doc, key, NULL, 0);
+    if (size > 0) {
+      buf = fz_malloc(ctx, size);
+      fz_lookup_metadata(ctx, doc, key, buf, size);
       res.status = true;
       res.value.res = buf;
     } else {
@@ -196,6 +284,7 @@ mupdf_metadata mupdf_lookup_metadata(fz_context *ctx, fz_document *doc,
     }
   }
   fz_catch(ctx) {
+    fz_free(ctx, buf);
     res.status = false;
     res.value.err_msg = fz_caught_message(ctx);
   }
@@ -274,6 +363,62 @@ mupdf_stext_json mupdf_stext_page_as_json_from_page(fz_context *ctx,
   return res;
 }
 
+/*
+  Runs the page through the OCR device and returns the recognized text.
+  language: tesseract language, e.g. "eng" or "eng+rus".
+  datadir: directory with *.traineddata files, NULL to use TESSDATA_PREFIX.
+  The returned text is allocated with fz_strdup and must be freed with fz_free.
+*/
+mupdf_text_from_page mupdf_page_ocr_as_plain_text(fz_context *ctx,
+                                                  fz_page *page,
+                                                  const char *language,
+                                                  const char *datadir) {
+  mupdf_text_from_page res;
+  fz_buffer *buf = NULL;
+  fz_output *out = NULL;
+  fz_stext_page *text = NULL;
+  fz_device *stext_dev = NULL;
+  fz_device *ocr_dev = NULL;
+  fz_var(buf);
+  fz_var(out);
+  fz_var(text);
+  fz_var(stext_dev);
+  fz_var(ocr_dev);
+  fz_try(ctx) {
+    const fz_matrix ctm = fz_scale(OCR_DPI / 72.0f, OCR_DPI / 72.0f);
+    const fz_rect mediabox = fz_transform_rect(fz_bound_page(ctx, page), ctm);
+    text = fz_new_stext_page(ctx, mediabox);
+    stext_dev = fz_new_stext_device(ctx, text, NULL);
+    ocr_dev = fz_new_ocr_device(ctx, stext_dev, ctm, mediabox, 1,
+                                language, datadir, NULL, NULL);
+    fz_run_page(ctx, page, ocr_dev, ctm, NULL);
+    fz_close_device(ctx, ocr_dev);
+    fz_close_device(ctx, stext_dev);
+
+    buf = fz_new_buffer(ctx, 8192);
+    out = fz_new_output_with_buffer(ctx, buf);
+    fz_print_stext_page_as_text(ctx, out, text);
+    fz_close_output(ctx, out);
+    res.status = true;
+    res.value.text = fz_strdup(ctx, fz_string_from_buffer(ctx, buf));
+  }
+  fz_always(ctx) {
+    fz_drop_device(ctx, ocr_dev);
+    fz_drop_device(ctx, stext_dev);
+    fz_drop_output(ctx, out);
+    fz_drop_buffer(ctx, buf);
+    fz_drop_stext_page(ctx, text);
+  }
+  fz_catch(ctx) {
+    res.status = false;
+    res.value.err_msg = fz_caught_message(ctx);
+  }
+  return res;
+}
+
+/*
+  The returned text is allocated with fz_strdup and must be freed with fz_free.
+*/
 mupdf_text_from_page mupdf_page_as_plain_text(fz_context *ctx, fz_page *page) {
   mupdf_text_from_page res;
   fz_buffer *buf = NULL;
@@ -288,12 +433,13 @@ mupdf_text_from_page mupdf_page_as_plain_text(fz_context *ctx, fz_page *page) {
     out = fz_new_output_with_buffer(ctx, buf);
     fz_print_stext_page_as_text(ctx, out, text);
     fz_close_output(ctx, out);
+    res.status = true;
+    res.value.text = fz_strdup(ctx, fz_string_from_buffer(ctx, buf));
   }
   fz_always(ctx) {
     fz_drop_output(ctx, out);
+    fz_drop_buffer(ctx, buf);
     fz_drop_stext_page(ctx, text);
-    res.status = true;
-    res.value.text = fz_string_from_buffer(ctx, buf);
   }
   fz_catch(ctx) {
     res.status = false;
@@ -336,3 +482,286 @@ mupdf_res mupdf_save_pixmap_as_jpeg(fz_context *ctx, fz_pixmap *pix,
   }
   return res;
 }
+
+/* Widgets */
+/*
+  Returns NULL as the widget if the page has no widgets or isn't a PDF page.
+*/
+mupdf_widget mupdf_first_widget(fz_context *ctx, fz_page *page) {
+  mupdf_widget res;
+  fz_try(ctx) {
+    pdf_page *pdf_page = pdf_page_from_fz_page(ctx, page);
+    res.status = true;
+    res.value.widget = pdf_page == NULL ? NULL : pdf_first_widget(ctx, pdf_page);
+  }
+  fz_catch(ctx) {
+    res.status = false;
+    res.value.err_msg = fz_caught_message(ctx);
+  }
+  return res;
+}
+
+mupdf_widget mupdf_next_widget(fz_context *ctx, pdf_annot *widget) {
+  mupdf_widget res;
+  fz_try(ctx) {
+    res.status = true;
+    res.value.widget = pdf_next_widget(ctx, widget);
+  }
+  fz_catch(ctx) {
+    res.status = false;
+    res.value.err_msg = fz_caught_message(ctx);
+  }
+  return res;
+}
+
+mupdf_widget_info_res mupdf_load_widget_info(fz_context *ctx,
+                                             pdf_annot *widget) {
+  mupdf_widget_info_res res;
+  mupdf_widget_info info = {PDF_WIDGET_TYPE_UNKNOWN, NULL, NULL, fz_empty_rect,
+                            0, NULL};
+  fz_var(info);
+  fz_try(ctx) {
+    pdf_obj *field = pdf_annot_obj(ctx, widget);
+    info.widget_type = pdf_widget_type(ctx, widget);
+    info.name = pdf_load_field_name(ctx, field);
+    const char *value = pdf_field_value(ctx, field);
+    info.value = fz_strdup(ctx, value == NULL ? "" : value);
+    info.rect = pdf_bound_widget(ctx, widget);
+    if (info.widget_type == PDF_WIDGET_TYPE_COMBOBOX ||
+        info.widget_type == PDF_WIDGET_TYPE_LISTBOX) {
+      const int count = pdf_choice_widget_options(ctx, widget, 0, NULL);
+      if (count > 0) {
+        const char **opts = fz_malloc(ctx, count * sizeof(char *));
+        fz_try(ctx) {
+          pdf_choice_widget_options(ctx, widget, 0, opts);
+          info.options = fz_calloc(ctx, count, sizeof(char *));
+          for (int i = 0; i < count; i++) {
+            info.options[i] = fz_strdup(ctx, opts[i]);
+            info.options_count = i + 1;
+          }
+        }
+        fz_always(ctx) { fz_free(ctx, opts); }
+        fz_catch(ctx) { fz_rethrow(ctx); }
+      }
+    }
+    res.status = true;
+    res.value.info = info;
+  }
+  fz_catch(ctx) {
+    fz_free(ctx, info.name);
+    fz_free(ctx, info.value);
+    for (int i = 0; i < info.options_count; i++) {
+      fz_free(ctx, info.options[i]);
+    }
+    fz_free(ctx, info.options);
+    res.status = false;
+    res.value.err_msg = fz_caught_message(ctx);
+  }
+  return res;
+}
+
+mupdf_res mupdf_set_widget_text(fz_context *ctx, pdf_annot *widget,
+                                const char *value) {
+  mupdf_res res;
+  fz_try(ctx) {
+    if (pdf_set_text_field_value(ctx, widget, value)) {
+      pdf_update_annot(ctx, widget);
+      res.status = true;
+    } else {
+      res.status = false;
+      res.err_msg = "value was rejected by the field";
+    }
+  }
+  fz_catch(ctx) {
+    res.status = false;
+    res.err_msg = fz_caught_message(ctx);
+  }
+  return res;
+}
+
+mupdf_res mupdf_set_widget_checked(fz_context *ctx, pdf_annot *widget,
+                                   const bool checked) {
+  mupdf_res res;
+  fz_try(ctx) {
+    const char *value = pdf_field_value(ctx, pdf_annot_obj(ctx, widget));
+    const bool is_checked = value != NULL && value[0] != 0 && strcmp(value, "Off") != 0;
+    if (is_checked != checked) {
+      pdf_toggle_widget(ctx, widget);
+      pdf_update_annot(ctx, widget);
+    }
+    res.status = true;
+  }
+  fz_catch(ctx) {
+    res.status = false;
+    res.err_msg = fz_caught_message(ctx);
+  }
+  return res;
+}
+
+mupdf_res mupdf_set_widget_choice(fz_context *ctx, pdf_annot *widget,
+                                  const int count, const char *values[]) {
+  mupdf_res res;
+  fz_try(ctx) {
+    pdf_choice_widget_set_value(ctx, widget, count, values);
+    pdf_update_annot(ctx, widget);
+    res.status = true;
+  }
+  fz_catch(ctx) {
+    res.status = false;
+    res.err_msg = fz_caught_message(ctx);
+  }
+  return res;
+}
+
+/*
+  incremental: append the changes to the end of the file, required when
+  saving over the file the document was opened from.
+*/
+mupdf_res mupdf_save_document(fz_context *ctx, fz_document *doc,
+                              const char *path_to_out, const bool incremental) {
+  mupdf_res res;
+  fz_try(ctx) {
+    pdf_document *pdf_doc = pdf_specifics(ctx, doc);
+    if (pdf_doc == NULL) {
+      res.status = false;
+      res.err_msg = "only PDF documents can be saved";
+    } else {
+      pdf_write_options opts = pdf_default_write_options;
+      opts.do_incremental = incremental;
+      pdf_save_document(ctx, pdf_doc, path_to_out, &opts);
+      res.status = true;
+    }
+  }
+  fz_catch(ctx) {
+    res.status = false;
+    res.err_msg = fz_caught_message(ctx);
+  }
+  return res;
+}
+
+/* Attachments */
+static pdf_obj *load_embedded_files(fz_context *ctx, fz_document *doc) {
+  pdf_document *pdf_doc = pdf_specifics(ctx, doc);
+  if (pdf_doc == NULL) {
+    return NULL;
+  }
+  return pdf_load_name_tree(ctx, pdf_doc, PDF_NAME(EmbeddedFiles));
+}
+
+static pdf_obj *embedded_file_stream(fz_context *ctx, pdf_obj *file_spec) {
+  return pdf_dict_get(ctx, pdf_dict_get(ctx, file_spec, PDF_NAME(EF)),
+                      PDF_NAME(F));
+}
+
+/* The size is optional, the decoded length or the length of an unfiltered stream are used if it's missing */
+static int64_t embedded_file_size(fz_context *ctx, pdf_obj *stream) {
+  pdf_obj *size = pdf_dict_get(ctx, pdf_dict_get(ctx, stream, PDF_NAME(Params)), PDF_NAME(Size));
+  if (pdf_is_int(ctx, size)) {
+    return pdf_to_int64(ctx, size);
+  }
+  pdf_obj *decoded_length = pdf_dict_get(ctx, stream, PDF_NAME(DL));
+  if (pdf_is_int(ctx, decoded_length)) {
+    return pdf_to_int64(ctx, decoded_length);
+  }
+  if (pdf_is_stream(ctx, stream) && pdf_dict_get(ctx, stream, PDF_NAME(Filter)) == NULL) {
+    return pdf_dict_get_int64(ctx, stream, PDF_NAME(Length));
+  }
+  return -1;
+}
+
+static void load_attachment_info(fz_context *ctx, pdf_obj *name, pdf_obj *file_spec,
+                                 mupdf_attachment_info *info) {
+  pdf_obj *stream = embedded_file_stream(ctx, file_spec);
+  info->name = fz_strdup(ctx, pdf_to_name(ctx, name));
+  const char *file_name = pdf_dict_get_text_string(ctx, file_spec, PDF_NAME(UF));
+  if (file_name[0] == 0) {
+    file_name = pdf_dict_get_text_string(ctx, file_spec, PDF_NAME(F));
+  }
+  info->file_name = fz_strdup(ctx, file_name[0] == 0 ? info->name : file_name);
+  const char *mime_type = pdf_dict_get_name(ctx, stream, PDF_NAME(Subtype));
+  if (mime_type != NULL && mime_type[0] != 0) {
+    info->mime_type = fz_strdup(ctx, mime_type);
+  }
+  const char *description = pdf_dict_get_text_string(ctx, file_spec, PDF_NAME(Desc));
+  if (description[0] != 0) {
+    info->description = fz_strdup(ctx, description);
+  }
+  info->size = embedded_file_size(ctx, stream);
+}
+
+static void free_attachment_infos(fz_context *ctx, mupdf_attachment_info *infos, int count) {
+  if (infos == NULL) {
+    return;
+  }
+  for (int i = 0; i < count; i++) {
+    fz_free(ctx, infos[i].name);
+    fz_free(ctx, infos[i].file_name);
+    fz_free(ctx, infos[i].mime_type);
+    fz_free(ctx, infos[i].description);
+  }
+  fz_free(ctx, infos);
+}
+
+/* The name tree is loaded once for all the attachments */
+mupdf_attachment_list mupdf_load_attachments(fz_context *ctx, fz_document *doc) {
+  mupdf_attachment_list res;
+  pdf_obj *embedded_files = NULL;
+  mupdf_attachment_info *infos = NULL;
+  int count = 0;
+  fz_var(embedded_files);
+  fz_var(infos);
+  fz_var(count);
+  fz_try(ctx) {
+    embedded_files = load_embedded_files(ctx, doc);
+    int len = pdf_dict_len(ctx, embedded_files);
+    if (len > 0) {
+      infos = fz_calloc(ctx, len, sizeof(mupdf_attachment_info));
+    }
+    for (; count < len; count++) {
+      load_attachment_info(ctx, pdf_dict_get_key(ctx, embedded_files, count),
+                           pdf_dict_get_val(ctx, embedded_files, count), &infos[count]);
+    }
+    res.status = true;
+    res.value.list.infos = infos;
+    res.value.list.count = count;
+  }
+  fz_always(ctx) { pdf_drop_obj(ctx, embedded_files); }
+  fz_catch(ctx) {
+    /* The info being loaded when the error was thrown may hold some strings */
+    free_attachment_infos(ctx, infos, infos == NULL ? 0 : count + 1);
+    res.status = false;
+    res.value.err_msg = fz_caught_message(ctx);
+  }
+  return res;
+}
+
+mupdf_attachment_data mupdf_load_attachment_data(fz_context *ctx,
+                                                 fz_document *doc,
+                                                 const int index) {
+  mupdf_attachment_data res;
+  pdf_obj *embedded_files = NULL;
+  fz_buffer *contents = NULL;
+  fz_var(embedded_files);
+  fz_var(contents);
+  fz_try(ctx) {
+    embedded_files = load_embedded_files(ctx, doc);
+    if (index < 0 || index >= pdf_dict_len(ctx, embedded_files)) {
+      fz_throw(ctx, FZ_ERROR_ARGUMENT, "attachment index out of range");
+    }
+    pdf_obj *stream = embedded_file_stream(ctx, pdf_dict_get_val(ctx, embedded_files, index));
+    if (!pdf_is_stream(ctx, stream)) {
+      fz_throw(ctx, FZ_ERROR_FORMAT, "attachment has no embedded file");
+    }
+    contents = pdf_load_stream(ctx, stream);
+    res.inner.value.data_len = fz_buffer_storage(ctx, contents, &res.inner.value.data);
+    res.inner.value.buf = contents;
+    res.status = true;
+  }
+  fz_always(ctx) { pdf_drop_obj(ctx, embedded_files); }
+  fz_catch(ctx) {
+    fz_drop_buffer(ctx, contents);
+    res.status = false;
+    res.inner.err_msg = fz_caught_message(ctx);
+  }
+  return res;
+}
@@ -0,0 +1,15 @@
+use serde::{Deserialize, Serialize};
+
+/// File embedded into a PDF document
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Attachment {
+  /// Position of the attachment in the document, used to extract it
+  pub index: usize,
+  /// Key of the attachment in the `EmbeddedFiles` name tree
+  pub name: String,
+  pub file_name: String,
+  pub mime_type: Option<String>,
+  pub description: Option<String>,
+  /// Size of the decoded file in bytes, `None` if the document doesn't tell it
+  pub size: Option<usize>,
+}
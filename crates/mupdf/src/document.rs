@@ -3,11 +3,12 @@ use std::fmt;
 
 use byte_unit::{rust_decimal::prelude::ToPrimitive, Byte, Unit};
 
-use mupdf_sys::{fz_context, fz_document, fz_drop_context, fz_drop_document, fz_drop_outline,
-                fz_is_external_link, fz_outline, fz_resolve_link, mupdf_doc_page_count,
-                mupdf_load_outline, mupdf_load_page, mupdf_lookup_metadata, mupdf_new_context,
-                mupdf_open_document};
+use mupdf_sys::{fz_context, fz_document, fz_drop_buffer, fz_drop_context, fz_drop_document, fz_drop_outline,
+                fz_free, fz_is_external_link, fz_outline, fz_resolve_link, mupdf_doc_page_count,
+                mupdf_load_attachment_data, mupdf_load_attachments, mupdf_load_outline, mupdf_load_page,
+                mupdf_lookup_metadata, mupdf_new_context, mupdf_open_document, mupdf_save_document};
 
+use crate::attachment::Attachment;
 use crate::outline::Outline;
 use crate::page::Page;
 
@@ -66,14 +67,81 @@ impl Document {
         if data.is_null() {
           Ok(String::new())
         } else {
-          Ok(CStr::from_ptr(data).to_str().unwrap().to_string())
+          let value = CStr::from_ptr(data).to_string_lossy().into_owned();
+          fz_free(self.ctx, data as *mut std::ffi::c_void);
+          Ok(value)
         }
       } else {
         Err(CStr::from_ptr(mupdf_res.value.err_msg).to_str().unwrap().to_string())
       }
     }
   }
-  unsafe fn walk_outlines(&self, outline: *mut fz_outline) -> Vec<Outline> {
+  /// Saves a PDF document with the changes made to its form fields.
+  ///
+  /// `incremental` appends the changes to the end of the file and
+  /// must be used when saving over the file the document was opened from.
+  pub fn save(&self, path_to_out: &str, incremental: bool) -> Result<(), String> {
+    let c_path_to_out = CString::new(path_to_out).unwrap();
+    unsafe {
+      let mupdf_res = mupdf_save_document(self.ctx, self.inner, c_path_to_out.as_ptr(), incremental);
+      if mupdf_res.status {
+        Ok(())
+      } else {
+        Err(CStr::from_ptr(mupdf_res.err_msg).to_str().unwrap().to_string())
+      }
+    }
+  }
+  /// Files embedded into the document, empty for non-PDF documents
+  pub fn attachments(&self) -> Result<Vec<Attachment>, String> {
+    unsafe {
+      let mupdf_res = mupdf_load_attachments(self.ctx, self.inner);
+      if !mupdf_res.status {
+        return Err(CStr::from_ptr(mupdf_res.value.err_msg).to_str().unwrap().to_string());
+      }
+      let list = mupdf_res.value.list;
+      let take_string = |ptr: *mut std::ffi::c_char| -> Option<String> {
+        if ptr.is_null() {
+          None
+        } else {
+          let res = CStr::from_ptr(ptr).to_string_lossy().into_owned();
+          fz_free(self.ctx, ptr as *mut std::ffi::c_void);
+          Some(res)
+        }
+      };
+      let attachments = (0..list.count as usize).map(|index| {
+        let info = *list.infos.add(index);
+        Attachment {
+          index,
+          name: take_string(info.name).unwrap_or_default(),
+          file_name: take_string(info.file_name).unwrap_or_default(),
+          mime_type: take_string(info.mime_type),
+          description: take_string(info.description),
+          size: usize::try_from(info.size).ok(),
+        }
+      }).collect();
+      fz_free(self.ctx, list.infos as *mut std::ffi::c_void);
+      Ok(attachments)
+    }
+  }
+  /// Extracts the contents of an embedded file
+  pub fn attachment_data(&self, attachment: &Attachment) -> Result<Vec<u8>, String> {
+    unsafe {
+      let mupdf_res = mupdf_load_attachment_data(self.ctx, self.inner, attachment.index as i32);
+      if mupdf_res.status {
+        let value = mupdf_res.inner.value;
+        let data = if value.data_len == 0 {
+          Vec::new()
+        } else {
+          std::slice::from_raw_parts(value.data, value.data_len).to_vec()
+        };
+        fz_drop_buffer(self.ctx, value.buf);
+        Ok(data)
+      } else {
+        Err(CStr::from_ptr(mupdf_res.inner.err_msg).to_str().unwrap().to_string())
+      }
+    }
+  }
+  unsafe fn walk_outlines(&self, outline: *mut fz_outline, depth: u32) -> Vec<Outline> {
     let mut outlines = Vec::new();
     let mut next = outline;
     while !next.is_null() {
@@ -95,7 +163,7 @@ impl Document {
         None
       };
       let down = if !(*next).down.is_null() {
-        self.walk_outlines((*next).down)
+        self.walk_outlines((*next).down, depth + 1)
       } else {
         Vec::new()
       };
@@ -106,6 +174,8 @@ impl Document {
         down,
         x,
         y,
+        depth,
+        is_open: (*next).is_open() != 0,
       });
       next = (*next).next;
     }
@@ -119,7 +189,7 @@ impl Document {
         if outline.is_null() {
           return Ok(Vec::new());
         }
-        let toc = self.walk_outlines(outline);
+        let toc = self.walk_outlines(outline, 0);
         fz_drop_outline(self.ctx, outline);
         Ok(toc)
       } else {
@@ -155,7 +225,12 @@ pub enum MetadataKey {
 }
 
 impl fmt::Display for MetadataKey {
+  /// Formats the key as expected by `fz_lookup_metadata`
   fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-    write!(f, "{:?}", self)
+    match self {
+      MetadataKey::Format => write!(f, "format"),
+      MetadataKey::Encryption => write!(f, "encryption"),
+      _ => write!(f, "info:{:?}", self),
+    }
   }
 }
\ No newline at end of file
@@ -1,4 +1,6 @@
+pub mod attachment;
 pub mod document;
 pub mod page;
 pub mod pixmap;
-mod outline;
+pub mod outline;
+pub mod widget;
@@ -1,4 +1,7 @@
-#[derive(Debug)]
+use serde::{Deserialize, Serialize};
+
+/// Entry of the document table of contents
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Outline {
   pub title: String,
   pub uri: Option<String>,
@@ -6,4 +9,52 @@ pub struct Outline {
   pub down: Vec<Outline>,
   pub x: f32,
   pub y: f32,
+  /// Nesting level, top-level entries have depth 0
+  pub depth: u32,
+  /// Whether the entry should be shown expanded
+  pub is_open: bool,
+}
+
+/// Entry of the flattened table of contents
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct FlatOutline<'a> {
+  /// Position of the entry in reading order
+  pub index: usize,
+  /// Index of the parent entry, `None` for top-level entries
+  pub parent: Option<usize>,
+  pub outline: &'a Outline,
+}
+
+/// Walks the outline tree in reading order (depth-first, parents before children)
+pub struct OutlineIter<'a> {
+  stack: Vec<(&'a Outline, Option<usize>)>,
+  index: usize,
+}
+
+impl<'a> Iterator for OutlineIter<'a> {
+  type Item = FlatOutline<'a>;
+
+  fn next(&mut self) -> Option<Self::Item> {
+    let (outline, parent) = self.stack.pop()?;
+    let index = self.index;
+    self.index += 1;
+    self.stack.extend(outline.down.iter().rev().map(|child| (child, Some(index))));
+    Some(FlatOutline { index, parent, outline })
+  }
+}
+
+/// Returns an iterator over all entries of the tree with their parent indices
+pub fn flatten(outlines: &[Outline]) -> OutlineIter {
+  OutlineIter {
+    stack: outlines.iter().rev().map(|outline| (outline, None)).collect(),
+    index: 0,
+  }
+}
+
+/// Finds the entry the reader is currently in: the last entry in reading order
+/// that starts on `page` or before it
+pub fn current_entry_for_page(outlines: &[Outline], page: u32) -> Option<FlatOutline> {
+  flatten(outlines)
+    .filter(|entry| entry.outline.page.is_some_and(|entry_page| entry_page <= page))
+    .max_by_key(|entry| entry.outline.page)
 }
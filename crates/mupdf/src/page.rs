@@ -1,10 +1,16 @@
 use std::ffi::CStr;
+#[cfg(feature = "ocr")]
+use std::ffi::CString;
 
 use serde::{Deserialize, Serialize};
 
-use mupdf_sys::{fz_context, fz_drop_page, fz_page, mupdf_page_to_pixmap, mupdf_stext_page_as_json_from_page};
+use mupdf_sys::{fz_context, fz_drop_page, fz_free, fz_page, mupdf_first_widget, mupdf_next_widget,
+                mupdf_page_as_plain_text, mupdf_page_to_pixmap, mupdf_stext_page_as_json_from_page};
+#[cfg(feature = "ocr")]
+use mupdf_sys::mupdf_page_ocr_as_plain_text;
 
 use crate::pixmap::Pixmap;
+use crate::widget::Widget;
 
 pub struct Page {
   ctx: *mut fz_context,
@@ -37,6 +43,62 @@ impl Page {
     }
   }
 
+  pub fn get_text(&self) -> Result<String, String> {
+    unsafe {
+      let mupdf_result = mupdf_page_as_plain_text(self.ctx, self.inner);
+      if mupdf_result.status {
+        let text_ptr = mupdf_result.value.text;
+        let text = CStr::from_ptr(text_ptr).to_string_lossy().into_owned();
+        fz_free(self.ctx, text_ptr as *mut std::ffi::c_void);
+        Ok(text)
+      } else {
+        Err(CStr::from_ptr(mupdf_result.value.err_msg).to_str().unwrap().to_string())
+      }
+    }
+  }
+  /// Recognizes the text of the page with Tesseract.
+  ///
+  /// `language` is a tesseract language such as `eng` or `eng+rus`,
+  /// `path_to_tessdata` is a directory with the `*.traineddata` files for it.
+  #[cfg(feature = "ocr")]
+  pub fn ocr_text(&self, language: &str, path_to_tessdata: &str) -> Result<String, String> {
+    let c_language = CString::new(language).unwrap();
+    let c_path_to_tessdata = CString::new(path_to_tessdata).unwrap();
+    unsafe {
+      let mupdf_result = mupdf_page_ocr_as_plain_text(
+        self.ctx, self.inner, c_language.as_ptr(), c_path_to_tessdata.as_ptr(),
+      );
+      if mupdf_result.status {
+        let text_ptr = mupdf_result.value.text;
+        let text = CStr::from_ptr(text_ptr).to_string_lossy().into_owned();
+        fz_free(self.ctx, text_ptr as *mut std::ffi::c_void);
+        Ok(text)
+      } else {
+        Err(CStr::from_ptr(mupdf_result.value.err_msg).to_str().unwrap().to_string())
+      }
+    }
+  }
+
+  /// Form fields of the page, empty for pages of non-PDF documents
+  pub fn widgets(&self) -> Result<Vec<Widget>, String> {
+    let mut widgets = Vec::new();
+    unsafe {
+      let mut mupdf_res = mupdf_first_widget(self.ctx, self.inner);
+      loop {
+        if !mupdf_res.status {
+          return Err(CStr::from_ptr(mupdf_res.value.err_msg).to_str().unwrap().to_string());
+        }
+        let widget = mupdf_res.value.widget;
+        if widget.is_null() {
+          break;
+        }
+        widgets.push(Widget::new(self.ctx, widget)?);
+        mupdf_res = mupdf_next_widget(self.ctx, widget);
+      }
+    }
+    Ok(widgets)
+  }
+
   pub fn get_stext(&self, scale: f32) -> Result<SText, String> {
     match self.get_stext_as_json(scale) {
       Ok(data) => {
@@ -0,0 +1,137 @@
+use std::ffi::{c_char, c_void, CStr, CString};
+use std::marker::PhantomData;
+
+use serde::{Deserialize, Serialize};
+
+use mupdf_sys::{fz_context, fz_free, pdf_annot, mupdf_load_widget_info, mupdf_set_widget_checked,
+                mupdf_set_widget_choice, mupdf_set_widget_text, mupdf_widget_info,
+                pdf_widget_type_PDF_WIDGET_TYPE_BUTTON, pdf_widget_type_PDF_WIDGET_TYPE_CHECKBOX,
+                pdf_widget_type_PDF_WIDGET_TYPE_COMBOBOX, pdf_widget_type_PDF_WIDGET_TYPE_LISTBOX,
+                pdf_widget_type_PDF_WIDGET_TYPE_RADIOBUTTON, pdf_widget_type_PDF_WIDGET_TYPE_SIGNATURE,
+                pdf_widget_type_PDF_WIDGET_TYPE_TEXT};
+
+use crate::page::Page;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum WidgetType {
+  Unknown,
+  Button,
+  Checkbox,
+  Combobox,
+  Listbox,
+  RadioButton,
+  Signature,
+  Text,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Rect {
+  pub x0: f32,
+  pub y0: f32,
+  pub x1: f32,
+  pub y1: f32,
+}
+
+/// AcroForm field of a PDF page, valid as long as the page it was loaded from
+pub struct Widget<'a> {
+  ctx: *mut fz_context,
+  inner: *mut pdf_annot,
+  pub name: String,
+  pub widget_type: WidgetType,
+  /// Text of a text field, selected option of a choice field,
+  /// `Off` or the name of the on state for checkboxes and radio buttons
+  pub value: String,
+  pub rect: Rect,
+  /// Options of a combobox or listbox, empty for other fields
+  pub options: Vec<String>,
+  page: PhantomData<&'a Page>,
+}
+
+impl<'a> Widget<'a> {
+  pub(crate) unsafe fn new(ctx: *mut fz_context, widget: *mut pdf_annot) -> Result<Self, String> {
+    let mupdf_res = mupdf_load_widget_info(ctx, widget);
+    if !mupdf_res.status {
+      return Err(CStr::from_ptr(mupdf_res.value.err_msg).to_str().unwrap().to_string());
+    }
+    let info = mupdf_res.value.info;
+    let options = (0..info.options_count as usize).map(|i| {
+      let option = *info.options.add(i);
+      let res = CStr::from_ptr(option).to_string_lossy().into_owned();
+      fz_free(ctx, option as *mut c_void);
+      res
+    }).collect();
+    let res = Widget {
+      ctx,
+      inner: widget,
+      name: CStr::from_ptr(info.name).to_string_lossy().into_owned(),
+      widget_type: Self::widget_type_from_raw(&info),
+      value: CStr::from_ptr(info.value).to_string_lossy().into_owned(),
+      rect: Rect { x0: info.rect.x0, y0: info.rect.y0, x1: info.rect.x1, y1: info.rect.y1 },
+      options,
+      page: PhantomData,
+    };
+    fz_free(ctx, info.name as *mut c_void);
+    fz_free(ctx, info.value as *mut c_void);
+    fz_free(ctx, info.options as *mut c_void);
+    Ok(res)
+  }
+  fn widget_type_from_raw(info: &mupdf_widget_info) -> WidgetType {
+    #[allow(non_upper_case_globals)]
+    match info.widget_type {
+      pdf_widget_type_PDF_WIDGET_TYPE_BUTTON => WidgetType::Button,
+      pdf_widget_type_PDF_WIDGET_TYPE_CHECKBOX => WidgetType::Checkbox,
+      pdf_widget_type_PDF_WIDGET_TYPE_COMBOBOX => WidgetType::Combobox,
+      pdf_widget_type_PDF_WIDGET_TYPE_LISTBOX => WidgetType::Listbox,
+      pdf_widget_type_PDF_WIDGET_TYPE_RADIOBUTTON => WidgetType::RadioButton,
+      pdf_widget_type_PDF_WIDGET_TYPE_SIGNATURE => WidgetType::Signature,
+      pdf_widget_type_PDF_WIDGET_TYPE_TEXT => WidgetType::Text,
+      _ => WidgetType::Unknown,
+    }
+  }
+  pub fn is_checked(&self) -> bool {
+    !self.value.is_empty() && self.value != "Off"
+  }
+  pub fn set_text(&mut self, value: &str) -> Result<(), String> {
+    let c_value = CString::new(value).unwrap();
+    unsafe {
+      let mupdf_res = mupdf_set_widget_text(self.ctx, self.inner, c_value.as_ptr());
+      if mupdf_res.status {
+        self.value = value.to_string();
+        Ok(())
+      } else {
+        Err(CStr::from_ptr(mupdf_res.err_msg).to_str().unwrap().to_string())
+      }
+    }
+  }
+  /// Checks or unchecks a checkbox or radio button
+  pub fn set_checked(&mut self, checked: bool) -> Result<(), String> {
+    unsafe {
+      let mupdf_res = mupdf_set_widget_checked(self.ctx, self.inner, checked);
+      if mupdf_res.status {
+        self.reload_value()
+      } else {
+        Err(CStr::from_ptr(mupdf_res.err_msg).to_str().unwrap().to_string())
+      }
+    }
+  }
+  /// Selects the options of a combobox or listbox
+  pub fn set_choice(&mut self, values: &[&str]) -> Result<(), String> {
+    let c_values: Vec<CString> = values.iter().map(|value| CString::new(*value).unwrap()).collect();
+    let mut c_values_ptrs: Vec<*const c_char> = c_values.iter().map(|value| value.as_ptr()).collect();
+    unsafe {
+      let mupdf_res = mupdf_set_widget_choice(
+        self.ctx, self.inner, c_values_ptrs.len() as i32, c_values_ptrs.as_mut_ptr(),
+      );
+      if mupdf_res.status {
+        self.reload_value()
+      } else {
+        Err(CStr::from_ptr(mupdf_res.err_msg).to_str().unwrap().to_string())
+      }
+    }
+  }
+  fn reload_value(&mut self) -> Result<(), String> {
+    let widget = unsafe { Widget::new(self.ctx, self.inner)? };
+    self.value = widget.value;
+    Ok(())
+  }
+}
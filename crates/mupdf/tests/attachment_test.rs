@@ -0,0 +1,55 @@
+use mupdf::attachment::Attachment;
+use mupdf::document::Document;
+use std::path::PathBuf;
+
+
+fn fixture(name: &str) -> String {
+  PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests").join("fixtures").join(name)
+    .to_str().unwrap().to_string()
+}
+
+fn find<'a>(attachments: &'a [Attachment], name: &str) -> &'a Attachment {
+  attachments.iter().find(|attachment| attachment.name == name).unwrap()
+}
+
+/// The fixture keeps its files in two leaves of the name tree
+#[test]
+fn lists_every_leaf_of_the_name_tree() {
+  let doc = Document::open(&fixture("attachments.pdf"), 20).unwrap();
+  let attachments = doc.attachments().unwrap();
+  let mut names = attachments.iter().map(|attachment| attachment.name.as_str()).collect::<Vec<_>>();
+  names.sort();
+  assert_eq!(names, vec!["notes.txt", "packed.bin", "raw.bin", "table.csv"]);
+
+  let notes = find(&attachments, "notes.txt");
+  assert_eq!(notes.file_name, "notes.txt");
+  assert_eq!(notes.mime_type.as_deref(), Some("text/plain"));
+  assert_eq!(notes.description.as_deref(), Some("Notes"));
+  assert_eq!(find(&attachments, "raw.bin").mime_type, None);
+}
+
+#[test]
+fn takes_the_size_from_the_document() {
+  let doc = Document::open(&fixture("attachments.pdf"), 20).unwrap();
+  let attachments = doc.attachments().unwrap();
+  // `Params/Size`
+  assert_eq!(find(&attachments, "notes.txt").size, Some(24));
+  // The decoded length of a compressed file
+  assert_eq!(find(&attachments, "table.csv").size, Some(512));
+  // The length of an uncompressed file
+  assert_eq!(find(&attachments, "raw.bin").size, Some(256));
+  // A compressed file of unknown size isn't decoded to measure it
+  assert_eq!(find(&attachments, "packed.bin").size, None);
+}
+
+#[test]
+fn extracts_the_decoded_file() {
+  let doc = Document::open(&fixture("attachments.pdf"), 20).unwrap();
+  let attachments = doc.attachments().unwrap();
+  let notes = doc.attachment_data(find(&attachments, "notes.txt")).unwrap();
+  assert_eq!(notes, b"The first attached file\n");
+  let packed = doc.attachment_data(find(&attachments, "packed.bin")).unwrap();
+  assert_eq!(packed, vec![0; 1000]);
+  let table = doc.attachment_data(find(&attachments, "table.csv")).unwrap();
+  assert_eq!(table, b"a,b\n1,2\n".repeat(64));
+}
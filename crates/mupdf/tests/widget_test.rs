@@ -0,0 +1,50 @@
+use mupdf::document::Document;
+use mupdf::widget::WidgetType;
+use std::path::PathBuf;
+
+
+fn fixture(name: &str) -> String {
+  PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests").join("fixtures").join(name)
+    .to_str().unwrap().to_string()
+}
+
+#[test]
+fn lists_form_fields() {
+  let doc = Document::open(&fixture("form.pdf"), 20).unwrap();
+  let page = doc.load_page(0).unwrap();
+  let widgets = page.widgets().unwrap();
+  let fields = widgets.iter().map(|widget| (widget.name.as_str(), widget.widget_type)).collect::<Vec<_>>();
+  assert_eq!(fields, vec![("name", WidgetType::Text), ("agree", WidgetType::Checkbox)]);
+  assert!(widgets.iter().all(|widget| !widget.is_checked()));
+}
+
+#[test]
+fn filled_form_survives_saving() {
+  // Unique per process, so parallel runs of the tests don't overwrite each other's output
+  let path_to_out = std::env::temp_dir().join(format!("mupdf_filled_form_{}.pdf", std::process::id()));
+  let path_to_out = path_to_out.to_str().unwrap().to_string();
+  {
+    let doc = Document::open(&fixture("form.pdf"), 20).unwrap();
+    let page = doc.load_page(0).unwrap();
+    for mut widget in page.widgets().unwrap() {
+      match widget.widget_type {
+        WidgetType::Text => widget.set_text("Ada Lovelace").unwrap(),
+        WidgetType::Checkbox => widget.set_checked(true).unwrap(),
+        _ => {}
+      }
+    }
+    doc.save(&path_to_out, false).unwrap();
+  }
+
+  let values = {
+    let doc = Document::open(&path_to_out, 20).unwrap();
+    let page = doc.load_page(0).unwrap();
+    let values = page.widgets().unwrap().into_iter().map(|widget| (widget.name, widget.value)).collect::<Vec<_>>();
+    values
+  };
+  std::fs::remove_file(&path_to_out).unwrap();
+  assert_eq!(values, vec![
+    ("name".to_string(), "Ada Lovelace".to_string()),
+    ("agree".to_string(), "Yes".to_string()),
+  ]);
+}